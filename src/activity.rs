@@ -0,0 +1,903 @@
+use crate::config::ActivitySettings;
+use crate::file_index::FileEntry;
+use crate::file_index::ShareIndex;
+use crate::listener::ClientAddr;
+use crate::mail;
+use crate::models::ActivitySummary;
+use crate::models::AppState;
+use crate::models::Share;
+use crate::webhooks;
+use axum::body::Body;
+use axum::body::BodyDataStream;
+use axum::body::Bytes;
+use axum::extract::ConnectInfo;
+use axum::extract::FromRequestParts;
+use axum::http::HeaderMap;
+use axum::http::header;
+use axum::http::request::Parts;
+use axum::response::Response;
+use chrono::SecondsFormat;
+use chrono::Utc;
+use futures_util::Stream;
+use futures_util::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use tokio::fs;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+/// Events of a share, one JSON object per line and only ever appended to.
+/// Kept in the share's directory under `SHARE_LOGS_DIR`, never in the share
+/// itself where it could be downloaded. Events not tied to a share, like
+/// logins with a wrong key, go to the same file in the state directory.
+const ACTIVITY_FILE: &str = ".activity.jsonl";
+/// The log before its last rotation.
+const ROTATED_ACTIVITY_FILE: &str = ".activity.1.jsonl";
+/// Size at which the log is rotated, so reading it for a summary stays
+/// cheap. Two files of this size hold tens of thousands of events.
+const MAX_LOG_BYTES: u64 = 4 * 1024 * 1024;
+/// Directory of the per-share logs in the state directory, one
+/// subdirectory per share name.
+const SHARE_LOGS_DIR: &str = "shares";
+
+/// One line of the activity log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActivityEvent {
+    /// RFC 3339 in UTC.
+    pub time: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+    /// Name of the key used, `None` for signed links.
+    pub key: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// `via` is `form` or `link`.
+    Login {
+        via: String,
+    },
+    LoginFailed {
+        reason: String,
+    },
+    /// `complete` once the last byte went out, false if the client broke
+    /// off. `version` is the file's `FileEntry::version` when the download
+    /// started, so a file replaced since then no longer counts.
+    FileDownload {
+        file: String,
+        #[serde(default)]
+        version: Option<String>,
+        bytes: u64,
+        complete: bool,
+    },
+    /// `format` as in the `/download-zip` query. `contents` is the share's
+    /// content hash when the download started and `parts` the number of
+    /// ZIPs it was split into, so later changes can be told apart.
+    ArchiveDownload {
+        format: String,
+        part: Option<usize>,
+        #[serde(default)]
+        contents: Option<String>,
+        #[serde(default)]
+        parts: usize,
+        bytes: u64,
+        complete: bool,
+    },
+    /// Files picked by the client, e.g. for retouching or printing.
+    SelectionSubmitted {
+        files: Vec<String>,
+        note: Option<String>,
+    },
+}
+
+impl EventKind {
+    fn set_transfer(&mut self, sent: u64, finished: bool) {
+        match self {
+            EventKind::FileDownload {
+                bytes, complete, ..
+            }
+            | EventKind::ArchiveDownload {
+                bytes, complete, ..
+            } => {
+                *bytes = sent;
+                *complete = finished;
+            }
+            EventKind::Login { .. }
+            | EventKind::LoginFailed { .. }
+            | EventKind::SelectionSubmitted { .. } => {}
+        }
+    }
+}
+
+/// Download of the file called `file` as it is now.
+pub fn file_event(index: &ShareIndex, file: &str) -> EventKind {
+    let files = index.files();
+    let version = files
+        .binary_search_by(|f| f.name.as_str().cmp(file))
+        .ok()
+        .map(|i| files[i].version());
+    EventKind::FileDownload {
+        file: file.to_string(),
+        version,
+        bytes: 0,
+        complete: false,
+    }
+}
+
+/// Download of the share's archive as it is now, `format` and `part` as in
+/// the `/download-zip` query.
+pub async fn archive_event(
+    index: &ShareIndex,
+    share: &Share,
+    format: Option<&str>,
+    part: Option<usize>,
+) -> EventKind {
+    EventKind::ArchiveDownload {
+        format: format.unwrap_or("zip").to_string(),
+        part,
+        contents: Some(index.content_hash(false).await),
+        parts: share.zip.parts(&index.files()).len(),
+        bytes: 0,
+        complete: false,
+    }
+}
+
+/// Address and user agent of the client making a request. Forwarded
+/// headers are only believed from a reverse proxy on the same host.
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<ClientAddr>>()
+            .and_then(|ConnectInfo(ClientAddr(ip))| *ip);
+        let ip = match peer {
+            Some(ip) if !ip.is_loopback() => Some(ip),
+            _ => forwarded_ip(&parts.headers).or(peer),
+        };
+        let user_agent = parts
+            .headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        Ok(ClientInfo { ip, user_agent })
+    }
+}
+
+/// The address the nearest proxy saw, the last one it appended.
+fn forwarded_ip(headers: &HeaderMap) -> Option<IpAddr> {
+    let forwarded_for = headers
+        .get("x-forwarded-for")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next());
+    let real_ip = headers.get("x-real-ip").and_then(|v| v.to_str().ok());
+    forwarded_for.or(real_ip)?.trim().parse().ok()
+}
+
+/// Keeps the network part only: a /24 of IPv4 or a /48 of IPv6.
+fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(v6) => {
+            let mut segments = v6.segments();
+            segments[3..].fill(0);
+            IpAddr::from(segments)
+        }
+    }
+}
+
+/// Appends to the activity logs of all shares, rotating a log that grew
+/// past `MAX_LOG_BYTES`. Logging is best effort: a log that can't be
+/// written never stops a login or download.
+#[derive(Default)]
+pub struct ActivityLog {
+    /// The downloads of each log read so far, by its directory. Held while
+    /// appending, so an event is never missed between reading and updating.
+    downloads: tokio::sync::Mutex<HashMap<PathBuf, Downloads>>,
+}
+
+/// What a log says was downloaded in full. Read from the log once, then
+/// kept up to date as events are appended.
+#[derive(Debug, Default)]
+pub struct Downloads {
+    /// Names and versions of single files.
+    files: HashSet<(String, String)>,
+    /// Content hashes, parts and part counts of archives.
+    archives: HashSet<(String, Option<usize>, usize)>,
+    /// By every download, completed or not.
+    pub bytes_sent: u64,
+    /// Time of the latest event of any kind.
+    pub last_activity: Option<String>,
+}
+
+impl Downloads {
+    fn add(&mut self, event: &ActivityEvent) {
+        self.last_activity = Some(event.time.clone());
+        match &event.kind {
+            EventKind::FileDownload {
+                file,
+                version,
+                bytes,
+                complete,
+            } => {
+                self.bytes_sent += bytes;
+                if let (true, Some(version)) = (complete, version) {
+                    self.files.insert((file.clone(), version.clone()));
+                }
+            }
+            EventKind::ArchiveDownload {
+                part,
+                contents,
+                parts,
+                bytes,
+                complete,
+                ..
+            } => {
+                self.bytes_sent += bytes;
+                if let (true, Some(contents)) = (complete, contents) {
+                    self.archives.insert((contents.clone(), *part, *parts));
+                }
+            }
+            EventKind::Login { .. }
+            | EventKind::LoginFailed { .. }
+            | EventKind::SelectionSubmitted { .. } => {}
+        }
+    }
+
+    /// True once the client has every one of `files`, whose content hash
+    /// is `contents` and which split into `parts` ZIPs: as the archive of
+    /// exactly these contents, all of its parts, or each file as it is now.
+    pub fn has_everything(&self, files: &[FileEntry], contents: &str, parts: usize) -> bool {
+        let archive = |part| self.archives.contains(&(contents.to_string(), part, parts));
+        !files.is_empty()
+            && (archive(None)
+                || (parts > 1 && (1..=parts).all(|p| archive(Some(p))))
+                || files
+                    .iter()
+                    .all(|f| self.files.contains(&(f.name.clone(), f.version()))))
+    }
+}
+
+impl ActivityLog {
+    /// Runs `check` on the downloads recorded in the log in `dir`.
+    pub async fn downloads<T>(&self, dir: &Path, check: impl FnOnce(&Downloads) -> T) -> T {
+        let mut downloads = self.downloads.lock().await;
+        if !downloads.contains_key(dir) {
+            let events = read_events(dir).await.unwrap_or_else(|e| {
+                warn!("Cannot read the activity log in {:?}: {}", dir, e);
+                Vec::new()
+            });
+            let mut read = Downloads::default();
+            events.iter().for_each(|event| read.add(event));
+            downloads.insert(dir.to_path_buf(), read);
+        }
+        check(&downloads[dir])
+    }
+
+    pub async fn append(&self, dir: &Path, event: &ActivityEvent) {
+        let mut line = match serde_json::to_string(event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Cannot serialize activity event: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let mut downloads = self.downloads.lock().await;
+        let written = async {
+            fs::create_dir_all(dir).await?;
+            let path = dir.join(ACTIVITY_FILE);
+            match fs::metadata(&path).await {
+                Ok(meta) if meta.len() >= MAX_LOG_BYTES => {
+                    fs::rename(&path, dir.join(ROTATED_ACTIVITY_FILE)).await?
+                }
+                _ => {}
+            }
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await?;
+            file.write_all(line.as_bytes()).await
+        }
+        .await;
+        match written {
+            Ok(()) => {
+                if let Some(downloads) = downloads.get_mut(dir) {
+                    downloads.add(event);
+                }
+            }
+            Err(e) => warn!("Cannot append to the activity log in {:?}: {}", dir, e),
+        }
+    }
+}
+
+fn new_event(
+    settings: &ActivitySettings,
+    key: Option<&str>,
+    client: &ClientInfo,
+    kind: EventKind,
+) -> ActivityEvent {
+    let ip = match client.ip {
+        Some(ip) if settings.anonymize => Some(anonymize_ip(ip)),
+        ip => ip,
+    };
+    ActivityEvent {
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        kind,
+        key: key.map(str::to_string),
+        ip: ip.map(|ip| ip.to_string()),
+        user_agent: client.user_agent.clone().filter(|_| !settings.anonymize),
+    }
+}
+
+/// Records `kind` in the share's log, unless activity logging is off, and
+/// tells the webhooks and, once everything is downloaded, the photographer.
+pub async fn record(
+    state: &AppState,
+    share: &Arc<Share>,
+    key: Option<&str>,
+    client: &ClientInfo,
+    kind: EventKind,
+) {
+    let config = state.config.load();
+    let event = new_event(&config.activity, key, client, kind);
+    publish(state, share, &event).await;
+}
+
+/// Records `kind` of a request no share could be told from, e.g. a login
+/// with a wrong key, in the log of the state directory.
+pub async fn record_unattributed(state: &AppState, client: &ClientInfo, kind: EventKind) {
+    let config = state.config.load();
+    if !config.activity.enabled {
+        return;
+    }
+    let event = new_event(&config.activity, None, client, kind);
+    state.activity.append(&config.state_dir, &event).await;
+}
+
+/// Where the log of the share called `share` is kept.
+pub fn share_log_dir(state_dir: &Path, share: &str) -> PathBuf {
+    state_dir.join(SHARE_LOGS_DIR).join(share)
+}
+
+/// Moves a log kept in the share's directory by earlier versions to the
+/// state directory, unless the share already has a log there.
+pub async fn move_share_log(share: &Share, state_dir: &Path) {
+    let log_dir = share_log_dir(state_dir, &share.name);
+    if fs::try_exists(log_dir.join(ACTIVITY_FILE))
+        .await
+        .unwrap_or(true)
+    {
+        return;
+    }
+    for name in [ROTATED_ACTIVITY_FILE, ACTIVITY_FILE] {
+        let old = share.dir.join(name);
+        if !fs::try_exists(&old).await.unwrap_or(false) {
+            continue;
+        }
+        // Copied rather than renamed, the state directory may be on
+        // another filesystem
+        let moved = async {
+            fs::create_dir_all(&log_dir).await?;
+            fs::copy(&old, log_dir.join(name)).await?;
+            fs::remove_file(&old).await
+        }
+        .await;
+        match moved {
+            Ok(()) => info!("Moved {:?} to {:?}", old, log_dir),
+            Err(e) => warn!("Cannot move {:?} to {:?}: {}", old, log_dir, e),
+        }
+    }
+}
+
+async fn publish(state: &AppState, share: &Arc<Share>, event: &ActivityEvent) {
+    let config = state.config.load();
+    if config.activity.enabled {
+        let dir = share_log_dir(&config.state_dir, &share.name);
+        state.activity.append(&dir, event).await;
+    }
+    webhooks::enqueue(state, share, event).await;
+    if let EventKind::FileDownload { complete: true, .. }
+    | EventKind::ArchiveDownload { complete: true, .. } = event.kind
+    {
+        mail::notify_downloaded_everything(state, share, event.key.as_deref()).await;
+    }
+}
+
+/// Records a download once its body is sent or dropped, with the bytes
+/// that actually went out. Error replies are passed through unrecorded.
+pub fn track_download(
+    state: &AppState,
+    share: &Arc<Share>,
+    key: Option<&str>,
+    client: &ClientInfo,
+    kind: EventKind,
+    response: Response,
+) -> Response {
+    let config = state.config.load();
+    let wanted = config.activity.enabled || !config.webhooks.is_empty();
+    if !wanted || !response.status().is_success() {
+        return response;
+    }
+
+    let mut event = new_event(&config.activity, key, client, kind);
+    let state = state.clone();
+    let share = share.clone();
+    on_body_end(response, move |sent, complete| {
+        event.kind.set_transfer(sent, complete);
+        // Bodies are dropped on runtime threads, but don't rely on it
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { publish(&state, &share, &event).await });
+        }
+    })
+}
+
+/// Calls `finish` with the bytes sent and whether the whole body went out,
+/// once it is sent or dropped.
+pub fn on_body_end(
+    response: Response,
+    finish: impl FnOnce(u64, bool) + Send + 'static,
+) -> Response {
+    response.map(|body| {
+        Body::from_stream(TrackedBody {
+            inner: body.into_data_stream(),
+            sent: 0,
+            complete: false,
+            finish: Some(Box::new(finish)),
+        })
+    })
+}
+
+type Finish = Box<dyn FnOnce(u64, bool) + Send>;
+
+/// Counts the bytes of a body and reports them when it is dropped.
+struct TrackedBody {
+    inner: BodyDataStream,
+    sent: u64,
+    complete: bool,
+    finish: Option<Finish>,
+}
+
+impl Stream for TrackedBody {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = self.inner.poll_next_unpin(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => self.sent += chunk.len() as u64,
+            Poll::Ready(Some(Err(_))) => self.finish_now(),
+            Poll::Ready(None) => {
+                self.complete = true;
+                self.finish_now();
+            }
+            Poll::Pending => {}
+        }
+        polled
+    }
+}
+
+impl TrackedBody {
+    fn finish_now(&mut self) {
+        if let Some(finish) = self.finish.take() {
+            finish(self.sent, self.complete);
+        }
+    }
+}
+
+impl Drop for TrackedBody {
+    fn drop(&mut self) {
+        self.finish_now();
+    }
+}
+
+/// The events of a share since the log's last rotation but one, oldest
+/// first. Lines that don't parse, e.g. one cut short by a crash, are skipped.
+pub async fn read_events(dir: &Path) -> std::io::Result<Vec<ActivityEvent>> {
+    let mut events = Vec::new();
+    for name in [ROTATED_ACTIVITY_FILE, ACTIVITY_FILE] {
+        let contents = match fs::read_to_string(dir.join(name)).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        events.extend(
+            contents
+                .lines()
+                .filter_map(|line| serde_json::from_str::<ActivityEvent>(line).ok()),
+        );
+    }
+    Ok(events)
+}
+
+/// Summary of a share's events against its current files, whose content
+/// hash is `contents` and which split into `parts` ZIPs. Only archives of
+/// exactly these contents count towards having downloaded everything.
+pub fn summarize(
+    share: &str,
+    events: &[ActivityEvent],
+    files: &[FileEntry],
+    contents: &str,
+    parts: usize,
+) -> ActivitySummary {
+    let mut summary = ActivitySummary {
+        share: share.to_string(),
+        logins: 0,
+        failed_logins: 0,
+        file_downloads: 0,
+        archive_downloads: 0,
+        interrupted_downloads: 0,
+        bytes_sent: 0,
+        downloaded_everything: false,
+        first_activity: events.first().map(|e| e.time.clone()),
+        last_activity: events.last().map(|e| e.time.clone()),
+    };
+
+    let mut downloads = Downloads::default();
+    for event in events {
+        downloads.add(event);
+        match &event.kind {
+            EventKind::Login { .. } => summary.logins += 1,
+            EventKind::LoginFailed { .. } => summary.failed_logins += 1,
+            EventKind::FileDownload { complete, .. } => match complete {
+                true => summary.file_downloads += 1,
+                false => summary.interrupted_downloads += 1,
+            },
+            EventKind::ArchiveDownload { complete, .. } => match complete {
+                true => summary.archive_downloads += 1,
+                false => summary.interrupted_downloads += 1,
+            },
+            EventKind::SelectionSubmitted { .. } => {}
+        }
+    }
+
+    summary.bytes_sent = downloads.bytes_sent;
+    summary.downloaded_everything = downloads.has_everything(files, contents, parts);
+    summary
+}
+
+/// Summary of every share in `shares`, in their configured order.
+pub async fn summarize_shares(state: &AppState, shares: &[Arc<Share>]) -> Vec<ActivitySummary> {
+    let state_dir = state.config.load().state_dir.clone();
+    let mut summaries = Vec::with_capacity(shares.len());
+    for share in shares {
+        let events = match read_events(&share_log_dir(&state_dir, &share.name)).await {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Cannot read the activity log of '{}': {}", share.name, e);
+                Vec::new()
+            }
+        };
+        let (files, contents) = match state.indexes.get(&share.dir).await {
+            Ok(index) => (index.files(), index.content_hash(false).await),
+            Err(_) => (Arc::new(Vec::new()), String::new()),
+        };
+        let parts = share.zip.parts(&files).len();
+        summaries.push(summarize(&share.name, &events, &files, &contents, parts));
+    }
+    summaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_app::FILE;
+    use crate::test_app::KEY;
+    use crate::test_app::SHARE;
+    use crate::test_app::TestApp;
+    use crate::test_app::csrf_of;
+    use crate::test_app::eventually;
+    use crate::test_app::key_cookie;
+    use crate::test_app::test_share;
+    use axum::http::Method;
+    use axum::http::Request;
+    use axum::http::StatusCode;
+    use std::time::SystemTime;
+
+    fn event(kind: EventKind) -> ActivityEvent {
+        ActivityEvent {
+            time: "2025-06-01T12:00:00Z".to_string(),
+            kind,
+            key: Some("owner".to_string()),
+            ip: None,
+            user_agent: None,
+        }
+    }
+
+    fn download(file: &str, complete: bool) -> ActivityEvent {
+        event(EventKind::FileDownload {
+            file: file.to_string(),
+            version: Some(entry(file).version()),
+            bytes: 10,
+            complete,
+        })
+    }
+
+    fn entry(name: &str) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            size: 10,
+            modified: SystemTime::UNIX_EPOCH,
+            inode: 0,
+            changed: 0,
+        }
+    }
+
+    #[test]
+    fn everything_counts_once_each_file_or_part_arrived() {
+        let files = [entry("a.jpg"), entry("b.jpg")];
+        let mut events = vec![
+            event(EventKind::Login {
+                via: "form".to_string(),
+            }),
+            download("a.jpg", true),
+            download("b.jpg", false),
+        ];
+        let summary = summarize("smith", &events, &files, "v1", 1);
+        assert_eq!(summary.logins, 1);
+        assert_eq!(summary.file_downloads, 1);
+        assert_eq!(summary.interrupted_downloads, 1);
+        assert_eq!(summary.bytes_sent, 20);
+        assert!(!summary.downloaded_everything);
+
+        events.push(download("b.jpg", true));
+        assert!(summarize("smith", &events, &files, "v1", 1).downloaded_everything);
+
+        let part = |part| {
+            event(EventKind::ArchiveDownload {
+                format: "zip".to_string(),
+                part: Some(part),
+                contents: Some("v1".to_string()),
+                parts: 2,
+                bytes: 10,
+                complete: true,
+            })
+        };
+        let parts = [part(1), part(2)];
+        assert!(!summarize("smith", &parts[..1], &files, "v1", 2).downloaded_everything);
+        assert!(summarize("smith", &parts, &files, "v1", 2).downloaded_everything);
+    }
+
+    #[test]
+    fn archives_of_earlier_contents_do_not_count() {
+        let files = [entry("a.jpg"), entry("b.jpg")];
+        let archive = |contents: Option<&str>, part, parts| {
+            event(EventKind::ArchiveDownload {
+                format: "zip".to_string(),
+                part,
+                contents: contents.map(str::to_string),
+                parts,
+                bytes: 10,
+                complete: true,
+            })
+        };
+        let everything = |events: &[ActivityEvent], parts| {
+            summarize("smith", events, &files, "v2", parts).downloaded_everything
+        };
+
+        assert!(everything(&[archive(Some("v2"), None, 1)], 1));
+        // Files added or replaced since, or a log from before hashes were kept
+        assert!(!everything(&[archive(Some("v1"), None, 1)], 1));
+        assert!(!everything(&[archive(None, None, 0)], 1));
+        // Same files, but split differently after the part size changed
+        let old_split = [
+            archive(Some("v2"), Some(1), 2),
+            archive(Some("v2"), Some(2), 2),
+        ];
+        assert!(everything(&old_split, 2));
+        assert!(!everything(&old_split, 3));
+    }
+
+    #[test]
+    fn replaced_files_must_be_downloaded_again() {
+        let files = [entry("a.jpg"), entry("b.jpg")];
+        let events = [download("a.jpg", true), download("b.jpg", true)];
+        assert!(summarize("smith", &events, &files, "v1", 1).downloaded_everything);
+
+        let mut replaced = files.clone();
+        replaced[1].changed += 1;
+        assert!(!summarize("smith", &events, &replaced, "v2", 1).downloaded_everything);
+
+        // Logs from before versions were kept don't count either
+        let unversioned = event(EventKind::FileDownload {
+            file: "b.jpg".to_string(),
+            version: None,
+            bytes: 10,
+            complete: true,
+        });
+        let events = [download("a.jpg", true), unversioned];
+        assert!(!summarize("smith", &events, &files, "v1", 1).downloaded_everything);
+    }
+
+    #[tokio::test]
+    async fn downloads_are_read_once_and_then_kept_up_to_date() {
+        let dir = std::env::temp_dir().join(format!(
+            "photo4share-activity-downloads-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let files = [entry("a.jpg"), entry("b.jpg")];
+        let log = ActivityLog::default();
+        log.append(&dir, &download("a.jpg", true)).await;
+
+        let everything = async |log: &ActivityLog| {
+            log.downloads(&dir, |d| (d.has_everything(&files, "v1", 1), d.bytes_sent))
+                .await
+        };
+        assert_eq!(everything(&log).await, (false, 10));
+
+        // Not read again: what was appended since counts, the file doesn't
+        std::fs::remove_file(dir.join(ACTIVITY_FILE)).unwrap();
+        log.append(&dir, &download("b.jpg", true)).await;
+        assert_eq!(everything(&log).await, (true, 20));
+        // A fresh log reads the file, which only has the later event
+        assert_eq!(everything(&ActivityLog::default()).await, (false, 10));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn logs_kept_in_the_share_are_moved_to_the_state_dir() {
+        let dir =
+            std::env::temp_dir().join(format!("photo4share-activity-move-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let share_dir = dir.join("share");
+        std::fs::create_dir_all(&share_dir).unwrap();
+        let log = ActivityLog::default();
+        log.append(&share_dir, &download("a.jpg", true)).await;
+        let share = test_share(&share_dir);
+        let state_dir = dir.join("state");
+
+        move_share_log(&share, &state_dir).await;
+        let log_dir = share_log_dir(&state_dir, SHARE);
+        assert_eq!(read_events(&log_dir).await.unwrap().len(), 1);
+        assert!(!share_dir.join(ACTIVITY_FILE).exists());
+
+        // A log already in the state directory is never replaced
+        log.append(&share_dir, &download("b.jpg", true)).await;
+        move_share_log(&share, &state_dir).await;
+        assert_eq!(read_events(&log_dir).await.unwrap().len(), 1);
+        assert!(share_dir.join(ACTIVITY_FILE).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_log_is_rotated_and_read_across_the_rotation() {
+        let dir = std::env::temp_dir().join(format!("photo4share-activity-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = ActivityLog::default();
+        log.append(&dir, &download("a.jpg", true)).await;
+        // Grow the log past the limit with lines too long to parse
+        let padding = format!("{}\n", "x".repeat(1023));
+        std::fs::write(
+            dir.join(ROTATED_ACTIVITY_FILE),
+            padding.repeat(MAX_LOG_BYTES as usize / 1024),
+        )
+        .unwrap();
+        let mut current = std::fs::read_to_string(dir.join(ACTIVITY_FILE)).unwrap();
+        current.push_str(&padding.repeat(MAX_LOG_BYTES as usize / 1024));
+        std::fs::write(dir.join(ACTIVITY_FILE), current).unwrap();
+
+        log.append(&dir, &download("b.jpg", true)).await;
+        let current = std::fs::metadata(dir.join(ACTIVITY_FILE)).unwrap().len();
+        assert!(current < 1024, "not rotated, {} bytes", current);
+        // The earlier rotated file is gone, the events of the last one kept
+        let events = read_events(&dir).await.unwrap();
+        let files: Vec<_> = events
+            .iter()
+            .map(|e| match &e.kind {
+                EventKind::FileDownload { file, .. } => file.as_str(),
+                _ => "",
+            })
+            .collect();
+        assert_eq!(files, ["a.jpg", "b.jpg"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn events_round_trip_as_json_lines() {
+        let line = serde_json::to_string(&download("a.jpg", true)).unwrap();
+        assert!(line.starts_with(r#"{"time":"2025-06-01T12:00:00Z","event":"file_download","#));
+        let parsed: ActivityEvent = serde_json::from_str(&line).unwrap();
+        assert!(matches!(
+            parsed.kind,
+            EventKind::FileDownload { complete: true, .. }
+        ));
+    }
+
+    #[test]
+    fn anonymized_addresses_keep_the_network_only() {
+        let v4: IpAddr = "203.0.113.77".parse().unwrap();
+        assert_eq!(anonymize_ip(v4).to_string(), "203.0.113.0");
+        let v6: IpAddr = "2001:db8:85a3:1:2:3:4:5".parse().unwrap();
+        assert_eq!(anonymize_ip(v6).to_string(), "2001:db8:85a3::");
+    }
+
+    #[tokio::test]
+    async fn failed_logins_are_logged_in_the_state_dir() {
+        let app = TestApp::builder("failed").build();
+
+        let login_page = app.send(Method::GET, "/login", None).await;
+        let (csrf_cookie, token) = csrf_of(login_page).await;
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/login")
+            .header(header::COOKIE, &csrf_cookie)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header("x-forwarded-for", "203.0.113.77")
+            .body(Body::from(format!("csrf_token={}&key=not+a+key", token)))
+            .unwrap();
+        assert_eq!(app.request(request).await.status(), StatusCode::OK);
+
+        let events = read_events(&app.state_dir()).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            &events[0].kind,
+            EventKind::LoginFailed { reason } if reason == "wrong key"
+        ));
+        assert_eq!(events[0].ip.as_deref(), Some("203.0.113.77"));
+        assert!(read_events(&app.dir).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn share_logs_are_kept_out_of_what_is_served() {
+        let app = TestApp::builder("hidden")
+            .file(FILE, b"photo")
+            .file(".notes", b"private")
+            .build();
+
+        let uri = format!("/api/v1/files/{}/download", FILE);
+        let response = app.send(Method::GET, &uri, Some(KEY)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let log_dir = share_log_dir(&app.state_dir(), SHARE);
+        let logged = async || !read_events(&log_dir).await.unwrap().is_empty();
+        assert!(eventually(logged).await);
+        let events = read_events(&log_dir).await.unwrap();
+        assert!(matches!(
+            &events[0].kind,
+            EventKind::FileDownload { file, version: Some(_), complete: true, .. } if file == FILE
+        ));
+        assert!(read_events(&app.dir).await.unwrap().is_empty());
+
+        // Only files in the index are served, so no hidden file ever is
+        for name in [".notes", ".state", "missing.jpg"] {
+            let uri = format!("/api/v1/files/{}/download", name);
+            let response = app.send(Method::GET, &uri, Some(KEY)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", name);
+            let uri = format!("/download/{}", name);
+            let response = app.get_with_cookie(&uri, &key_cookie(KEY)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::file_index::FileEntry;
+use crate::zip_utils::ZipCompression;
+use crate::zip_utils::is_compressed_format;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use zip::AesMode;
+use zip::CompressionMethod;
+use zip::ZipWriter;
+use zip::result::ZipResult;
+use zip::write::FileOptions;
+use zip::write::SimpleFileOptions;
+
+/// Writes a ZIP of `files` to `dest` with every entry AES-256 encrypted.
+/// async_zip can't encrypt, so this is synchronous and meant for a blocking
+/// thread. Adds the size of every file written to `progress`.
+pub fn write_encrypted_zip(
+    dest: &Path,
+    dir: &Path,
+    files: &[FileEntry],
+    manifests: &[(&str, String)],
+    compression: ZipCompression,
+    password: &str,
+    progress: &AtomicU64,
+) -> ZipResult<()> {
+    let mut zip = ZipWriter::new(BufWriter::new(File::create_new(dest)?));
+
+    for entry in files {
+        // Removed since it was indexed, skip it like the plain build does
+        let Ok(mut file) = File::open(dir.join(&entry.name)) else {
+            continue;
+        };
+
+        let options = entry_options(compression, &entry.name, entry.size, password);
+        zip.start_file(entry.name.as_str(), options)?;
+        std::io::copy(&mut file, &mut zip)?;
+        progress.fetch_add(entry.size, Ordering::Relaxed);
+    }
+
+    for (name, contents) in manifests {
+        zip.start_file(*name, entry_options(compression, name, 0, password))?;
+        zip.write_all(contents.as_bytes())?;
+    }
+
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+fn entry_options<'k>(
+    compression: ZipCompression,
+    name: &str,
+    size: u64,
+    password: &'k str,
+) -> FileOptions<'k, ()> {
+    SimpleFileOptions::default()
+        .compression_method(compression_method(compression, name))
+        .large_file(size >= u32::MAX as u64)
+        .unix_permissions(0o644)
+        .with_aes_encryption(AesMode::Aes256, password)
+}
+
+fn compression_method(compression: ZipCompression, filename: &str) -> CompressionMethod {
+    match compression {
+        ZipCompression::Store => CompressionMethod::Stored,
+        ZipCompression::Deflate => CompressionMethod::Deflated,
+        ZipCompression::Zstd => CompressionMethod::Zstd,
+        ZipCompression::Auto if is_compressed_format(filename) => CompressionMethod::Stored,
+        ZipCompression::Auto => CompressionMethod::Deflated,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::SystemTime;
+    use zip::ZipArchive;
+    use zip::result::ZipError;
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    fn write_share(dir: &Path, files: &[(&str, &[u8])]) -> Vec<FileEntry> {
+        std::fs::create_dir_all(dir).unwrap();
+        files
+            .iter()
+            .map(|(name, contents)| {
+                std::fs::write(dir.join(name), contents).unwrap();
+                FileEntry {
+                    name: name.to_string(),
+                    size: contents.len() as u64,
+                    modified: SystemTime::now(),
+                    inode: 0,
+                    changed: 0,
+                }
+            })
+            .collect()
+    }
+
+    fn round_trip(compression: ZipCompression) {
+        let root = std::env::temp_dir().join(format!(
+            "photo4share-aes-{:?}-{}",
+            compression,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        let text = "Знімки з весілля\n".repeat(1000);
+        let entries = write_share(
+            &root.join("share"),
+            &[
+                ("a.jpg", b"\xff\xd8\xff\xe0 not really a jpeg"),
+                ("notes.txt", text.as_bytes()),
+            ],
+        );
+        let dest = root.join("out.zip");
+        let progress = AtomicU64::new(0);
+
+        write_encrypted_zip(
+            &dest,
+            &root.join("share"),
+            &entries,
+            &[],
+            compression,
+            PASSWORD,
+            &progress,
+        )
+        .unwrap();
+        assert_eq!(
+            progress.load(Ordering::Relaxed),
+            entries.iter().map(|e| e.size).sum::<u64>()
+        );
+
+        let mut archive = ZipArchive::new(File::open(&dest).unwrap()).unwrap();
+        assert_eq!(archive.len(), 2);
+        for (i, entry) in entries.iter().enumerate() {
+            // Nothing is readable without the password
+            assert!(matches!(
+                archive.by_index(i),
+                Err(ZipError::UnsupportedArchive(_))
+            ));
+            assert!(matches!(
+                archive.by_index_decrypt(i, b"wrong password"),
+                Err(ZipError::InvalidPassword)
+            ));
+
+            let mut file = archive.by_index_decrypt(i, PASSWORD.as_bytes()).unwrap();
+            assert_eq!(file.name(), entry.name);
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).unwrap();
+            assert_eq!(
+                contents,
+                std::fs::read(root.join("share").join(&entry.name)).unwrap()
+            );
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn stored_entries_decrypt_with_the_password() {
+        round_trip(ZipCompression::Store);
+    }
+
+    #[test]
+    fn compressed_entries_decrypt_with_the_password() {
+        round_trip(ZipCompression::Deflate);
+        round_trip(ZipCompression::Zstd);
+        round_trip(ZipCompression::Auto);
+    }
+}
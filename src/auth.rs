@@ -1,18 +1,167 @@
+use crate::models::AppState;
+use crate::models::Permission;
+use crate::models::Share;
+use crate::models::ShareKey;
+use crate::signed_links::verify_session;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::http::header;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::Rng;
+use rand::rng;
+use std::sync::Arc;
 use subtle::ConstantTimeEq;
 use tower_cookies::Cookies;
 
+/// Prefix of stored key hashes, so the algorithm can change later.
+pub const KEY_HASH_PREFIX: &str = "blake3:";
+const KEY_HASH_CONTEXT: &str = "photo4share 2025 share key";
+
 pub fn verify_user_sent_key(provided: &str, expected: &str) -> bool {
     verify_key(provided, expected)
 }
 
-pub fn verify_cookie_key(cookies: &Cookies, expected: &str) -> bool {
-    if let Some(cookie) = cookies.get("share_key") {
-        verify_key(cookie.value(), expected)
-    } else {
-        false
+/// Cookie holding the key entered in the login form.
+pub const KEY_COOKIE: &str = "share_key";
+/// Cookie set by a login link, see `signed_links::mint_session`.
+pub const SESSION_COOKIE: &str = "share_session";
+
+/// Cookie holding the admin key entered in the login form.
+pub const ADMIN_COOKIE: &str = "admin_key";
+
+/// True if the request carries the admin key, as a bearer token or in the
+/// cookie the login form sets for it.
+pub fn is_admin(headers: &HeaderMap, cookies: &Cookies, state: &AppState) -> bool {
+    let Some(expected) = state.config.load().admin_key_hash else {
+        return false;
+    };
+    let key = match headers.get(header::AUTHORIZATION) {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(|key| key.trim().to_string()),
+        None => cookies.get(ADMIN_COOKIE).map(|c| c.value().to_string()),
+    };
+    key.is_some_and(|key| bool::from(hash_key(&key).ct_eq(&expected)))
+}
+
+/// Who a request comes from: a share and the key it was unlocked with.
+pub struct Principal {
+    pub share: Arc<Share>,
+    /// Index into `share.keys`.
+    key: usize,
+}
+
+impl Principal {
+    /// Panics if the share has no such key.
+    pub fn new(share: Arc<Share>, key: usize) -> Self {
+        assert!(key < share.keys.len(), "share key out of range");
+        Self { share, key }
+    }
+
+    pub fn key(&self) -> &ShareKey {
+        &self.share.keys[self.key]
+    }
+
+    pub fn can(&self, permission: Permission) -> bool {
+        self.key().permissions.contains(&permission)
+    }
+
+    /// Error for handlers to reply with when the key lacks `permission`.
+    pub fn check(&self, permission: Permission) -> Result<(), (StatusCode, &'static str)> {
+        if self.can(permission) {
+            return Ok(());
+        }
+        let message = match permission {
+            Permission::List => "This key may not list the files",
+            Permission::Preview => "This key may not open previews",
+            Permission::Download => "This key may only view previews",
+            Permission::DownloadZip => "This key may not download the archive",
+            Permission::Comment => "This key may not comment",
+            Permission::Select => "This key may not select files",
+            Permission::Upload => "This key may not upload files",
+        };
+        Err((StatusCode::FORBIDDEN, message))
+    }
+}
+
+/// Finds the share and key the browser is logged in with, by the key in
+/// the `share_key` cookie or else the session a login link started.
+pub fn authenticated_share(cookies: &Cookies, state: &AppState) -> Option<Principal> {
+    let config = state.config.load();
+    if let Some(principal) = cookies
+        .get(KEY_COOKIE)
+        .and_then(|cookie| find_share_by_key(&config.shares, cookie.value()))
+    {
+        return Some(principal);
+    }
+    let cookie = cookies.get(SESSION_COOKIE)?;
+    verify_session(
+        config.link_secret.as_deref()?,
+        cookie.value(),
+        &config.shares,
+    )
+}
+
+/// Finds the share and key of an API request: the key sent as
+/// `Authorization: Bearer <key>`, or else the session cookie of a browser.
+/// A bearer key that matches no share is rejected outright.
+pub fn authenticated_api_share(
+    headers: &HeaderMap,
+    cookies: &Cookies,
+    state: &AppState,
+) -> Option<Principal> {
+    match headers.get(header::AUTHORIZATION) {
+        Some(value) => {
+            let key = value.to_str().ok()?.strip_prefix("Bearer ")?.trim();
+            find_share_by_key(&state.config.load().shares, key)
+        }
+        None => authenticated_share(cookies, state),
     }
 }
 
+/// Looks up a share by one of its plain keys. Every key is compared so the
+/// time taken doesn't reveal which one matched. Expired shares match none.
+pub fn find_share_by_key(shares: &[Arc<Share>], key: &str) -> Option<Principal> {
+    let hash = hash_key(key);
+    let mut found = None;
+    for share in shares {
+        for (index, share_key) in share.keys.iter().enumerate() {
+            if bool::from(share_key.key_hash.ct_eq(&hash)) {
+                found = Some(Principal::new(share.clone(), index));
+            }
+        }
+    }
+    found.filter(|principal| !principal.share.is_expired())
+}
+
+/// Keys are long random strings, so a fast keyed hash is enough here and
+/// keeps the per-request cookie check cheap.
+pub fn hash_key(key: &str) -> [u8; 32] {
+    blake3::derive_key(KEY_HASH_CONTEXT, key.as_bytes())
+}
+
+pub fn format_key_hash(hash: &[u8; 32]) -> String {
+    format!(
+        "{}{}",
+        KEY_HASH_PREFIX,
+        blake3::Hash::from_bytes(*hash).to_hex()
+    )
+}
+
+pub fn parse_key_hash(value: &str) -> Option<[u8; 32]> {
+    let hex = value.strip_prefix(KEY_HASH_PREFIX)?;
+    blake3::Hash::from_hex(hex).ok().map(|h| *h.as_bytes())
+}
+
+/// Generates a new random share key suitable for handing to a client.
+pub fn generate_share_key() -> String {
+    let bytes: [u8; 24] = rng().random();
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
 // Constant-time
 fn verify_key(provided: &str, expected: &str) -> bool {
     provided.as_bytes().ct_eq(expected.as_bytes()).into()
@@ -0,0 +1,262 @@
+use crate::file_index::FileEntry;
+use sha2::Digest;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Name of the SHA-256 manifest, readable by `sha256sum -c`.
+pub const SHA256SUMS: &str = "SHA256SUMS";
+/// Name of the BLAKE3 manifest, readable by `b3sum -c`.
+pub const B3SUMS: &str = "B3SUMS";
+
+const READ_BUFFER: usize = 1024 * 1024;
+
+#[derive(Clone)]
+pub struct FileDigest {
+    pub name: String,
+    pub sha256: String,
+    pub blake3: String,
+}
+
+/// Checksums of a share's files, kept as long as the file's metadata stays
+/// the same. Lives in the share's index, which drops entries of files
+/// that changed or disappeared.
+#[derive(Default)]
+pub struct DigestCache {
+    digests: Mutex<HashMap<String, (FileEntry, FileDigest)>>,
+    /// Held while hashing, so requests arriving while a cold share is being
+    /// hashed wait for that pass instead of hashing every file again.
+    hashing: tokio::sync::Mutex<()>,
+}
+
+impl DigestCache {
+    /// Digests of `files` in the same order, hashing only files that are
+    /// new or changed since they were last hashed. Files that no longer
+    /// exist are left out.
+    pub async fn digests(
+        &self,
+        dir: &Path,
+        files: &[FileEntry],
+    ) -> std::io::Result<Vec<FileDigest>> {
+        let mut result = Vec::with_capacity(files.len());
+        let mut hashing = None;
+        for entry in files {
+            if let Some(digest) = self.cached(entry) {
+                result.push(digest);
+                continue;
+            }
+            if hashing.is_none() {
+                hashing = Some(self.hashing.lock().await);
+                // Whoever held the lock may have just hashed it
+                if let Some(digest) = self.cached(entry) {
+                    result.push(digest);
+                    continue;
+                }
+            }
+
+            let path = dir.join(&entry.name);
+            let name = entry.name.clone();
+            let digest = match tokio::task::spawn_blocking(move || hash_file(&path, name))
+                .await
+                .map_err(std::io::Error::other)?
+            {
+                Ok(digest) => digest,
+                // Removed since it was indexed, archives skip it too
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            self.digests
+                .lock()
+                .unwrap()
+                .insert(entry.name.clone(), (entry.clone(), digest.clone()));
+            result.push(digest);
+        }
+        Ok(result)
+    }
+
+    fn cached(&self, entry: &FileEntry) -> Option<FileDigest> {
+        let digests = self.digests.lock().unwrap();
+        let (hashed, digest) = digests.get(&entry.name)?;
+        (hashed == entry).then(|| digest.clone())
+    }
+
+    /// Forgets every digest not matching one of `files`.
+    pub fn retain(&self, files: &[FileEntry]) {
+        self.digests.lock().unwrap().retain(|name, (hashed, _)| {
+            files
+                .binary_search_by(|f| f.name.as_str().cmp(name))
+                .is_ok_and(|i| files[i] == *hashed)
+        });
+    }
+}
+
+fn hash_file(path: &Path, name: String) -> std::io::Result<FileDigest> {
+    let mut file = std::fs::File::open(path)?;
+    let mut sha256 = Sha256::new();
+    let mut blake3 = blake3::Hasher::new();
+    let mut buffer = vec![0; READ_BUFFER];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        sha256.update(&buffer[..read]);
+        blake3.update(&buffer[..read]);
+    }
+
+    Ok(FileDigest {
+        name,
+        sha256: sha256
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        blake3: blake3.finalize().to_hex().to_string(),
+    })
+}
+
+/// Manifest in the `sha256sum`/`b3sum` format, `<hex>  <name>` per line.
+pub fn manifest(digests: &[FileDigest], blake3: bool) -> String {
+    let mut manifest = String::new();
+    for digest in digests {
+        let hash = if blake3 {
+            &digest.blake3
+        } else {
+            &digest.sha256
+        };
+        // Same escaping coreutils uses for names it can't print verbatim
+        if digest.name.contains(['\\', '\n', '\r']) {
+            let name = digest
+                .name
+                .replace('\\', "\\\\")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r");
+            manifest.push_str(&format!("\\{}  {}\n", hash, name));
+        } else {
+            manifest.push_str(&format!("{}  {}\n", hash, digest.name));
+        }
+    }
+    manifest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    fn digest(name: &str) -> FileDigest {
+        FileDigest {
+            name: name.to_string(),
+            sha256: "5a".repeat(32),
+            blake3: "b3".repeat(32),
+        }
+    }
+
+    fn entry(dir: &Path, name: &str) -> FileEntry {
+        let meta = std::fs::metadata(dir.join(name)).unwrap();
+        FileEntry {
+            name: name.to_string(),
+            size: meta.len(),
+            modified: meta.modified().unwrap(),
+            inode: meta.ino(),
+            changed: meta.ctime() as i128 * 1_000_000_000 + meta.ctime_nsec() as i128,
+        }
+    }
+
+    #[test]
+    fn manifest_escapes_names_like_coreutils() {
+        let digests = [
+            digest("plain.jpg"),
+            digest("with space.jpg"),
+            digest("back\\slash.jpg"),
+            digest("two\nlines\r.jpg"),
+        ];
+        let sha256 = "5a".repeat(32);
+        assert_eq!(
+            manifest(&digests, false),
+            format!(
+                "{h}  plain.jpg\n{h}  with space.jpg\n\\{h}  back\\\\slash.jpg\n\\{h}  two\\nlines\\r.jpg\n",
+                h = sha256
+            )
+        );
+        assert!(manifest(&digests[..1], true).starts_with(&"b3".repeat(32)));
+        assert_eq!(manifest(&[], false), "");
+    }
+
+    #[tokio::test]
+    async fn digests_are_kept_until_the_file_changes() {
+        let dir = std::env::temp_dir().join(format!("photo4share-digests-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.jpg"), b"abc").unwrap();
+        let cache = DigestCache::default();
+
+        let first = entry(&dir, "a.jpg");
+        let digests = cache
+            .digests(&dir, std::slice::from_ref(&first))
+            .await
+            .unwrap();
+        assert_eq!(
+            digests[0].sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(digests[0].blake3, blake3::hash(b"abc").to_hex().as_str());
+
+        // Same metadata, so the file isn't read again
+        std::fs::remove_file(dir.join("a.jpg")).unwrap();
+        let cached = cache
+            .digests(&dir, std::slice::from_ref(&first))
+            .await
+            .unwrap();
+        assert_eq!(cached[0].sha256, digests[0].sha256);
+
+        std::fs::write(dir.join("a.jpg"), b"changed").unwrap();
+        let second = entry(&dir, "a.jpg");
+        assert_ne!(first, second);
+        let changed = cache
+            .digests(&dir, std::slice::from_ref(&second))
+            .await
+            .unwrap();
+        assert_eq!(
+            changed[0].blake3,
+            blake3::hash(b"changed").to_hex().as_str()
+        );
+
+        // Forgotten once the index no longer lists it, and gone files are
+        // left out
+        cache.retain(&[]);
+        std::fs::remove_file(dir.join("a.jpg")).unwrap();
+        assert!(cache.digests(&dir, &[second]).await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_pass() {
+        let dir = std::env::temp_dir().join(format!(
+            "photo4share-digests-concurrent-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut files = Vec::new();
+        for i in 0..20 {
+            let name = format!("{:02}.jpg", i);
+            std::fs::write(dir.join(&name), name.as_bytes()).unwrap();
+            files.push(entry(&dir, &name));
+        }
+        let cache = DigestCache::default();
+
+        let (a, b) = tokio::join!(cache.digests(&dir, &files), cache.digests(&dir, &files));
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.len(), files.len());
+        for (a, b) in a.iter().zip(&b) {
+            assert_eq!((&a.name, &a.sha256), (&b.name, &b.sha256));
+        }
+        assert_eq!(cache.digests.lock().unwrap().len(), files.len());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,883 @@
+use crate::auth::format_key_hash;
+use crate::auth::generate_share_key;
+use crate::auth::hash_key;
+use crate::config;
+use crate::config::Config;
+use crate::config::is_valid_share_name;
+use crate::file_index::ShareIndex;
+use crate::file_utils::validate_path;
+use crate::mail;
+use crate::previews::prune_previews;
+use crate::signed_links::DEFAULT_LINK_TTL;
+use crate::signed_links::LinkTarget;
+use crate::signed_links::UsedLinks;
+use crate::signed_links::mint_link;
+use crate::signed_links::parse_link_query;
+use crate::zip_utils::ensure_cached_zip;
+use crate::zip_utils::prune_zip_cache;
+use crate::zip_utils::zip_part_numbers;
+use clap::Parser;
+use clap::Subcommand;
+use std::io::BufRead;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use toml_edit::DocumentMut;
+use toml_edit::Item;
+use toml_edit::Table;
+use toml_edit::value;
+
+#[derive(Parser)]
+#[command(version, about = "Share photo deliveries with clients")]
+pub struct Cli {
+    /// Path to the TOML config file (defaults to ./photo4share.toml if present)
+    #[arg(short, long, global = true, env = "PHOTO4SHARE_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the web server (the default when no command is given)
+    Serve,
+    /// Manage shares in the config file
+    #[command(subcommand)]
+    Share(ShareCommand),
+    /// Print the hash of a key for the `key_hash` setting
+    HashKey {
+        /// Key to hash; read from stdin when omitted
+        key: Option<String>,
+    },
+    /// Maintain the per-share ZIP caches
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Validate the configuration and report every problem found
+    CheckConfig,
+    /// Manage one-time links that log a browser in to a share
+    #[command(subcommand)]
+    LoginLink(LoginLinkCommand),
+    /// Send emails to clients
+    #[command(subcommand)]
+    Mail(MailCommand),
+    /// Print a signed link that downloads without the login form
+    Link {
+        /// Share the link belongs to
+        share: String,
+        /// File to download; the share's archive when omitted
+        #[arg(long)]
+        file: Option<String>,
+        /// Part of a share delivered as several ZIPs
+        #[arg(long, conflicts_with = "file")]
+        part: Option<usize>,
+        /// Archive format: zip, tar or tar.zst
+        #[arg(long, conflicts_with = "file")]
+        format: Option<String>,
+        /// Hours until the link expires
+        #[arg(long, default_value_t = DEFAULT_LINK_TTL.as_secs() / 3600)]
+        hours: u64,
+        /// Stop the link working after its first download
+        #[arg(long)]
+        single_use: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ShareCommand {
+    /// Add a share and print its access key
+    Add {
+        name: String,
+        /// Directory with the files to deliver
+        #[arg(long)]
+        dir: PathBuf,
+        /// Greeting shown above the file list
+        #[arg(long)]
+        greet: String,
+        /// Use this key instead of generating one
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// List the shares defined in the config file
+    List,
+    /// Remove a share from the config file
+    Remove { name: String },
+    /// Replace a share's key and print the new one
+    RotateKey {
+        name: String,
+        /// Use this key instead of generating one
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Add a further key with its own role and print it
+    AddKey {
+        /// Share to add the key to
+        name: String,
+        /// Name of the key, e.g. guests
+        key_name: String,
+        /// full, download, view or a role from [roles]
+        #[arg(long)]
+        role: String,
+        /// Use this key instead of generating one
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Remove a key added with add-key
+    RemoveKey { name: String, key_name: String },
+}
+
+#[derive(Subcommand)]
+pub enum LoginLinkCommand {
+    /// Print a link that logs in to the share once
+    Create {
+        share: String,
+        /// Key to log in with; the share's first key when omitted
+        #[arg(long)]
+        key: Option<String>,
+        /// Hours until the link expires
+        #[arg(long, default_value_t = DEFAULT_LINK_TTL.as_secs() / 3600)]
+        hours: u64,
+    },
+    /// Stop a login link from working before it is used
+    Revoke {
+        /// The link as printed by `create`, or just its nonce
+        link: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MailCommand {
+    /// Email the client that the gallery is ready, with a one-time login link
+    Ready {
+        share: String,
+        /// Key to log in with; the share's first key when omitted
+        #[arg(long)]
+        key: Option<String>,
+        /// Hours until the link expires
+        #[arg(long, default_value_t = DEFAULT_LINK_TTL.as_secs() / 3600)]
+        hours: u64,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommand {
+    /// Build the ZIP of every share's current contents ahead of time
+    Warm {
+        /// Only this share
+        #[arg(long)]
+        share: Option<String>,
+    },
+    /// Delete ZIPs and previews built for outdated share contents
+    Prune {
+        /// Only this share
+        #[arg(long)]
+        share: Option<String>,
+    },
+}
+
+pub async fn run(command: Command, config_path: Option<PathBuf>) -> ExitCode {
+    let result = match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Share(command) => run_share_command(command, config_path),
+        Command::HashKey { key } => hash_key_command(key),
+        Command::Cache(command) => run_cache_command(command, config_path).await,
+        Command::CheckConfig => check_config(config_path),
+        Command::LoginLink(command) => run_login_link_command(command, config_path).await,
+        Command::Mail(command) => run_mail_command(command, config_path).await,
+        Command::Link {
+            share,
+            file,
+            part,
+            format,
+            hours,
+            single_use,
+        } => {
+            let target = match file {
+                Some(name) => LinkTarget::File(name),
+                None => LinkTarget::Archive { format, part },
+            };
+            link_command(&share, target, hours, single_use, config_path).await
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn check_config(config_path: Option<PathBuf>) -> Result<(), String> {
+    let config = config::load(config_path.as_deref()).map_err(|e| e.to_string())?;
+    match &config_path {
+        Some(path) => println!("{}: OK", path.display()),
+        None => println!("environment configuration: OK"),
+    }
+    println!(
+        "{} share(s), {} listener(s)",
+        config.shares.len(),
+        config.listen.len()
+    );
+    Ok(())
+}
+
+async fn link_command(
+    share_name: &str,
+    target: LinkTarget,
+    hours: u64,
+    single_use: bool,
+    config_path: Option<PathBuf>,
+) -> Result<(), String> {
+    let config = config::load(config_path.as_deref()).map_err(|e| e.to_string())?;
+    let share = selected_shares(&config, Some(share_name))?.remove(0);
+
+    // Refuse links that could only ever fail
+    match &target {
+        LinkTarget::File(name) => {
+            if !matches!(validate_path(&share.dir, name).await, Ok(Some(_))) {
+                return Err(format!("{}: no file named '{}'", share.name, name));
+            }
+        }
+        LinkTarget::Archive { format, part } => {
+            match format.as_deref() {
+                None | Some("zip") => {}
+                Some("tar" | "tar.zst") if share.zip.password.is_some() => {
+                    return Err(format!("{}: only offered as encrypted ZIP", share.name));
+                }
+                Some("tar" | "tar.zst") => {}
+                Some(other) => return Err(format!("unknown archive format '{}'", other)),
+            }
+            if format.as_deref().is_none_or(|f| f == "zip") {
+                let index = ShareIndex::load(&share.dir)
+                    .await
+                    .map_err(|e| format!("{}: {}", share.name, e))?;
+                let parts = zip_part_numbers(&index, &share.zip);
+                if !parts.contains(part) {
+                    return Err(match part {
+                        None => format!(
+                            "{}: split into {} parts, pick one with --part",
+                            share.name,
+                            parts.len()
+                        ),
+                        Some(_) => format!("{}: no such part", share.name),
+                    });
+                }
+            }
+        }
+        LinkTarget::Login { .. } => {}
+    }
+
+    print_link(&config, &share.name, &target, hours, single_use)
+}
+
+fn print_link(
+    config: &Config,
+    share: &str,
+    target: &LinkTarget,
+    hours: u64,
+    single_use: bool,
+) -> Result<(), String> {
+    let secret = config
+        .link_secret
+        .as_deref()
+        .ok_or_else(|| "set server.link_secret (LINK_SECRET) to mint links".to_string())?;
+    if hours == 0 {
+        return Err("--hours must be greater than 0".to_string());
+    }
+    let seconds = hours
+        .checked_mul(3600)
+        .ok_or_else(|| "--hours is too large".to_string())?;
+
+    let link = mint_link(
+        secret,
+        share,
+        target,
+        Duration::from_secs(seconds),
+        single_use,
+    )
+    .map_err(|e| format!("{}: {}", share, e))?;
+    match &config.public_url {
+        Some(base) => println!("{}{}", base, link),
+        None => {
+            eprintln!("note: set server.public_url (PUBLIC_URL) to print full URLs");
+            println!("{}", link);
+        }
+    }
+    Ok(())
+}
+
+async fn run_login_link_command(
+    command: LoginLinkCommand,
+    config_path: Option<PathBuf>,
+) -> Result<(), String> {
+    let config = config::load(config_path.as_deref()).map_err(|e| e.to_string())?;
+
+    match command {
+        LoginLinkCommand::Create { share, key, hours } => {
+            let share = selected_shares(&config, Some(&share))?.remove(0);
+            if let Some(key) = &key
+                && !share.keys.iter().any(|k| &k.name == key)
+            {
+                return Err(format!("{}: no key named '{}'", share.name, key));
+            }
+            print_link(
+                &config,
+                &share.name,
+                &LinkTarget::Login { key },
+                hours,
+                true,
+            )
+        }
+        LoginLinkCommand::Revoke { link } => {
+            let used = UsedLinks::new(&config.state_dir);
+            let Some((_, query)) = link.split_once('?') else {
+                // A bare nonce doesn't say when the link expires
+                let fresh = used.revoke(&link).await.map_err(|e| e.to_string())?;
+                if fresh {
+                    println!("login link revoked");
+                } else {
+                    println!("login link was already used or revoked");
+                }
+                return Ok(());
+            };
+            let query = parse_link_query(query);
+            let (Some(share), Some(nonce), Some(expires)) =
+                (&query.share, &query.nonce, query.expires)
+            else {
+                return Err("not a login link".to_string());
+            };
+            let share = selected_shares(&config, Some(share))?.remove(0);
+
+            // A revoked link is one that counts as used
+            let fresh = used
+                .consume(nonce, expires)
+                .await
+                .map_err(|e| format!("{}: {}", share.name, e))?;
+            if fresh {
+                println!("{}: login link revoked", share.name);
+            } else {
+                println!("{}: login link was already used or revoked", share.name);
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn run_mail_command(
+    command: MailCommand,
+    config_path: Option<PathBuf>,
+) -> Result<(), String> {
+    let config = config::load(config_path.as_deref()).map_err(|e| e.to_string())?;
+
+    match command {
+        MailCommand::Ready { share, key, hours } => {
+            let share = selected_shares(&config, Some(&share))?.remove(0);
+            if hours == 0 {
+                return Err("--hours must be greater than 0".to_string());
+            }
+            let settings = config
+                .email
+                .as_ref()
+                .ok_or("configure [email] to send emails")?;
+            let transport = mail::transport(&settings.transport);
+            let to = mail::send_gallery_ready(
+                &config,
+                &*transport,
+                share,
+                key,
+                Duration::from_secs(hours.checked_mul(3600).ok_or("--hours is too large")?),
+            )
+            .await?;
+            println!("{}: sent the login link to {}", share.name, to);
+            Ok(())
+        }
+    }
+}
+
+fn hash_key_command(key: Option<String>) -> Result<(), String> {
+    let key = match key {
+        Some(key) => key,
+        None => {
+            let mut line = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(|e| format!("cannot read key from stdin: {}", e))?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    check_key_length(&key)?;
+    println!("{}", format_key_hash(&hash_key(&key)));
+    Ok(())
+}
+
+fn check_key_length(key: &str) -> Result<(), String> {
+    if key.chars().count() < config::MIN_KEY_LENGTH {
+        return Err(format!(
+            "key must be at least {} characters long",
+            config::MIN_KEY_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+fn run_share_command(command: ShareCommand, config_path: Option<PathBuf>) -> Result<(), String> {
+    // Share management always works on a file, creating the default one if needed
+    let path = config_path.unwrap_or_else(|| PathBuf::from("photo4share.toml"));
+    let mut doc = read_document(&path)?;
+
+    match command {
+        ShareCommand::List => {
+            let shares = doc.get("shares").and_then(Item::as_array_of_tables);
+            let Some(shares) = shares.filter(|s| !s.is_empty()) else {
+                println!("No shares in {}", path.display());
+                return Ok(());
+            };
+            for share in shares {
+                let field = |name: &str| share.get(name).and_then(Item::as_str).unwrap_or("-");
+                let extra_keys = share
+                    .get("keys")
+                    .and_then(Item::as_array_of_tables)
+                    .map_or(0, |keys| keys.len());
+                let own_key = share.contains_key("key_hash") || share.contains_key("key");
+                let key_kind = format!("{} key(s)", extra_keys + own_key as usize);
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    field("name"),
+                    field("dir"),
+                    key_kind,
+                    field("greet")
+                );
+            }
+            return Ok(());
+        }
+        ShareCommand::Add {
+            name,
+            dir,
+            greet,
+            key,
+        } => {
+            if !is_valid_share_name(&name) {
+                return Err(format!(
+                    "invalid share name '{}', use letters, digits, '-' and '_'",
+                    name
+                ));
+            }
+            if find_share_index(&doc, &name).is_some() {
+                return Err(format!("share '{}' already exists", name));
+            }
+            if !dir.is_dir() {
+                return Err(format!("{} is not a directory", dir.display()));
+            }
+            let key = key.unwrap_or_else(generate_share_key);
+            check_key_length(&key)?;
+
+            let mut table = Table::new();
+            table["name"] = value(name.as_str());
+            table["dir"] = value(dir.to_string_lossy().as_ref());
+            table["greet"] = value(greet);
+            table["key_hash"] = value(format_key_hash(&hash_key(&key)));
+
+            let shares = doc
+                .entry("shares")
+                .or_insert(Item::ArrayOfTables(Default::default()))
+                .as_array_of_tables_mut()
+                .ok_or("'shares' in the config file is not an array of tables")?;
+            shares.push(table);
+
+            write_document(&path, &doc)?;
+            println!("Added share '{}'. Access key: {}", name, key);
+        }
+        ShareCommand::Remove { name } => {
+            let index = find_share_index(&doc, &name)
+                .ok_or_else(|| format!("share '{}' not found in {}", name, path.display()))?;
+            shares_mut(&mut doc)?.remove(index);
+            write_document(&path, &doc)?;
+            println!("Removed share '{}'", name);
+        }
+        ShareCommand::RotateKey { name, key } => {
+            let index = find_share_index(&doc, &name)
+                .ok_or_else(|| format!("share '{}' not found in {}", name, path.display()))?;
+            let key = key.unwrap_or_else(generate_share_key);
+            check_key_length(&key)?;
+
+            let share = shares_mut(&mut doc)?
+                .get_mut(index)
+                .ok_or("share disappeared while editing")?;
+            share.remove("key");
+            share["key_hash"] = value(format_key_hash(&hash_key(&key)));
+
+            write_document(&path, &doc)?;
+            println!("New access key for '{}': {}", name, key);
+        }
+        ShareCommand::AddKey {
+            name,
+            key_name,
+            role,
+            key,
+        } => {
+            if !is_valid_share_name(&key_name) || key_name == config::OWNER_KEY_NAME {
+                return Err(format!(
+                    "invalid key name '{}', use letters, digits, '-' and '_'",
+                    key_name
+                ));
+            }
+            let index = find_share_index(&doc, &name)
+                .ok_or_else(|| format!("share '{}' not found in {}", name, path.display()))?;
+            let key = key.unwrap_or_else(generate_share_key);
+            check_key_length(&key)?;
+
+            let share = shares_mut(&mut doc)?
+                .get_mut(index)
+                .ok_or("share disappeared while editing")?;
+            let keys = share
+                .entry("keys")
+                .or_insert(Item::ArrayOfTables(Default::default()))
+                .as_array_of_tables_mut()
+                .ok_or("'keys' of the share is not an array of tables")?;
+            if keys
+                .iter()
+                .any(|k| k.get("name").and_then(Item::as_str) == Some(key_name.as_str()))
+            {
+                return Err(format!("share '{}' already has a key '{}'", name, key_name));
+            }
+            let mut table = Table::new();
+            table["name"] = value(key_name.as_str());
+            table["role"] = value(role.as_str());
+            table["key_hash"] = value(format_key_hash(&hash_key(&key)));
+            keys.push(table);
+
+            write_document(&path, &doc)?;
+            println!(
+                "Added key '{}' ({}) to '{}'. Access key: {}",
+                key_name, role, name, key
+            );
+        }
+        ShareCommand::RemoveKey { name, key_name } => {
+            let index = find_share_index(&doc, &name)
+                .ok_or_else(|| format!("share '{}' not found in {}", name, path.display()))?;
+            let share = shares_mut(&mut doc)?
+                .get_mut(index)
+                .ok_or("share disappeared while editing")?;
+            let keys = share
+                .get_mut("keys")
+                .and_then(Item::as_array_of_tables_mut)
+                .ok_or_else(|| format!("share '{}' has no added keys", name))?;
+            let position = keys
+                .iter()
+                .position(|k| k.get("name").and_then(Item::as_str) == Some(key_name.as_str()))
+                .ok_or_else(|| format!("share '{}' has no key '{}'", name, key_name))?;
+            keys.remove(position);
+            if keys.is_empty() {
+                share.remove("keys");
+            }
+
+            write_document(&path, &doc)?;
+            println!("Removed key '{}' from '{}'", key_name, name);
+        }
+    }
+
+    Ok(())
+}
+
+fn read_document(path: &Path) -> Result<DocumentMut, String> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => contents
+            .parse::<DocumentMut>()
+            .map_err(|e| format!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DocumentMut::new()),
+        Err(e) => Err(format!("cannot read {}: {}", path.display(), e)),
+    }
+}
+
+/// Writes through a temp file so a crash never leaves a truncated config.
+fn write_document(path: &Path, doc: &DocumentMut) -> Result<(), String> {
+    let temp = path.with_extension("toml.tmp");
+    std::fs::write(&temp, doc.to_string())
+        .map_err(|e| format!("cannot write {}: {}", temp.display(), e))?;
+    if let Ok(meta) = std::fs::metadata(path) {
+        let _ = std::fs::set_permissions(&temp, meta.permissions());
+    }
+    std::fs::rename(&temp, path).map_err(|e| format!("cannot replace {}: {}", path.display(), e))
+}
+
+fn find_share_index(doc: &DocumentMut, name: &str) -> Option<usize> {
+    doc.get("shares")?
+        .as_array_of_tables()?
+        .iter()
+        .position(|share| share.get("name").and_then(Item::as_str) == Some(name))
+}
+
+fn shares_mut(doc: &mut DocumentMut) -> Result<&mut toml_edit::ArrayOfTables, String> {
+    doc.get_mut("shares")
+        .and_then(Item::as_array_of_tables_mut)
+        .ok_or_else(|| "no shares in the config file".to_string())
+}
+
+async fn run_cache_command(
+    command: CacheCommand,
+    config_path: Option<PathBuf>,
+) -> Result<(), String> {
+    let config = config::load(config_path.as_deref()).map_err(|e| e.to_string())?;
+
+    match command {
+        CacheCommand::Warm { share } => {
+            for share in selected_shares(&config, share.as_deref())? {
+                let index = ShareIndex::load(&share.dir)
+                    .await
+                    .map_err(|e| format!("{}: {}", share.name, e))?;
+                for part in zip_part_numbers(&index, &share.zip) {
+                    match ensure_cached_zip(&index, &share.zip, part).await {
+                        Ok(path) => {
+                            let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                            println!("{}: {} ({} bytes)", share.name, path.display(), size);
+                        }
+                        Err(e) => return Err(format!("{}: {}", share.name, e.message())),
+                    }
+                }
+            }
+        }
+        CacheCommand::Prune { share } => {
+            for share in selected_shares(&config, share.as_deref())? {
+                let index = ShareIndex::load(&share.dir)
+                    .await
+                    .map_err(|e| format!("{}: {}", share.name, e))?;
+                let (removed, freed) = prune_zip_cache(&index, &share.zip)
+                    .await
+                    .map_err(|e| format!("{}: {}", share.name, e))?;
+                let previews = prune_previews(&share.dir, &index.files())
+                    .await
+                    .map_err(|e| format!("{}: {}", share.name, e))?;
+                println!(
+                    "{}: removed {} archive(s) and {} preview(s), freed {} bytes",
+                    share.name, removed, previews, freed
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn selected_shares<'a>(
+    config: &'a Config,
+    name: Option<&str>,
+) -> Result<Vec<&'a crate::models::Share>, String> {
+    match name {
+        Some(name) => config
+            .shares
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| vec![s.as_ref()])
+            .ok_or_else(|| format!("share '{}' not found", name)),
+        None => Ok(config.shares.iter().map(|s| s.as_ref()).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "a key long enough";
+    const OTHER_KEY: &str = "another key long enough";
+
+    /// A config file with a comment in a scratch directory that also serves
+    /// as the share directory.
+    fn config_file(name: &str) -> (PathBuf, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("photo4share-cli-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("photo4share.toml");
+        std::fs::write(&path, "# deliveries\n").unwrap();
+        (dir, path)
+    }
+
+    fn share_command(command: ShareCommand, path: &Path) -> Result<DocumentMut, String> {
+        run_share_command(command, Some(path.to_path_buf()))?;
+        read_document(path)
+    }
+
+    fn add(name: &str, dir: &Path, key: Option<&str>) -> ShareCommand {
+        ShareCommand::Add {
+            name: name.to_string(),
+            dir: dir.to_path_buf(),
+            greet: "Hi".to_string(),
+            key: key.map(str::to_string),
+        }
+    }
+
+    fn share<'a>(doc: &'a DocumentMut, name: &str) -> &'a Table {
+        let index = find_share_index(doc, name).unwrap();
+        doc["shares"]
+            .as_array_of_tables()
+            .unwrap()
+            .get(index)
+            .unwrap()
+    }
+
+    fn key_hash(key: &str) -> String {
+        format_key_hash(&hash_key(key))
+    }
+
+    #[test]
+    fn share_commands_edit_the_config_file_in_place() {
+        let (dir, path) = config_file("edit");
+
+        let doc = share_command(add("s1", &dir, Some(KEY)), &path).unwrap();
+        assert!(doc.to_string().contains("# deliveries\n"));
+        assert_eq!(
+            share(&doc, "s1")["key_hash"].as_str(),
+            Some(key_hash(KEY).as_str())
+        );
+        assert_eq!(share(&doc, "s1")["dir"].as_str(), dir.to_str());
+
+        let rotate = ShareCommand::RotateKey {
+            name: "s1".to_string(),
+            key: Some(OTHER_KEY.to_string()),
+        };
+        let doc = share_command(rotate, &path).unwrap();
+        assert_eq!(
+            share(&doc, "s1")["key_hash"].as_str(),
+            Some(key_hash(OTHER_KEY).as_str())
+        );
+
+        let add_key = ShareCommand::AddKey {
+            name: "s1".to_string(),
+            key_name: "guests".to_string(),
+            role: "view".to_string(),
+            key: Some(KEY.to_string()),
+        };
+        let doc = share_command(add_key, &path).unwrap();
+        let keys = share(&doc, "s1")["keys"].as_array_of_tables().unwrap();
+        assert_eq!(keys.len(), 1);
+        let guests = keys.get(0).unwrap();
+        assert_eq!(guests["name"].as_str(), Some("guests"));
+        assert_eq!(guests["role"].as_str(), Some("view"));
+        assert_eq!(guests["key_hash"].as_str(), Some(key_hash(KEY).as_str()));
+
+        // The edited file is still a valid config
+        config::load(Some(&path)).unwrap();
+
+        let remove_key = ShareCommand::RemoveKey {
+            name: "s1".to_string(),
+            key_name: "guests".to_string(),
+        };
+        let doc = share_command(remove_key, &path).unwrap();
+        assert!(!share(&doc, "s1").contains_key("keys"));
+
+        let doc = share_command(
+            ShareCommand::Remove {
+                name: "s1".to_string(),
+            },
+            &path,
+        )
+        .unwrap();
+        assert!(find_share_index(&doc, "s1").is_none());
+        assert!(!dir.join("photo4share.toml.tmp").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn share_commands_reject_bad_input_without_touching_the_file() {
+        let (dir, path) = config_file("reject");
+        share_command(add("s1", &dir, Some(KEY)), &path).unwrap();
+        let before = std::fs::read_to_string(&path).unwrap();
+
+        let add_key = |key_name: &str, key: &str| ShareCommand::AddKey {
+            name: "s1".to_string(),
+            key_name: key_name.to_string(),
+            role: "view".to_string(),
+            key: Some(key.to_string()),
+        };
+        let cases = [
+            (add("bad name", &dir, Some(KEY)), "invalid share name"),
+            (add("s1", &dir, Some(KEY)), "already exists"),
+            (
+                add("s2", &dir.join("missing"), Some(KEY)),
+                "is not a directory",
+            ),
+            (add("s2", &dir, Some("short")), "at least"),
+            (
+                ShareCommand::Remove {
+                    name: "s2".to_string(),
+                },
+                "share 's2' not found",
+            ),
+            (
+                ShareCommand::RotateKey {
+                    name: "s1".to_string(),
+                    key: Some("short".to_string()),
+                },
+                "at least",
+            ),
+            (add_key(config::OWNER_KEY_NAME, KEY), "invalid key name"),
+            (add_key("guests", "short"), "at least"),
+            (
+                ShareCommand::RemoveKey {
+                    name: "s1".to_string(),
+                    key_name: "guests".to_string(),
+                },
+                "has no added keys",
+            ),
+        ];
+        for (command, expected) in cases {
+            let error = run_share_command(command, Some(path.clone())).unwrap_err();
+            assert!(error.contains(expected), "{:?} lacks {:?}", error, expected);
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), before);
+
+        run_share_command(add_key("guests", KEY), Some(path.clone())).unwrap();
+        let error =
+            run_share_command(add_key("guests", OTHER_KEY), Some(path.clone())).unwrap_err();
+        assert!(error.contains("already has a key 'guests'"), "{}", error);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn generated_keys_are_long_enough() {
+        assert!(check_key_length(&generate_share_key()).is_ok());
+        assert!(check_key_length(&"x".repeat(config::MIN_KEY_LENGTH)).is_ok());
+        assert!(check_key_length(&"x".repeat(config::MIN_KEY_LENGTH - 1)).is_err());
+    }
+
+    #[test]
+    fn subcommands_parse_from_the_command_line() {
+        let cli = Cli::try_parse_from([
+            "photo4share",
+            "--config",
+            "deliveries.toml",
+            "share",
+            "add-key",
+            "s1",
+            "guests",
+            "--role",
+            "view",
+        ])
+        .unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("deliveries.toml")));
+        assert!(matches!(
+            cli.command,
+            Some(Command::Share(ShareCommand::AddKey { ref name, ref key_name, ref role, key: None }))
+                if name == "s1" && key_name == "guests" && role == "view"
+        ));
+
+        let cli = Cli::try_parse_from(["photo4share"]).unwrap();
+        assert!(cli.command.is_none());
+
+        // A share's file and its archive are different links
+        let conflicting = [
+            "photo4share",
+            "link",
+            "s1",
+            "--file",
+            "a.jpg",
+            "--part",
+            "1",
+        ];
+        assert!(Cli::try_parse_from(conflicting).is_err());
+    }
+}
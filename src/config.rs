@@ -0,0 +1,1643 @@
+use crate::auth::KEY_HASH_PREFIX;
+use crate::auth::hash_key;
+use crate::auth::parse_key_hash;
+use crate::listener::ListenAddr;
+use crate::listener::parse_listen_list;
+use crate::mail::MailSettings;
+use crate::mail::SmtpSecurity;
+use crate::mail::SmtpSettings;
+use crate::mail::TransportSettings;
+use crate::models::Permission;
+use crate::models::Share;
+use crate::models::ShareKey;
+use crate::models::UploadConfig;
+use crate::tls::TlsSettings;
+use crate::webhooks::Webhook;
+use crate::webhooks::WebhookEvent;
+use crate::zip_utils::ZipCompression;
+use crate::zip_utils::ZipOptions;
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Used when neither `--config` nor `PHOTO4SHARE_CONFIG` is given.
+const DEFAULT_CONFIG_PATH: &str = "photo4share.toml";
+/// Used when `server.state_dir` isn't set, relative like the config path.
+const DEFAULT_STATE_DIR: &str = "photo4share-state";
+/// Share keys are typed or pasted by clients, but still must not be guessable.
+pub const MIN_KEY_LENGTH: usize = 16;
+/// AES only protects the archive as well as its password does.
+const MIN_ZIP_PASSWORD_LENGTH: usize = 12;
+/// Anyone holding the link secret can mint download links for every share.
+const MIN_LINK_SECRET_LENGTH: usize = 32;
+/// Anyone holding a webhook secret can forge events to its receiver.
+const MIN_WEBHOOK_SECRET_LENGTH: usize = 16;
+
+/// Name of the share configured through `SHARE_DIR`/`SHARE_KEY`/`GREET`.
+pub const ENV_SHARE_NAME: &str = "default";
+/// Name and role of a share's own `key`/`key_hash` among its keys.
+pub const OWNER_KEY_NAME: &str = "owner";
+const OWNER_ROLE: &str = "full";
+
+/// Roles every configuration has; `[roles]` can add more.
+const BUILTIN_ROLES: [(&str, &[Permission]); 3] = [
+    ("full", &Permission::ALL),
+    (
+        "download",
+        &[
+            Permission::List,
+            Permission::Preview,
+            Permission::Download,
+            Permission::DownloadZip,
+        ],
+    ),
+    ("view", &[Permission::List, Permission::Preview]),
+];
+
+/// Raw contents of the TOML file. Every value is optional here, since it may
+/// come from an environment variable instead.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub shares: Vec<ShareSection>,
+    /// Role name to its permissions, next to the built-in roles.
+    pub roles: BTreeMap<String, Vec<String>>,
+    pub server: ServerSection,
+    pub cache: CacheSection,
+    pub activity: ActivitySection,
+    pub webhooks: Vec<WebhookSection>,
+    pub email: EmailSection,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShareSection {
+    pub name: Option<String>,
+    pub dir: Option<PathBuf>,
+    /// Plain access key; prefer `key_hash` so the file holds no secrets.
+    pub key: Option<String>,
+    /// Output of `photo4share hash-key`.
+    pub key_hash: Option<String>,
+    pub greet: Option<String>,
+    /// `store`, `deflate`, `zstd` or `auto`.
+    pub compression: Option<String>,
+    /// Encrypts the ZIP with AES-256; tell the client out of band.
+    pub zip_password: Option<String>,
+    /// Deliver the share as several ZIPs of at most this size.
+    pub zip_part_max_mb: Option<u64>,
+    /// Offer a B3SUMS manifest next to SHA256SUMS.
+    pub b3sums: Option<bool>,
+    /// Key cached archives on file contents, not just their metadata.
+    pub hash_contents: Option<bool>,
+    /// Further keys with their own roles, e.g. view-only ones for guests.
+    pub keys: Vec<KeySection>,
+    pub upload: Option<UploadSection>,
+    /// The client's address, for the "gallery ready" and expiry emails.
+    pub email: Option<String>,
+    /// Last day the share can be opened, as `YYYY-MM-DD`.
+    pub expires: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeySection {
+    pub name: Option<String>,
+    pub key: Option<String>,
+    pub key_hash: Option<String>,
+    /// `full`, `download`, `view` or one from `[roles]`.
+    pub role: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub listen: Option<Vec<String>>,
+    pub unix_socket_mode: Option<String>,
+    pub shutdown_timeout_secs: Option<u64>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Key of signed download links; links are disabled without it.
+    pub link_secret: Option<String>,
+    /// Address clients reach the server at, e.g. `https://photos.example.com`.
+    pub public_url: Option<String>,
+    /// Unlocks `/admin` and `/api/v1/admin`; prefer `admin_key_hash`.
+    pub admin_key: Option<String>,
+    pub admin_key_hash: Option<String>,
+    /// Where the server keeps its own state, outside every share.
+    pub state_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSection {
+    pub max_total_mb: Option<u64>,
+    pub max_age_hours: Option<u64>,
+    pub keep_latest_only: Option<bool>,
+    pub eviction_interval_secs: Option<u64>,
+    pub prebuild: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActivitySection {
+    pub enabled: Option<bool>,
+    /// Truncate client addresses and leave out user agents.
+    pub anonymize: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmailSection {
+    /// `smtp`, or `file` to write `.eml` files into `dir` instead.
+    pub transport: Option<String>,
+    pub dir: Option<PathBuf>,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    /// `starttls`, `tls` or `none`.
+    pub smtp_security: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// Sender of every email, e.g. `Studio <studio@example.com>`.
+    pub from: Option<String>,
+    /// Gets a notice when a client has downloaded everything.
+    pub photographer: Option<String>,
+    /// Days before a share's last day its client is reminded.
+    pub reminder_days: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookSection {
+    pub url: Option<String>,
+    /// Key of the payload signatures, shared with the receiver.
+    pub secret: Option<String>,
+    /// `login`, `download.complete`, `archive.complete` and
+    /// `selection.submitted`; all of them if left out.
+    pub events: Option<Vec<String>>,
+    /// Names of the shares to report on; all of them if left out.
+    pub shares: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadSection {
+    pub enabled: Option<bool>,
+    pub quota_mb: Option<u64>,
+    pub max_file_mb: Option<u64>,
+    pub allowed_extensions: Option<Vec<String>>,
+    pub notify_command: Option<String>,
+}
+
+/// Validated configuration the server runs with.
+#[derive(Clone)]
+pub struct Config {
+    pub shares: Vec<Arc<Share>>,
+    pub listen: Vec<ListenAddr>,
+    pub unix_socket_mode: Option<u32>,
+    pub shutdown_timeout: Duration,
+    pub tls: Option<TlsSettings>,
+    pub cache: CacheSettings,
+    pub link_secret: Option<String>,
+    /// Without a trailing slash.
+    pub public_url: Option<String>,
+    /// Hash of the admin key, see `auth::hash_key`.
+    pub admin_key_hash: Option<[u8; 32]>,
+    /// Used single-use links and other state that isn't a share's.
+    pub state_dir: PathBuf,
+    pub activity: ActivitySettings,
+    pub webhooks: Vec<Webhook>,
+    /// `None` unless `[email]` is configured.
+    pub email: Option<MailSettings>,
+}
+
+/// Eviction policy for the `.zipcache` directories of all shares.
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+    /// Upper bound for all cached archives together.
+    pub max_total_bytes: Option<u64>,
+    /// Archives unused for longer than this are removed.
+    pub max_age: Option<Duration>,
+    /// Remove archives built from anything but a share's latest contents.
+    pub keep_latest_only: bool,
+    pub eviction_interval: Duration,
+    /// Build archives in the background as soon as a share changes.
+    pub prebuild: bool,
+}
+
+/// What goes into the per-share activity logs.
+#[derive(Clone, Debug)]
+pub struct ActivitySettings {
+    pub enabled: bool,
+    pub anonymize: bool,
+}
+
+/// Every problem found while loading, so they can all be fixed in one go.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration ({} problem(s)):", self.0.len())?;
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Resolves the config file path: explicit path first, then the default file
+/// if it exists. `None` means configuration comes from the environment only.
+pub fn resolve_path(explicit: Option<&Path>) -> Option<PathBuf> {
+    match explicit {
+        Some(path) => Some(path.to_path_buf()),
+        None => {
+            let default = PathBuf::from(DEFAULT_CONFIG_PATH);
+            default.exists().then_some(default)
+        }
+    }
+}
+
+/// Reads the TOML file (if any), applies environment overrides and validates.
+pub fn load(path: Option<&Path>) -> Result<Config, ConfigErrors> {
+    let mut errors = Vec::new();
+
+    let mut file = match path {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(contents) => match toml::from_str::<ConfigFile>(&contents) {
+                Ok(file) => file,
+                Err(e) => {
+                    errors.push(format!("{}: {}", path.display(), e.message()));
+                    ConfigFile::default()
+                }
+            },
+            Err(e) => {
+                errors.push(format!("cannot read config file {}: {}", path.display(), e));
+                ConfigFile::default()
+            }
+        },
+        None => ConfigFile::default(),
+    };
+
+    apply_env_overrides(&mut file, &|name| env::var(name).ok(), &mut errors);
+    let config = validate(file, &mut errors);
+
+    match config {
+        Some(config) if errors.is_empty() => Ok(config),
+        _ => Err(ConfigErrors(errors)),
+    }
+}
+
+/// Looks up an environment variable; the real environment outside of tests.
+type Vars<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+fn apply_env_overrides(file: &mut ConfigFile, vars: Vars, errors: &mut Vec<String>) {
+    const SHARE_VARS: [&str; 13] = [
+        "SHARE_DIR",
+        "SHARE_KEY",
+        "GREET",
+        "ZIP_COMPRESSION",
+        "ZIP_PASSWORD",
+        "ZIP_PART_MAX_MB",
+        "B3SUMS",
+        "HASH_CONTENTS",
+        "UPLOAD_ENABLED",
+        "UPLOAD_QUOTA_MB",
+        "UPLOAD_MAX_FILE_MB",
+        "UPLOAD_ALLOWED_EXTENSIONS",
+        "UPLOAD_NOTIFY_COMMAND",
+    ];
+
+    // The single-share environment variables predate the config file and
+    // map onto the share called "default", creating it when necessary
+    if SHARE_VARS.iter().any(|var| vars(var).is_some()) {
+        let index = match file
+            .shares
+            .iter()
+            .position(|s| s.name.as_deref() == Some(ENV_SHARE_NAME))
+        {
+            Some(index) => index,
+            None => {
+                file.shares.push(ShareSection {
+                    name: Some(ENV_SHARE_NAME.to_string()),
+                    ..Default::default()
+                });
+                file.shares.len() - 1
+            }
+        };
+        let share = &mut file.shares[index];
+
+        override_with(
+            &mut share.dir,
+            env_string(vars, "SHARE_DIR").map(PathBuf::from),
+        );
+        if let Some(key) = env_string(vars, "SHARE_KEY") {
+            share.key = Some(key);
+            share.key_hash = None;
+        }
+        override_with(&mut share.greet, env_string(vars, "GREET"));
+        override_with(&mut share.compression, env_string(vars, "ZIP_COMPRESSION"));
+        override_with(&mut share.zip_password, env_string(vars, "ZIP_PASSWORD"));
+        override_with(
+            &mut share.zip_part_max_mb,
+            env_parse(vars, "ZIP_PART_MAX_MB", errors),
+        );
+        override_with(&mut share.b3sums, env_bool(vars, "B3SUMS", errors));
+        override_with(
+            &mut share.hash_contents,
+            env_bool(vars, "HASH_CONTENTS", errors),
+        );
+
+        let upload = share.upload.get_or_insert_with(UploadSection::default);
+        override_with(
+            &mut upload.enabled,
+            env_bool(vars, "UPLOAD_ENABLED", errors),
+        );
+        override_with(
+            &mut upload.quota_mb,
+            env_parse(vars, "UPLOAD_QUOTA_MB", errors),
+        );
+        override_with(
+            &mut upload.max_file_mb,
+            env_parse(vars, "UPLOAD_MAX_FILE_MB", errors),
+        );
+        override_with(
+            &mut upload.allowed_extensions,
+            env_string(vars, "UPLOAD_ALLOWED_EXTENSIONS").map(|v| split_list(&v)),
+        );
+        override_with(
+            &mut upload.notify_command,
+            env_string(vars, "UPLOAD_NOTIFY_COMMAND"),
+        );
+    }
+
+    override_with(
+        &mut file.server.listen,
+        env_string(vars, "LISTEN").map(|v| split_list(&v)),
+    );
+    override_with(
+        &mut file.server.unix_socket_mode,
+        env_string(vars, "UNIX_SOCKET_MODE"),
+    );
+    override_with(
+        &mut file.server.shutdown_timeout_secs,
+        env_parse(vars, "SHUTDOWN_TIMEOUT_SECS", errors),
+    );
+    override_with(
+        &mut file.server.tls_cert,
+        env_string(vars, "TLS_CERT").map(PathBuf::from),
+    );
+    override_with(
+        &mut file.server.tls_key,
+        env_string(vars, "TLS_KEY").map(PathBuf::from),
+    );
+    override_with(
+        &mut file.server.link_secret,
+        env_string(vars, "LINK_SECRET"),
+    );
+    override_with(&mut file.server.public_url, env_string(vars, "PUBLIC_URL"));
+    override_with(
+        &mut file.server.state_dir,
+        env_string(vars, "STATE_DIR").map(PathBuf::from),
+    );
+    if let Some(key) = env_string(vars, "ADMIN_KEY") {
+        file.server.admin_key = Some(key);
+        file.server.admin_key_hash = None;
+    }
+    override_with(
+        &mut file.email.transport,
+        env_string(vars, "MAIL_TRANSPORT"),
+    );
+    override_with(
+        &mut file.email.dir,
+        env_string(vars, "MAIL_DIR").map(PathBuf::from),
+    );
+    override_with(&mut file.email.smtp_host, env_string(vars, "SMTP_HOST"));
+    override_with(
+        &mut file.email.smtp_port,
+        env_parse(vars, "SMTP_PORT", errors),
+    );
+    override_with(
+        &mut file.email.smtp_security,
+        env_string(vars, "SMTP_SECURITY"),
+    );
+    override_with(
+        &mut file.email.smtp_username,
+        env_string(vars, "SMTP_USERNAME"),
+    );
+    override_with(
+        &mut file.email.smtp_password,
+        env_string(vars, "SMTP_PASSWORD"),
+    );
+    override_with(&mut file.email.from, env_string(vars, "MAIL_FROM"));
+    override_with(
+        &mut file.email.photographer,
+        env_string(vars, "MAIL_PHOTOGRAPHER"),
+    );
+    override_with(
+        &mut file.email.reminder_days,
+        env_parse(vars, "MAIL_REMINDER_DAYS", errors),
+    );
+    override_with(
+        &mut file.activity.enabled,
+        env_bool(vars, "ACTIVITY_LOG", errors),
+    );
+    override_with(
+        &mut file.activity.anonymize,
+        env_bool(vars, "ACTIVITY_ANONYMIZE", errors),
+    );
+
+    override_with(
+        &mut file.cache.max_total_mb,
+        env_parse(vars, "ZIP_CACHE_MAX_MB", errors),
+    );
+    override_with(
+        &mut file.cache.max_age_hours,
+        env_parse(vars, "ZIP_CACHE_MAX_AGE_HOURS", errors),
+    );
+    override_with(
+        &mut file.cache.keep_latest_only,
+        env_bool(vars, "ZIP_CACHE_KEEP_LATEST_ONLY", errors),
+    );
+    override_with(
+        &mut file.cache.eviction_interval_secs,
+        env_parse(vars, "ZIP_CACHE_EVICTION_INTERVAL_SECS", errors),
+    );
+    override_with(
+        &mut file.cache.prebuild,
+        env_bool(vars, "ZIP_CACHE_PREBUILD", errors),
+    );
+}
+
+fn validate(file: ConfigFile, errors: &mut Vec<String>) -> Option<Config> {
+    if file.shares.is_empty() {
+        errors.push(
+            "no shares configured, add a [[shares]] table or set SHARE_DIR, SHARE_KEY and GREET"
+                .to_string(),
+        );
+    }
+
+    let roles = validate_roles(file.roles, errors);
+
+    let mut shares: Vec<Arc<Share>> = Vec::with_capacity(file.shares.len());
+    for (index, section) in file.shares.into_iter().enumerate() {
+        if let Some(share) = validate_share(index, section, &roles, errors) {
+            if shares.iter().any(|s| s.name == share.name) {
+                errors.push(format!("share '{}' is defined more than once", share.name));
+            }
+            // A key must tell its share apart, or logins would be ambiguous
+            for key in &share.keys {
+                let same = shares
+                    .iter()
+                    .find(|s| s.keys.iter().any(|k| k.key_hash == key.key_hash));
+                if let Some(other) = same {
+                    errors.push(format!(
+                        "shares '{}' and '{}' use the same key",
+                        other.name, share.name
+                    ));
+                }
+            }
+            shares.push(Arc::new(share));
+        }
+    }
+
+    let listen = match file.server.listen {
+        Some(list) => match parse_listen_list(&list.join(",")) {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                errors.push(format!("server.listen (LISTEN): {}", e));
+                vec![]
+            }
+        },
+        None => vec![ListenAddr::Tcp(([0, 0, 0, 0], 3000).into())],
+    };
+
+    let unix_socket_mode = file
+        .server
+        .unix_socket_mode
+        .and_then(|mode| match u32::from_str_radix(&mode, 8) {
+            Ok(m) if m <= 0o777 => Some(m),
+            _ => {
+                errors.push(format!(
+                    "server.unix_socket_mode (UNIX_SOCKET_MODE) '{}' is not an octal mode like 660",
+                    mode
+                ));
+                None
+            }
+        });
+
+    let tls = match (file.server.tls_cert, file.server.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            check_readable_file(&cert_path, "server.tls_cert (TLS_CERT)", errors);
+            check_readable_file(&key_path, "server.tls_key (TLS_KEY)", errors);
+            Some(TlsSettings {
+                cert_path,
+                key_path,
+            })
+        }
+        (None, None) => None,
+        _ => {
+            errors.push(
+                "server.tls_cert (TLS_CERT) and server.tls_key (TLS_KEY) must be set together"
+                    .to_string(),
+            );
+            None
+        }
+    };
+
+    if let Some(secret) = &file.server.link_secret
+        && secret.chars().count() < MIN_LINK_SECRET_LENGTH
+    {
+        errors.push(format!(
+            "server.link_secret (LINK_SECRET) must be at least {} characters long",
+            MIN_LINK_SECRET_LENGTH
+        ));
+    }
+    let public_url = file
+        .server
+        .public_url
+        .map(|url| url.trim_end_matches('/').to_string());
+    if let Some(url) = &public_url
+        && !url.starts_with("https://")
+        && !url.starts_with("http://")
+    {
+        errors.push(format!(
+            "server.public_url (PUBLIC_URL) '{}' must start with https:// or http://",
+            url
+        ));
+    }
+
+    let admin_key_hash = match (&file.server.admin_key, &file.server.admin_key_hash) {
+        (None, None) => None,
+        _ => parse_key(
+            "server.admin_key (ADMIN_KEY)",
+            file.server.admin_key,
+            file.server.admin_key_hash,
+            errors,
+        ),
+    };
+    if let Some(hash) = admin_key_hash
+        && shares
+            .iter()
+            .any(|s| s.keys.iter().any(|k| k.key_hash == hash))
+    {
+        errors.push("server.admin_key (ADMIN_KEY) is also a share key".to_string());
+    }
+
+    let webhooks = file
+        .webhooks
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, section)| validate_webhook(index, section, &shares, errors))
+        .collect::<Vec<_>>();
+    for (i, webhook) in webhooks.iter().enumerate() {
+        // Queued deliveries find their secret by URL
+        if webhooks[..i].iter().any(|w| w.url == webhook.url) {
+            errors.push(format!(
+                "webhook {} is configured more than once",
+                webhook.url
+            ));
+        }
+    }
+
+    let activity = ActivitySettings {
+        enabled: file.activity.enabled.unwrap_or(true),
+        anonymize: file.activity.anonymize.unwrap_or(true),
+    };
+    let email = validate_email(file.email, &activity, errors);
+
+    let eviction_interval_secs = file.cache.eviction_interval_secs.unwrap_or(3600);
+    if eviction_interval_secs == 0 {
+        errors.push(
+            "cache.eviction_interval_secs (ZIP_CACHE_EVICTION_INTERVAL_SECS) must be greater than 0"
+                .to_string(),
+        );
+    }
+    let cache = CacheSettings {
+        max_total_bytes: file
+            .cache
+            .max_total_mb
+            .map(|mb| mb_to_bytes(mb, "cache.max_total_mb (ZIP_CACHE_MAX_MB)", errors)),
+        max_age: file.cache.max_age_hours.map(|hours| {
+            Duration::from_secs(scaled(
+                hours,
+                3600,
+                "cache.max_age_hours (ZIP_CACHE_MAX_AGE_HOURS)",
+                errors,
+            ))
+        }),
+        keep_latest_only: file.cache.keep_latest_only.unwrap_or(true),
+        eviction_interval: Duration::from_secs(eviction_interval_secs),
+        prebuild: file.cache.prebuild.unwrap_or(true),
+    };
+
+    Some(Config {
+        shares,
+        listen,
+        unix_socket_mode,
+        shutdown_timeout: Duration::from_secs(file.server.shutdown_timeout_secs.unwrap_or(300)),
+        tls,
+        cache,
+        link_secret: file.server.link_secret,
+        public_url,
+        admin_key_hash,
+        state_dir: file
+            .server
+            .state_dir
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR)),
+        activity,
+        webhooks,
+        email,
+    })
+}
+
+/// A bare address or `Name <address>`, safe to put in a header.
+fn is_valid_mailbox(mailbox: &str) -> bool {
+    let address = crate::mail::address(mailbox);
+    !mailbox.contains(['\r', '\n'])
+        && (!mailbox.contains('<') || mailbox.trim_end().ends_with('>'))
+        && !address.contains([' ', '<', '>'])
+        && address
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && domain.contains('.'))
+}
+
+/// The `[email]` section, if configured. Telling the photographer about
+/// finished downloads needs the activity log to know what was downloaded.
+fn validate_email(
+    section: EmailSection,
+    activity: &ActivitySettings,
+    errors: &mut Vec<String>,
+) -> Option<MailSettings> {
+    let transport = match section.transport.as_deref() {
+        None if section.smtp_host.is_none() && section.dir.is_none() => {
+            if section.from.is_some() || section.photographer.is_some() {
+                errors.push("email.smtp_host (SMTP_HOST) is not set".to_string());
+            }
+            return None;
+        }
+        None | Some("smtp") => {
+            let security = match section.smtp_security.as_deref().map(SmtpSecurity::from_str) {
+                Some(Ok(security)) => security,
+                Some(Err(e)) => {
+                    errors.push(format!("email.smtp_security (SMTP_SECURITY): {}", e));
+                    SmtpSecurity::StartTls
+                }
+                None => SmtpSecurity::StartTls,
+            };
+            if section.smtp_username.is_some() != section.smtp_password.is_some() {
+                errors.push(
+                    "email.smtp_username (SMTP_USERNAME) and email.smtp_password (SMTP_PASSWORD) must be set together"
+                        .to_string(),
+                );
+            }
+            let Some(host) = section.smtp_host else {
+                errors.push("email.smtp_host (SMTP_HOST) is not set".to_string());
+                return None;
+            };
+            TransportSettings::Smtp(SmtpSettings {
+                host,
+                port: section.smtp_port.unwrap_or_else(|| security.default_port()),
+                security,
+                username: section.smtp_username,
+                password: section.smtp_password,
+            })
+        }
+        Some("file") => match section.dir {
+            Some(dir) => TransportSettings::File(dir),
+            None => {
+                errors.push("email.dir (MAIL_DIR) is required by the file transport".to_string());
+                return None;
+            }
+        },
+        Some(other) => {
+            errors.push(format!(
+                "email.transport (MAIL_TRANSPORT) '{}' must be smtp or file",
+                other
+            ));
+            return None;
+        }
+    };
+
+    let from = match section.from {
+        Some(from) if is_valid_mailbox(&from) => from,
+        Some(from) => {
+            errors.push(format!(
+                "email.from (MAIL_FROM) '{}' is not an email address",
+                from
+            ));
+            return None;
+        }
+        None => {
+            errors.push("email.from (MAIL_FROM) is not set".to_string());
+            return None;
+        }
+    };
+    if let Some(photographer) = &section.photographer
+        && !is_valid_mailbox(photographer)
+    {
+        errors.push(format!(
+            "email.photographer (MAIL_PHOTOGRAPHER) '{}' is not an email address",
+            photographer
+        ));
+    }
+    if section.photographer.is_some() && !activity.enabled {
+        errors.push(
+            "email.photographer (MAIL_PHOTOGRAPHER) needs activity.enabled (ACTIVITY_LOG), the notice is based on the activity log"
+                .to_string(),
+        );
+    }
+
+    Some(MailSettings {
+        transport,
+        from,
+        photographer: section.photographer,
+        reminder_days: section.reminder_days.unwrap_or(3),
+    })
+}
+
+fn validate_webhook(
+    index: usize,
+    section: WebhookSection,
+    shares: &[Arc<Share>],
+    errors: &mut Vec<String>,
+) -> Option<Webhook> {
+    let label = format!("webhooks[{}]", index);
+    let url = match section.url {
+        Some(url) if url.starts_with("https://") || url.starts_with("http://") => {
+            match url.parse() {
+                Ok(url) => Some(url),
+                Err(e) => {
+                    errors.push(format!("{}: url '{}' is invalid: {}", label, url, e));
+                    None
+                }
+            }
+        }
+        Some(url) => {
+            errors.push(format!(
+                "{}: url '{}' must start with https:// or http://",
+                label, url
+            ));
+            None
+        }
+        None => {
+            errors.push(format!("{}: url is required", label));
+            None
+        }
+    };
+
+    let secret = match section.secret {
+        Some(secret) if secret.chars().count() >= MIN_WEBHOOK_SECRET_LENGTH => Some(secret),
+        Some(_) => {
+            errors.push(format!(
+                "{}: secret must be at least {} characters long",
+                label, MIN_WEBHOOK_SECRET_LENGTH
+            ));
+            None
+        }
+        None => {
+            errors.push(format!("{}: secret is required", label));
+            None
+        }
+    };
+
+    let events = match section.events {
+        Some(names) => {
+            let mut events = Vec::new();
+            for name in names {
+                match name.parse::<WebhookEvent>() {
+                    Ok(e) if !events.contains(&e) => events.push(e),
+                    Ok(_) => {}
+                    Err(e) => errors.push(format!("{}: {}", label, e)),
+                }
+            }
+            events
+        }
+        None => WebhookEvent::ALL.to_vec(),
+    };
+
+    if let Some(names) = &section.shares {
+        for name in names {
+            if !shares.iter().any(|s| &s.name == name) {
+                errors.push(format!("{}: no share named '{}'", label, name));
+            }
+        }
+    }
+
+    Some(Webhook {
+        url: url?,
+        secret: secret?,
+        events,
+        shares: section.shares,
+    })
+}
+
+/// The built-in roles plus those of `[roles]`, which may not redefine them.
+fn validate_roles(
+    section: BTreeMap<String, Vec<String>>,
+    errors: &mut Vec<String>,
+) -> BTreeMap<String, Vec<Permission>> {
+    let mut roles: BTreeMap<String, Vec<Permission>> = BUILTIN_ROLES
+        .iter()
+        .map(|(name, permissions)| (name.to_string(), permissions.to_vec()))
+        .collect();
+
+    for (name, names) in section {
+        if roles.contains_key(&name) {
+            errors.push(format!(
+                "roles.{}: built-in roles cannot be redefined",
+                name
+            ));
+            continue;
+        }
+        let mut permissions = Vec::new();
+        for permission in names {
+            match permission.parse::<Permission>() {
+                Ok(p) if !permissions.contains(&p) => permissions.push(p),
+                Ok(_) => {}
+                Err(e) => errors.push(format!("roles.{}: {}", name, e)),
+            }
+        }
+        roles.insert(name, permissions);
+    }
+    roles
+}
+
+/// Share names end up in URLs and log lines, so keep them simple.
+pub fn is_valid_share_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn validate_share(
+    index: usize,
+    section: ShareSection,
+    roles: &BTreeMap<String, Vec<Permission>>,
+    errors: &mut Vec<String>,
+) -> Option<Share> {
+    let name = match section.name {
+        Some(name) if is_valid_share_name(&name) => name,
+        Some(name) => {
+            errors.push(format!(
+                "share '{}': name may only contain letters, digits, '-' and '_'",
+                name
+            ));
+            return None;
+        }
+        None => {
+            errors.push(format!("shares[{}]: name is not set", index));
+            return None;
+        }
+    };
+    let label = if name == ENV_SHARE_NAME {
+        format!("share '{}' (SHARE_DIR, SHARE_KEY, GREET)", name)
+    } else {
+        format!("share '{}'", name)
+    };
+    let error_count = errors.len();
+
+    let dir = section.dir.unwrap_or_default();
+    if dir.as_os_str().is_empty() {
+        errors.push(format!("{}: dir is not set", label));
+    } else {
+        check_readable_dir(&dir, &format!("{}: dir", label), errors);
+    }
+
+    let mut keys = Vec::new();
+    if section.key.is_some() || section.key_hash.is_some() {
+        let key_label = format!("{}: key", label);
+        if let Some(key_hash) = parse_key(&key_label, section.key, section.key_hash, errors) {
+            keys.push(ShareKey {
+                name: OWNER_KEY_NAME.to_string(),
+                key_hash,
+                role: OWNER_ROLE.to_string(),
+                permissions: roles[OWNER_ROLE].clone(),
+            });
+        }
+    }
+    for (index, key) in section.keys.into_iter().enumerate() {
+        let name = match key.name {
+            Some(name) if is_valid_share_name(&name) => name,
+            Some(name) => {
+                errors.push(format!(
+                    "{}: key name '{}' may only contain letters, digits, '-' and '_'",
+                    label, name
+                ));
+                continue;
+            }
+            None => {
+                errors.push(format!("{}: keys[{}]: name is not set", label, index));
+                continue;
+            }
+        };
+        let key_label = format!("{}: key '{}'", label, name);
+        if keys.iter().any(|k: &ShareKey| k.name == name) {
+            errors.push(format!("{} is defined more than once", key_label));
+        }
+        let permissions = match key.role.as_deref().map(|role| roles.get(role)) {
+            Some(Some(permissions)) => permissions.clone(),
+            Some(None) => {
+                errors.push(format!(
+                    "{}: unknown role '{}', define it under [roles]",
+                    key_label,
+                    key.role.unwrap_or_default()
+                ));
+                continue;
+            }
+            None => {
+                errors.push(format!("{}: role is not set", key_label));
+                continue;
+            }
+        };
+        let Some(key_hash) = parse_key(&key_label, key.key, key.key_hash, errors) else {
+            continue;
+        };
+        if let Some(other) = keys.iter().find(|k| k.key_hash == key_hash) {
+            errors.push(format!("{}: same key as '{}'", key_label, other.name));
+        }
+        keys.push(ShareKey {
+            name,
+            key_hash,
+            role: key.role.unwrap_or_default(),
+            permissions,
+        });
+    }
+    if keys.is_empty() && errors.len() == error_count {
+        errors.push(format!("{}: key is not set", label));
+    }
+
+    let greet = section.greet.unwrap_or_else(|| {
+        errors.push(format!("{}: greet is not set", label));
+        String::new()
+    });
+
+    let compression = match section.compression.as_deref().map(ZipCompression::from_str) {
+        Some(Ok(compression)) => compression,
+        Some(Err(e)) => {
+            errors.push(format!("{}: {}", label, e));
+            ZipCompression::default()
+        }
+        None => ZipCompression::default(),
+    };
+
+    let password = section.zip_password.filter(|password| {
+        let long_enough = password.chars().count() >= MIN_ZIP_PASSWORD_LENGTH;
+        if !long_enough {
+            errors.push(format!(
+                "{}: zip_password must be at least {} characters long",
+                label, MIN_ZIP_PASSWORD_LENGTH
+            ));
+        }
+        long_enough
+    });
+
+    if section.zip_part_max_mb == Some(0) {
+        errors.push(format!("{}: zip_part_max_mb must be greater than 0", label));
+    }
+    let part_max_bytes = section
+        .zip_part_max_mb
+        .map(|mb| mb_to_bytes(mb, &format!("{}: zip_part_max_mb", label), errors));
+
+    let upload = section
+        .upload
+        .and_then(|upload| validate_upload(&label, upload, errors));
+
+    if let Some(email) = &section.email
+        && !is_valid_mailbox(email)
+    {
+        errors.push(format!(
+            "{}: email '{}' is not an email address",
+            label, email
+        ));
+    }
+    let expires =
+        section
+            .expires
+            .and_then(|date| match NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+                Ok(date) => Some(date),
+                Err(_) => {
+                    errors.push(format!(
+                        "{}: expires '{}' is not a date like 2025-09-30",
+                        label, date
+                    ));
+                    None
+                }
+            });
+
+    if errors.len() > error_count {
+        return None;
+    }
+    Some(Share {
+        name,
+        dir,
+        keys,
+        greet,
+        zip: ZipOptions {
+            compression,
+            password,
+            part_max_bytes,
+            b3sums: section.b3sums.unwrap_or(false),
+            hash_contents: section.hash_contents.unwrap_or(false),
+        },
+        upload,
+        email: section.email,
+        expires,
+    })
+}
+
+/// Hash of a key given either plainly or already hashed.
+fn parse_key(
+    label: &str,
+    key: Option<String>,
+    key_hash: Option<String>,
+    errors: &mut Vec<String>,
+) -> Option<[u8; 32]> {
+    match (key, key_hash) {
+        (Some(_), Some(_)) => {
+            errors.push(format!("{}: set either key or key_hash, not both", label));
+            None
+        }
+        (Some(key), None) if key.chars().count() < MIN_KEY_LENGTH => {
+            errors.push(format!(
+                "{} must be at least {} characters long",
+                label, MIN_KEY_LENGTH
+            ));
+            None
+        }
+        (Some(key), None) => Some(hash_key(&key)),
+        (None, Some(hash)) => {
+            let parsed = parse_key_hash(&hash);
+            if parsed.is_none() {
+                errors.push(format!(
+                    "{}: key_hash must look like '{}<64 hex digits>', see `photo4share hash-key`",
+                    label, KEY_HASH_PREFIX
+                ));
+            }
+            parsed
+        }
+        (None, None) => {
+            errors.push(format!("{} is not set", label));
+            None
+        }
+    }
+}
+
+fn validate_upload(
+    label: &str,
+    section: UploadSection,
+    errors: &mut Vec<String>,
+) -> Option<UploadConfig> {
+    if !section.enabled.unwrap_or(false) {
+        return None;
+    }
+
+    let quota_mb = section.quota_mb.unwrap_or(10 * 1024);
+    let max_file_mb = section.max_file_mb.unwrap_or(2 * 1024);
+    if quota_mb == 0 {
+        errors.push(format!("{}: upload.quota_mb must be greater than 0", label));
+    }
+    if max_file_mb == 0 {
+        errors.push(format!(
+            "{}: upload.max_file_mb must be greater than 0",
+            label
+        ));
+    }
+
+    Some(UploadConfig {
+        quota_bytes: mb_to_bytes(quota_mb, &format!("{}: upload.quota_mb", label), errors),
+        max_file_bytes: mb_to_bytes(
+            max_file_mb,
+            &format!("{}: upload.max_file_mb", label),
+            errors,
+        ),
+        allowed_extensions: section
+            .allowed_extensions
+            .unwrap_or_default()
+            .iter()
+            .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+            .filter(|e| !e.is_empty())
+            .collect(),
+        notify_command: section.notify_command.filter(|c| !c.is_empty()),
+    })
+}
+
+fn check_readable_dir(path: &Path, name: &str, errors: &mut Vec<String>) {
+    match std::fs::metadata(path) {
+        Ok(meta) if !meta.is_dir() => {
+            errors.push(format!("{} {} is not a directory", name, path.display()));
+        }
+        Ok(_) => {
+            if let Err(e) = std::fs::read_dir(path) {
+                errors.push(format!(
+                    "{} {} is not readable: {}",
+                    name,
+                    path.display(),
+                    e
+                ));
+            }
+        }
+        Err(e) => errors.push(format!("{} {} does not exist: {}", name, path.display(), e)),
+    }
+}
+
+fn check_readable_file(path: &Path, name: &str, errors: &mut Vec<String>) {
+    if let Err(e) = std::fs::File::open(path) {
+        errors.push(format!(
+            "{} {} is not readable: {}",
+            name,
+            path.display(),
+            e
+        ));
+    }
+}
+
+fn mb_to_bytes(mb: u64, name: &str, errors: &mut Vec<String>) -> u64 {
+    scaled(mb, 1024 * 1024, name, errors)
+}
+
+/// `value * factor`, or an error if a setting is too large for that.
+fn scaled(value: u64, factor: u64, name: &str, errors: &mut Vec<String>) -> u64 {
+    value.checked_mul(factor).unwrap_or_else(|| {
+        errors.push(format!("{} {} is too large", name, value));
+        0
+    })
+}
+
+fn override_with<T>(slot: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *slot = value;
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|part| part.trim().to_string())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+fn env_string(vars: Vars, name: &str) -> Option<String> {
+    vars(name)
+}
+
+fn env_parse<T: FromStr>(vars: Vars, name: &str, errors: &mut Vec<String>) -> Option<T> {
+    let value = env_string(vars, name)?;
+    match value.trim().parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            errors.push(format!("{}='{}' is not a valid number", name, value));
+            None
+        }
+    }
+}
+
+fn env_bool(vars: Vars, name: &str, errors: &mut Vec<String>) -> Option<bool> {
+    let value = env_string(vars, name)?;
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" | "" => Some(false),
+        _ => {
+            errors.push(format!("{}='{}' is not a valid boolean", name, value));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::format_key_hash;
+
+    const KEY: &str = "a key long enough";
+    const OTHER_KEY: &str = "another key long enough";
+
+    fn share_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("photo4share-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Loads `toml` with `{dir}` standing for an existing directory and only
+    /// `vars` in the environment.
+    fn load_with(toml: &str, vars: &[(&str, &str)]) -> Result<Config, Vec<String>> {
+        let toml = toml.replace("{dir}", &share_dir().display().to_string());
+        let mut errors = Vec::new();
+        let mut file: ConfigFile = toml::from_str(&toml).unwrap();
+        let lookup = |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        };
+        apply_env_overrides(&mut file, &lookup, &mut errors);
+        match validate(file, &mut errors) {
+            Some(config) if errors.is_empty() => Ok(config),
+            _ => Err(errors),
+        }
+    }
+
+    fn errors_of(toml: &str, vars: &[(&str, &str)]) -> Vec<String> {
+        match load_with(toml, vars) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors,
+        }
+    }
+
+    fn share(extra: &str) -> String {
+        format!(
+            "[[shares]]\nname = \"s1\"\ndir = \"{{dir}}\"\nkey = \"{}\"\ngreet = \"Hi\"\n{}",
+            KEY, extra
+        )
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let big = u64::MAX / 1024;
+        let cases: Vec<(String, &str)> = vec![
+            (String::new(), "no shares configured"),
+            (share("compression = \"lzma\""), "lzma"),
+            (share("zip_password = \"short\""), "zip_password must be at least"),
+            (share("zip_part_max_mb = 0"), "zip_part_max_mb must be greater than 0"),
+            (
+                share(&format!("zip_part_max_mb = {}", big)),
+                "zip_part_max_mb 18014398509481983 is too large",
+            ),
+            (share("expires = \"30.09.2025\""), "is not a date"),
+            (share("email = \"nobody\""), "is not an email address"),
+            (
+                share("[[shares.keys]]\nname = \"guests\"\nkey = \"guest key long enough\"\nrole = \"editor\""),
+                "unknown role 'editor'",
+            ),
+            (
+                share(&format!("[[shares.keys]]\nname = \"guests\"\nkey = \"{}\"\nrole = \"view\"", KEY)),
+                "same key as 'owner'",
+            ),
+            (
+                share("[shares.upload]\nenabled = true\nquota_mb = 0"),
+                "upload.quota_mb must be greater than 0",
+            ),
+            (
+                share(&format!("[shares.upload]\nenabled = true\nmax_file_mb = {}", big)),
+                "upload.max_file_mb 18014398509481983 is too large",
+            ),
+            (
+                "[[shares]]\nname = \"s 1\"\ndir = \"{dir}\"\nkey = \"a key long enough\"\ngreet = \"Hi\"".to_string(),
+                "name may only contain",
+            ),
+            (
+                "[[shares]]\nname = \"s1\"\ndir = \"{dir}\"\nkey = \"short\"\ngreet = \"Hi\"".to_string(),
+                "key must be at least 16 characters long",
+            ),
+            (
+                "[[shares]]\nname = \"s1\"\ndir = \"/nonexistent/photo4share\"\nkey = \"a key long enough\"\ngreet = \"Hi\"".to_string(),
+                "does not exist",
+            ),
+            (format!("{}\n{}", share(""), share("")), "defined more than once"),
+            (
+                format!("{}\n{}", share(""), share("").replace("s1", "s2")),
+                "shares 's1' and 's2' use the same key",
+            ),
+            (
+                format!("{}\n[roles]\nview = [\"list\"]", share("")),
+                "built-in roles cannot be redefined",
+            ),
+            (
+                format!("{}\n[roles]\neditor = [\"paint\"]", share("")),
+                "roles.editor",
+            ),
+            (
+                format!("[server]\nlisten = [\"nowhere\"]\n{}", share("")),
+                "invalid listen address 'nowhere'",
+            ),
+            (
+                format!("[server]\nunix_socket_mode = \"999\"\n{}", share("")),
+                "not an octal mode",
+            ),
+            (
+                format!("[server]\ntls_cert = \"/nonexistent.pem\"\n{}", share("")),
+                "must be set together",
+            ),
+            (
+                format!("[server]\nlink_secret = \"short\"\n{}", share("")),
+                "link_secret (LINK_SECRET) must be at least",
+            ),
+            (
+                format!("[server]\npublic_url = \"photos.example.com\"\n{}", share("")),
+                "must start with https://",
+            ),
+            (
+                format!("[server]\nadmin_key = \"{}\"\n{}", KEY, share("")),
+                "also a share key",
+            ),
+            (
+                format!("[cache]\nmax_total_mb = {}\n{}", big, share("")),
+                "cache.max_total_mb (ZIP_CACHE_MAX_MB) 18014398509481983 is too large",
+            ),
+            (
+                format!("[cache]\nmax_age_hours = {}\n{}", i64::MAX, share("")),
+                "cache.max_age_hours (ZIP_CACHE_MAX_AGE_HOURS)",
+            ),
+            (
+                format!("[cache]\neviction_interval_secs = 0\n{}", share("")),
+                "eviction_interval_secs",
+            ),
+            (
+                format!(
+                    "{}\n[[webhooks]]\nurl = \"ftp://example.com\"\nsecret = \"0123456789abcdef\"",
+                    share("")
+                ),
+                "must start with https:// or http://",
+            ),
+            (
+                format!(
+                    "{}\n[[webhooks]]\nurl = \"https://example.com\"\nsecret = \"0123456789abcdef\"\nevents = [\"logout\"]",
+                    share("")
+                ),
+                "unknown event 'logout'",
+            ),
+            (
+                format!(
+                    "{}\n[[webhooks]]\nurl = \"https://example.com\"\nsecret = \"0123456789abcdef\"\nshares = [\"s9\"]",
+                    share("")
+                ),
+                "no share named 's9'",
+            ),
+            (
+                format!(
+                    "{}\n[[webhooks]]\nurl = \"https://example.com\"\nsecret = \"0123456789abcdef\"\n[[webhooks]]\nurl = \"https://example.com\"\nsecret = \"0123456789abcdef\"",
+                    share("")
+                ),
+                "configured more than once",
+            ),
+        ];
+
+        for (toml, expected) in &cases {
+            let errors = errors_of(toml, &[]);
+            assert!(
+                errors.iter().any(|e| e.contains(expected)),
+                "expected {:?} for\n{}\ngot {:?}",
+                expected,
+                toml,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn valid_configuration_gets_defaults() {
+        let config = load_with(
+            &share("zip_part_max_mb = 100\n[shares.upload]\nenabled = true"),
+            &[],
+        )
+        .unwrap_or_else(|e| panic!("{:?}", e));
+        let share = &config.shares[0];
+        assert_eq!(share.keys[0].name, OWNER_KEY_NAME);
+        assert_eq!(share.keys[0].key_hash, hash_key(KEY));
+        assert_eq!(share.zip.part_max_bytes, Some(100 * 1024 * 1024));
+        let upload = share.upload.as_ref().unwrap();
+        assert_eq!(upload.quota_bytes, 10 * 1024 * 1024 * 1024);
+        assert_eq!(upload.max_file_bytes, 2 * 1024 * 1024 * 1024);
+        assert_eq!(
+            config.listen,
+            vec![ListenAddr::Tcp(([0, 0, 0, 0], 3000).into())]
+        );
+        assert!(config.cache.keep_latest_only);
+        assert!(config.email.is_none());
+        assert!(config.activity.enabled && config.activity.anonymize);
+    }
+
+    #[test]
+    fn roles_grant_their_permissions_to_keys() {
+        let keys = "[[shares.keys]]\nname = \"guests\"\nkey = \"guest key long enough\"\nrole = \"view\"\n\
+                    [[shares.keys]]\nname = \"family\"\nkey = \"family key long enough\"\nrole = \"download\"\n\
+                    [[shares.keys]]\nname = \"client\"\nkey = \"client key long enough\"\nrole = \"sender\"";
+        let toml = format!(
+            "{}\n[roles]\nsender = [\"list\", \"upload\", \"list\"]",
+            share(keys)
+        );
+        let config = load_with(&toml, &[]).unwrap_or_else(|e| panic!("{:?}", e));
+        let permissions = |name: &str| {
+            let key = config.shares[0].keys.iter().find(|k| k.name == name);
+            key.unwrap().permissions.clone()
+        };
+        assert_eq!(permissions(OWNER_KEY_NAME), Permission::ALL);
+        assert_eq!(
+            permissions("guests"),
+            [Permission::List, Permission::Preview]
+        );
+        assert_eq!(
+            permissions("family"),
+            [
+                Permission::List,
+                Permission::Preview,
+                Permission::Download,
+                Permission::DownloadZip,
+            ]
+        );
+        assert_eq!(
+            permissions("client"),
+            [Permission::List, Permission::Upload]
+        );
+        // Only the owner's full role may send files by default
+        assert!(!permissions("family").contains(&Permission::Upload));
+    }
+
+    #[test]
+    fn mailboxes_are_single_addresses() {
+        for valid in [
+            "studio@example.com",
+            "Студія Світло <studio@example.com>",
+            "<studio@example.com>",
+        ] {
+            assert!(is_valid_mailbox(valid), "{:?}", valid);
+        }
+        for invalid in [
+            "",
+            "studio",
+            "@example.com",
+            "studio@localhost",
+            "studio @example.com",
+            "Studio <studio@example.com",
+            "studio@example.com\r\nBcc: all@example.com",
+        ] {
+            assert!(!is_valid_mailbox(invalid), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn email_settings_are_checked() {
+        let smtp = "[email]\nsmtp_host = \"mail.example.com\"\nfrom = \"studio@example.com\"\n";
+        let cases = [
+            (
+                "[email]\nfrom = \"studio@example.com\"",
+                "email.smtp_host (SMTP_HOST) is not set",
+            ),
+            (
+                "[email]\ntransport = \"file\"\nfrom = \"studio@example.com\"",
+                "email.dir (MAIL_DIR)",
+            ),
+            ("[email]\ntransport = \"pigeon\"", "must be smtp or file"),
+            (
+                "[email]\nsmtp_host = \"mail.example.com\"",
+                "email.from (MAIL_FROM) is not set",
+            ),
+            (
+                "[email]\nsmtp_host = \"mail.example.com\"\nfrom = \"studio\"",
+                "email.from (MAIL_FROM) 'studio' is not an email address",
+            ),
+            (
+                &format!("{}smtp_security = \"ssl\"", smtp),
+                "unknown SMTP security 'ssl'",
+            ),
+            (
+                &format!("{}smtp_username = \"studio\"", smtp),
+                "must be set together",
+            ),
+            (
+                &format!("{}photographer = \"me\"", smtp),
+                "email.photographer (MAIL_PHOTOGRAPHER) 'me' is not an email address",
+            ),
+            (
+                &format!(
+                    "{}photographer = \"me@example.com\"\n[activity]\nenabled = false",
+                    smtp
+                ),
+                "needs activity.enabled (ACTIVITY_LOG)",
+            ),
+        ];
+        for (section, expected) in cases {
+            let errors = errors_of(&format!("{}\n{}", share(""), section), &[]);
+            assert!(
+                errors.iter().any(|e| e.contains(expected)),
+                "expected {:?} for {:?}, got {:?}",
+                expected,
+                section,
+                errors
+            );
+        }
+
+        let toml = format!("{}\n{}photographer = \"me@example.com\"", share(""), smtp);
+        let config = load_with(&toml, &[]).unwrap_or_else(|e| panic!("{:?}", e));
+        let email = config.email.unwrap();
+        assert!(matches!(
+            email.transport,
+            TransportSettings::Smtp(SmtpSettings { port: 587, .. })
+        ));
+        assert_eq!(email.photographer.as_deref(), Some("me@example.com"));
+        // Without the photographer's address the activity log may be off
+        let toml = format!("{}\n{}[activity]\nenabled = false", share(""), smtp);
+        assert!(load_with(&toml, &[]).is_ok());
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let dir = share_dir().display().to_string();
+        let env_share: &[(&str, &str)] =
+            &[("SHARE_DIR", &dir), ("SHARE_KEY", KEY), ("GREET", "Hello")];
+
+        // The single-share variables create the default share...
+        let config = load_with("", env_share).unwrap_or_else(|e| panic!("{:?}", e));
+        assert_eq!(config.shares.len(), 1);
+        assert_eq!(config.shares[0].name, ENV_SHARE_NAME);
+        assert_eq!(config.shares[0].greet, "Hello");
+
+        // ...or replace the key of an existing one, hashed or not
+        let toml = format!(
+            "[[shares]]\nname = \"default\"\ndir = \"{{dir}}\"\nkey_hash = \"{}\"\ngreet = \"Hi\"",
+            format_key_hash(&hash_key(OTHER_KEY))
+        );
+        let config = load_with(&toml, &[("SHARE_KEY", KEY)]).unwrap_or_else(|e| panic!("{:?}", e));
+        assert_eq!(config.shares[0].keys[0].key_hash, hash_key(KEY));
+        assert_eq!(config.shares[0].greet, "Hi");
+
+        // The archive variables alone apply to a default share from the file
+        let zip_vars = [
+            ("ZIP_PASSWORD", "a zip password"),
+            ("ZIP_COMPRESSION", "zstd"),
+            ("ZIP_PART_MAX_MB", "100"),
+            ("B3SUMS", "true"),
+            ("HASH_CONTENTS", "yes"),
+        ];
+        let config = load_with(&toml, &zip_vars).unwrap_or_else(|e| panic!("{:?}", e));
+        let zip = &config.shares[0].zip;
+        assert_eq!(zip.password.as_deref(), Some("a zip password"));
+        assert_eq!(zip.compression, ZipCompression::Zstd);
+        assert_eq!(zip.part_max_bytes, Some(100 * 1024 * 1024));
+        assert!(zip.b3sums && zip.hash_contents);
+        // Without a default share in the file they aren't silently dropped
+        let errors = errors_of(&share(""), &zip_vars[..1]);
+        assert!(errors.iter().any(|e| e.contains("default")), "{:?}", errors);
+
+        let cases: Vec<(Vec<(&str, &str)>, &str)> = vec![
+            (
+                vec![("UPLOAD_QUOTA_MB", "lots")],
+                "UPLOAD_QUOTA_MB='lots' is not a valid number",
+            ),
+            (
+                vec![("UPLOAD_ENABLED", "maybe")],
+                "UPLOAD_ENABLED='maybe' is not a valid boolean",
+            ),
+            (
+                vec![("ZIP_CACHE_PREBUILD", "2")],
+                "ZIP_CACHE_PREBUILD='2' is not a valid boolean",
+            ),
+            (
+                vec![("SMTP_PORT", "70000")],
+                "SMTP_PORT='70000' is not a valid number",
+            ),
+            (
+                vec![("LISTEN", "127.0.0.1:80, nowhere")],
+                "invalid listen address 'nowhere'",
+            ),
+            (
+                vec![("ZIP_PART_MAX_MB", "0")],
+                "zip_part_max_mb must be greater than 0",
+            ),
+            (vec![("LINK_SECRET", "short")], "link_secret (LINK_SECRET)"),
+        ];
+        for (vars, expected) in cases {
+            let vars = [env_share, &vars].concat();
+            let errors = errors_of("", &vars);
+            assert!(
+                errors.iter().any(|e| e.contains(expected)),
+                "expected {:?} for {:?}, got {:?}",
+                expected,
+                vars,
+                errors
+            );
+        }
+
+        let vars = [
+            env_share,
+            &[
+                ("LISTEN", "127.0.0.1:8080, unix:/run/photo4share.sock"),
+                ("UPLOAD_ENABLED", "yes"),
+                ("UPLOAD_ALLOWED_EXTENSIONS", ".JPG, cr2,"),
+                ("ZIP_CACHE_KEEP_LATEST_ONLY", "off"),
+                ("ADMIN_KEY", OTHER_KEY),
+            ],
+        ]
+        .concat();
+        let toml = format!(
+            "[server]\nadmin_key_hash = \"{}\"",
+            format_key_hash(&hash_key("the old admin key"))
+        );
+        let config = load_with(&toml, &vars).unwrap_or_else(|e| panic!("{:?}", e));
+        assert_eq!(config.listen.len(), 2);
+        let upload = config.shares[0].upload.as_ref().unwrap();
+        assert_eq!(upload.allowed_extensions, ["jpg", "cr2"]);
+        assert!(!config.cache.keep_latest_only);
+        assert_eq!(config.admin_key_hash, Some(hash_key(OTHER_KEY)));
+    }
+}
@@ -0,0 +1,80 @@
+use crate::file_index::FileEntry;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Pixel sizes of a share's images, kept like `DigestCache` until the file
+/// changes. Only the image header is read, so filling it is cheap.
+#[derive(Default)]
+pub struct DimensionCache {
+    sizes: Mutex<HashMap<String, (FileEntry, Option<Dimensions>)>>,
+}
+
+impl DimensionCache {
+    /// Dimensions of `files` in the same order, `None` for anything that
+    /// isn't a readable image.
+    pub async fn dimensions(&self, dir: &Path, files: &[FileEntry]) -> Vec<Option<Dimensions>> {
+        let missing: Vec<FileEntry> = {
+            let sizes = self.sizes.lock().unwrap();
+            files
+                .iter()
+                .filter(|f| sizes.get(&f.name).is_none_or(|(read, _)| read != *f))
+                .cloned()
+                .collect()
+        };
+
+        if !missing.is_empty() {
+            let dir = dir.to_path_buf();
+            let read = tokio::task::spawn_blocking(move || {
+                missing
+                    .into_iter()
+                    .map(|entry| {
+                        let dimensions = read_dimensions(&dir.join(&entry.name));
+                        (entry, dimensions)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .unwrap_or_default();
+
+            let mut sizes = self.sizes.lock().unwrap();
+            for (entry, dimensions) in read {
+                sizes.insert(entry.name.clone(), (entry, dimensions));
+            }
+        }
+
+        let sizes = self.sizes.lock().unwrap();
+        files
+            .iter()
+            .map(|f| sizes.get(&f.name).and_then(|(_, dimensions)| *dimensions))
+            .collect()
+    }
+
+    /// Forgets every size not matching one of `files`.
+    pub fn retain(&self, files: &[FileEntry]) {
+        self.sizes.lock().unwrap().retain(|name, (read, _)| {
+            files
+                .binary_search_by(|f| f.name.as_str().cmp(name))
+                .is_ok_and(|i| files[i] == *read)
+        });
+    }
+}
+
+fn read_dimensions(path: &Path) -> Option<Dimensions> {
+    let mime = mime_guess::from_path(path).first()?;
+    if mime.type_() != mime_guess::mime::IMAGE {
+        return None;
+    }
+    let size = imagesize::size(path).ok()?;
+    Some(Dimensions {
+        width: size.width.try_into().ok()?,
+        height: size.height.try_into().ok()?,
+    })
+}
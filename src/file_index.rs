@@ -0,0 +1,573 @@
+use crate::checksums::DigestCache;
+use crate::checksums::FileDigest;
+use crate::dimensions::DimensionCache;
+use crate::dimensions::Dimensions;
+use crate::file_utils::list_share_files;
+use crate::file_utils::should_include_file;
+use notify::Event;
+use notify::PollWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::Weak;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Events arriving within this window are applied together, so copying a
+/// batch of photos into a share doesn't cause one update per write.
+const EVENT_DEBOUNCE: Duration = Duration::from_millis(250);
+/// Used when inotify can't watch the directory (watch limit, network FS).
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// A watched share is rescanned before hashing once its last full scan is
+/// this old, so an event the watcher missed can't keep a stale archive.
+const HASH_RESCAN_INTERVAL: Duration = Duration::from_secs(60);
+/// Mixed into the content hash, so archives cached before their layout
+/// changed (e.g. when the checksum manifests were added) aren't served.
+const ARCHIVE_LAYOUT: &[u8] = b"manifest-v1";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: SystemTime,
+    pub inode: u64,
+    /// Status change time in nanoseconds. Unlike the mtime it can't be set
+    /// back, so it catches files restored with a preserved mtime.
+    pub changed: i128,
+}
+
+impl FileEntry {
+    /// Identifies this version of the file. Changes whenever the share's
+    /// content hash would because of this file.
+    pub fn version(&self) -> String {
+        fingerprint(std::slice::from_ref(self), &HashMap::new(), false)[..16].to_string()
+    }
+}
+
+/// In-memory list of a share's visible files, kept up to date by a
+/// filesystem watcher. Every change bumps `generation`.
+pub struct ShareIndex {
+    dir: PathBuf,
+    files: RwLock<Arc<Vec<FileEntry>>>,
+    generation: AtomicU64,
+    generation_tx: watch::Sender<u64>,
+    hash: Mutex<HashMap<bool, (u64, String)>>,
+    /// Held while hashing, so concurrent callers share one pass.
+    hashing: tokio::sync::Mutex<()>,
+    /// When the directory was last read in full.
+    scanned: Mutex<Option<Instant>>,
+    digests: DigestCache,
+    dimensions: DimensionCache,
+    watcher: Mutex<Option<Box<dyn Watcher + Send>>>,
+    /// Cancelled once no configured share uses the directory any more.
+    retired: CancellationToken,
+}
+
+impl ShareIndex {
+    /// Scans `dir` once without watching it, for one-off CLI use.
+    pub async fn load(dir: &Path) -> std::io::Result<Arc<Self>> {
+        let index = Arc::new(Self {
+            dir: dir.to_path_buf(),
+            files: RwLock::new(Arc::new(Vec::new())),
+            generation: AtomicU64::new(0),
+            generation_tx: watch::channel(0).0,
+            hash: Mutex::new(HashMap::new()),
+            hashing: tokio::sync::Mutex::new(()),
+            scanned: Mutex::new(None),
+            digests: DigestCache::default(),
+            dimensions: DimensionCache::default(),
+            watcher: Mutex::new(None),
+            retired: CancellationToken::new(),
+        });
+        index.rescan().await?;
+        Ok(index)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Visible files sorted by name.
+    pub fn files(&self) -> Arc<Vec<FileEntry>> {
+        self.files.read().unwrap().clone()
+    }
+
+    /// Changes whenever the set of files or their size/mtime changes.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Notifies the receiver each time the generation changes.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.generation_tx.subscribe()
+    }
+
+    /// Fingerprint of the share's contents, the key of its cached archives.
+    /// Covers every file's name, size, nanosecond mtime, inode and ctime,
+    /// and with `hash_contents` also the blake3 of its data, so a file
+    /// replaced within the same second or restored with a preserved mtime
+    /// never gets a stale archive. Computed once per generation; file
+    /// digests are cached until the file changes, and concurrent callers
+    /// wait for one hashing pass. The same pass produces the SHA-256 the
+    /// archive's manifest needs, so the files are read only once.
+    pub async fn content_hash(&self, hash_contents: bool) -> String {
+        if self.needs_rescan()
+            && let Err(e) = self.rescan().await
+        {
+            warn!("Rescan of {:?} failed: {}", self.dir, e);
+        }
+        if let Some(hash) = self.cached_hash(hash_contents) {
+            return hash;
+        }
+
+        let _hashing = self.hashing.lock().await;
+        // Another caller may have finished the same pass while we waited
+        if let Some(hash) = self.cached_hash(hash_contents) {
+            return hash;
+        }
+
+        let generation = self.generation();
+        let files = self.files();
+        let mut digests = HashMap::new();
+        if hash_contents {
+            match self.digests(&files).await {
+                Ok(list) => digests.extend(list.into_iter().map(|d| (d.name, d.blake3))),
+                Err(e) => {
+                    // Not cached, so the next request tries again
+                    warn!("Hashing {:?} failed, using metadata only: {}", self.dir, e);
+                    return fingerprint(&files, &digests, hash_contents);
+                }
+            }
+        }
+
+        let hash = fingerprint(&files, &digests, hash_contents);
+        self.hash
+            .lock()
+            .unwrap()
+            .insert(hash_contents, (generation, hash.clone()));
+        hash
+    }
+
+    fn cached_hash(&self, hash_contents: bool) -> Option<String> {
+        let hash = self.hash.lock().unwrap();
+        let (generation, hash) = hash.get(&hash_contents)?;
+        (*generation == self.generation()).then(|| hash.clone())
+    }
+
+    /// Without a watcher nothing else notices changes; with one, a periodic
+    /// full scan covers events it missed.
+    fn needs_rescan(&self) -> bool {
+        if self.watcher.lock().unwrap().is_none() {
+            return true;
+        }
+        self.scanned
+            .lock()
+            .unwrap()
+            .is_none_or(|scanned| scanned.elapsed() >= HASH_RESCAN_INTERVAL)
+    }
+
+    /// Checksums of `files`, hashing only those not hashed since they last
+    /// changed.
+    pub async fn digests(&self, files: &[FileEntry]) -> std::io::Result<Vec<FileDigest>> {
+        self.digests.digests(&self.dir, files).await
+    }
+
+    /// Pixel sizes of the images among `files`.
+    pub async fn dimensions(&self, files: &[FileEntry]) -> Vec<Option<Dimensions>> {
+        self.dimensions.dimensions(&self.dir, files).await
+    }
+
+    /// Re-reads the whole directory.
+    pub async fn rescan(&self) -> std::io::Result<()> {
+        let started = Instant::now();
+        let mut files = Vec::new();
+        for path in list_share_files(&self.dir).await? {
+            if let Some(entry) = stat_entry(&path).await {
+                files.push(entry);
+            }
+        }
+        self.replace(files);
+        *self.scanned.lock().unwrap() = Some(started);
+        Ok(())
+    }
+
+    /// Re-checks just the given paths, e.g. the ones named in watcher events.
+    async fn refresh_paths(&self, paths: &BTreeSet<PathBuf>) {
+        let mut files = self.files().as_ref().clone();
+
+        for path in paths {
+            // Only direct children of the share are listed
+            if path.parent() != Some(self.dir.as_path()) {
+                continue;
+            }
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+
+            let entry = match should_include_file(&self.dir, path).await {
+                Ok(true) => stat_entry(path).await,
+                _ => None,
+            };
+            let position = files.binary_search_by(|f| f.name.as_str().cmp(name));
+            match (position, entry) {
+                (Ok(i), Some(entry)) => files[i] = entry,
+                (Err(i), Some(entry)) => files.insert(i, entry),
+                (Ok(i), None) => {
+                    files.remove(i);
+                }
+                (Err(_), None) => {}
+            }
+        }
+
+        self.replace(files);
+    }
+
+    /// Stops watching, for a directory no share uses any more.
+    fn retire(&self) {
+        self.watcher.lock().unwrap().take();
+        self.retired.cancel();
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.retired.is_cancelled()
+    }
+
+    /// Resolves once the index is retired.
+    pub async fn retired(&self) {
+        self.retired.cancelled().await
+    }
+
+    fn replace(&self, files: Vec<FileEntry>) {
+        let mut current = self.files.write().unwrap();
+        if **current != files {
+            self.digests.retain(&files);
+            self.dimensions.retain(&files);
+            *current = Arc::new(files);
+            let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+            self.generation_tx.send_replace(generation);
+        }
+    }
+}
+
+fn fingerprint(
+    files: &[FileEntry],
+    digests: &HashMap<String, String>,
+    hash_contents: bool,
+) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(ARCHIVE_LAYOUT);
+    hasher.update(&[hash_contents as u8]);
+    for file in files {
+        let mtime = file
+            .modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        // Length-prefixed, so no two lists of names hash alike
+        hasher.update(&(file.name.len() as u64).to_le_bytes());
+        hasher.update(file.name.as_bytes());
+        hasher.update(&file.size.to_le_bytes());
+        hasher.update(&mtime.to_le_bytes());
+        hasher.update(&file.inode.to_le_bytes());
+        hasher.update(&file.changed.to_le_bytes());
+        if let Some(digest) = digests.get(&file.name) {
+            hasher.update(digest.as_bytes());
+        }
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+async fn stat_entry(path: &Path) -> Option<FileEntry> {
+    let name = path.file_name()?.to_str()?.to_string();
+    let meta = fs::metadata(path).await.ok()?;
+    Some(FileEntry {
+        name,
+        size: meta.len(),
+        modified: meta.modified().ok()?,
+        inode: meta.ino(),
+        changed: meta.ctime() as i128 * 1_000_000_000 + meta.ctime_nsec() as i128,
+    })
+}
+
+/// Watched indexes for every share directory, created on first use.
+#[derive(Default)]
+pub struct FileIndexes {
+    indexes: tokio::sync::Mutex<HashMap<PathBuf, Arc<ShareIndex>>>,
+}
+
+impl FileIndexes {
+    pub async fn get(&self, dir: &Path) -> std::io::Result<Arc<ShareIndex>> {
+        let mut indexes = self.indexes.lock().await;
+        if let Some(index) = indexes.get(dir) {
+            return Ok(index.clone());
+        }
+
+        let index = ShareIndex::load(dir).await?;
+        start_watching(&index);
+        indexes.insert(dir.to_path_buf(), index.clone());
+        Ok(index)
+    }
+
+    /// Drops the indexes of directories not in `dirs`, e.g. after a share
+    /// was removed from the configuration.
+    pub async fn retain(&self, dirs: &HashSet<&Path>) {
+        self.indexes.lock().await.retain(|dir, index| {
+            let keep = dirs.contains(dir.as_path());
+            if !keep {
+                debug!("No share uses {:?} any more, dropping its index", dir);
+                index.retire();
+            }
+            keep
+        });
+    }
+}
+
+fn start_watching(index: &Arc<ShareIndex>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let handler = move |event: notify::Result<Event>| {
+        let _ = tx.send(event);
+    };
+
+    let watcher: Box<dyn Watcher + Send> = match notify::recommended_watcher(handler.clone())
+        .and_then(|mut w| w.watch(&index.dir, RecursiveMode::NonRecursive).map(|_| w))
+    {
+        Ok(w) => {
+            info!("Watching {:?} for changes", index.dir);
+            Box::new(w)
+        }
+        Err(e) => {
+            warn!(
+                "Native file watching unavailable for {:?} ({}), polling every {}s",
+                index.dir,
+                e,
+                POLL_INTERVAL.as_secs()
+            );
+            let config = notify::Config::default().with_poll_interval(POLL_INTERVAL);
+            match PollWatcher::new(handler, config)
+                .and_then(|mut w| w.watch(&index.dir, RecursiveMode::NonRecursive).map(|_| w))
+            {
+                Ok(w) => Box::new(w),
+                Err(e) => {
+                    warn!("Cannot watch {:?}, index won't update: {}", index.dir, e);
+                    return;
+                }
+            }
+        }
+    };
+    *index.watcher.lock().unwrap() = Some(watcher);
+
+    tokio::spawn(apply_events(Arc::downgrade(index), rx));
+}
+
+async fn apply_events(
+    index: Weak<ShareIndex>,
+    mut rx: mpsc::UnboundedReceiver<notify::Result<Event>>,
+) {
+    while let Some(first) = rx.recv().await {
+        tokio::time::sleep(EVENT_DEBOUNCE).await;
+
+        let mut events = vec![first];
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+
+        let Some(index) = index.upgrade() else {
+            break;
+        };
+
+        let mut paths = BTreeSet::new();
+        let mut full_rescan = false;
+        for event in events {
+            match event {
+                Ok(event) if event.need_rescan() => full_rescan = true,
+                Ok(event) => paths.extend(event.paths),
+                Err(e) => {
+                    debug!("Watcher error for {:?}: {}", index.dir, e);
+                    full_rescan = true;
+                }
+            }
+        }
+
+        let before = index.generation();
+        if full_rescan {
+            if let Err(e) = index.rescan().await {
+                warn!("Rescan of {:?} failed: {}", index.dir, e);
+            }
+        } else {
+            index.refresh_paths(&paths).await;
+        }
+        if index.generation() != before {
+            debug!(
+                "Index of {:?} updated to generation {}",
+                index.dir,
+                index.generation()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_share(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("photo4share-index-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn content_hash_sees_changes_the_watcher_has_not_reported() {
+        let dir = temp_share("fresh");
+        std::fs::write(dir.join("a.jpg"), b"first").unwrap();
+        // Not watched at all, like an index whose watcher failed to start
+        let index = ShareIndex::load(&dir).await.unwrap();
+        let before = index.content_hash(false).await;
+        assert_eq!(index.content_hash(false).await, before);
+
+        std::fs::write(dir.join("a.jpg"), b"second").unwrap();
+        let replaced = index.content_hash(false).await;
+        assert_ne!(replaced, before);
+
+        std::fs::write(dir.join("b.jpg"), b"new").unwrap();
+        assert_ne!(index.content_hash(false).await, replaced);
+        assert_eq!(index.files().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn file(name: &str) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            size: 10,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            inode: 42,
+            changed: 1_700_000_000_000_000_000,
+        }
+    }
+
+    #[test]
+    fn fingerprint_covers_every_field() {
+        let files = vec![file("a.jpg"), file("b.jpg")];
+        let none = HashMap::new();
+        let base = fingerprint(&files, &none, false);
+        assert_eq!(base, fingerprint(&files.clone(), &HashMap::new(), false));
+        assert_eq!(base.len(), 64);
+
+        type Change = fn(&mut FileEntry);
+        let changes: [(&str, Change); 5] = [
+            ("name", |f| f.name.push('x')),
+            ("size", |f| f.size += 1),
+            ("mtime", |f| f.modified += Duration::from_nanos(1)),
+            ("inode", |f| f.inode += 1),
+            ("ctime", |f| f.changed += 1),
+        ];
+        for (field, change) in changes {
+            let mut changed = files.clone();
+            change(&mut changed[1]);
+            assert_ne!(fingerprint(&changed, &none, false), base, "{}", field);
+        }
+
+        assert_ne!(fingerprint(&files[..1], &none, false), base);
+        assert_ne!(fingerprint(&files, &none, true), base);
+        // Names are length-prefixed, so they can't run into each other
+        assert_ne!(
+            fingerprint(&[file("ab"), file("c")], &none, false),
+            fingerprint(&[file("a"), file("bc")], &none, false)
+        );
+
+        let digests = HashMap::from([("a.jpg".to_string(), "one".to_string())]);
+        let other = HashMap::from([("a.jpg".to_string(), "two".to_string())]);
+        let hashed = fingerprint(&files, &digests, true);
+        assert_ne!(hashed, fingerprint(&files, &none, true));
+        assert_ne!(hashed, fingerprint(&files, &other, true));
+    }
+
+    #[tokio::test]
+    async fn content_hash_with_contents_is_computed_once() {
+        let dir = temp_share("contents");
+        for i in 0..10 {
+            std::fs::write(dir.join(format!("{}.jpg", i)), [i as u8; 64]).unwrap();
+        }
+        let index = ShareIndex::load(&dir).await.unwrap();
+
+        let (a, b) = tokio::join!(index.content_hash(true), index.content_hash(true));
+        assert_eq!(a, b);
+        assert_ne!(a, index.content_hash(false).await);
+        assert_eq!(
+            index
+                .digests
+                .digests(&dir, &index.files())
+                .await
+                .unwrap()
+                .len(),
+            10
+        );
+
+        std::fs::write(dir.join("3.jpg"), [0xff; 64]).unwrap();
+        assert_ne!(index.content_hash(true).await, a);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn watched_shares_are_rescanned_for_hashing_only_when_stale() {
+        let dir = temp_share("stale");
+        std::fs::write(dir.join("a.jpg"), b"first").unwrap();
+        let unwatched = ShareIndex::load(&dir).await.unwrap();
+        assert!(unwatched.needs_rescan());
+
+        let indexes = FileIndexes::default();
+        let index = indexes.get(&dir).await.unwrap();
+        assert!(!index.needs_rescan());
+        let hash = index.content_hash(false).await;
+        assert_eq!(index.content_hash(false).await, hash);
+
+        *index.scanned.lock().unwrap() = Instant::now().checked_sub(HASH_RESCAN_INTERVAL);
+        assert!(index.needs_rescan());
+        index.content_hash(false).await;
+        assert!(!index.needs_rescan());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn indexes_of_removed_shares_are_dropped() {
+        let kept = temp_share("kept");
+        let removed = temp_share("removed");
+        let indexes = FileIndexes::default();
+        let kept_index = indexes.get(&kept).await.unwrap();
+        let removed_index = indexes.get(&removed).await.unwrap();
+
+        indexes.retain(&HashSet::from([kept.as_path()])).await;
+        assert!(!kept_index.is_retired());
+        assert!(removed_index.is_retired());
+        assert!(removed_index.watcher.lock().unwrap().is_none());
+        assert!(Arc::ptr_eq(&indexes.get(&kept).await.unwrap(), &kept_index));
+        assert!(!Arc::ptr_eq(
+            &indexes.get(&removed).await.unwrap(),
+            &removed_index
+        ));
+
+        std::fs::remove_dir_all(&kept).unwrap();
+        std::fs::remove_dir_all(&removed).unwrap();
+    }
+}
@@ -4,12 +4,21 @@ use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::response::Response;
 use path_clean::PathClean;
+use percent_encoding::AsciiSet;
+use percent_encoding::NON_ALPHANUMERIC;
 use std::path::Path;
 use std::path::PathBuf;
 use tokio::fs;
 use tokio::io;
 use tracing::{debug, error, warn};
 
+/// Characters escaped in file names put into URL paths and queries.
+pub const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
 pub async fn validate_path(base_dir: &Path, filename: &str) -> io::Result<Option<PathBuf>> {
     // Basic filename safety check
     if filename.contains("..") || filename.contains('/') || filename.contains('\\') {
@@ -75,6 +84,22 @@ pub async fn should_include_file(base_dir: &Path, path: &Path) -> io::Result<boo
     }
 }
 
+/// Files a client can see in a share, sorted by name.
+pub async fn list_share_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir).await?;
+    let mut files = Vec::new();
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if let Ok(true) = should_include_file(dir, &path).await {
+            files.push(path);
+        }
+    }
+
+    files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    Ok(files)
+}
+
 pub fn error_response(status: StatusCode, message: &str) -> Response {
     debug!(
         "Generating error response: {} - {}",
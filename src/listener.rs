@@ -0,0 +1,311 @@
+use crate::tls::ReloadableTls;
+use crate::tls::TlsListener;
+use axum::Router;
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use std::fmt;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::net::UnixListener;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// A single address the server accepts connections on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix socket path is empty".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+
+        s.parse::<SocketAddr>().map(ListenAddr::Tcp).map_err(|_| {
+            format!(
+                "invalid listen address '{}', expected IP:PORT or unix:PATH",
+                s
+            )
+        })
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parses a comma-separated list such as `127.0.0.1:3000,unix:/run/p4s.sock`.
+pub fn parse_listen_list(s: &str) -> Result<Vec<ListenAddr>, String> {
+    let addrs = s
+        .split(',')
+        .filter(|part| !part.trim().is_empty())
+        .map(ListenAddr::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if addrs.is_empty() {
+        return Err("no listen addresses given".to_string());
+    }
+    Ok(addrs)
+}
+
+/// Address of the peer of a connection, `None` on Unix sockets where the
+/// reverse proxy is the peer.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub Option<IpAddr>);
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        ClientAddr(Some(stream.remote_addr().ip()))
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        ClientAddr(Some(stream.remote_addr().ip()))
+    }
+}
+
+impl Connected<IncomingStream<'_, UnixListener>> for ClientAddr {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        ClientAddr(None)
+    }
+}
+
+pub enum BoundListener {
+    Tcp(TcpListener),
+    Tls(TlsListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// Binds every address up front so misconfiguration fails before serving.
+/// With TLS configured, TCP listeners speak HTTPS; Unix sockets stay plain
+/// since they are only reachable by a local reverse proxy. TLS listeners
+/// stop accepting once `shutdown` is cancelled.
+pub async fn bind_all(
+    addrs: &[ListenAddr],
+    socket_mode: Option<u32>,
+    tls: Option<&Arc<ReloadableTls>>,
+    shutdown: &CancellationToken,
+) -> std::io::Result<Vec<BoundListener>> {
+    let mut bound = Vec::with_capacity(addrs.len());
+
+    for addr in addrs {
+        match addr {
+            ListenAddr::Tcp(socket_addr) => {
+                let listener = TcpListener::bind(socket_addr).await?;
+                let local_addr = listener.local_addr()?;
+                match tls {
+                    Some(tls) => {
+                        info!("Listening on https://{}", local_addr);
+                        bound.push(BoundListener::Tls(TlsListener::new(
+                            listener,
+                            tls.clone(),
+                            shutdown.clone(),
+                        )?));
+                    }
+                    None => {
+                        info!("Listening on http://{}", local_addr);
+                        bound.push(BoundListener::Tcp(listener));
+                    }
+                }
+            }
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                if let Some(mode) = socket_mode {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+                }
+                info!(
+                    "Listening on unix:{} (mode {:o})",
+                    path.display(),
+                    std::fs::metadata(path)?.permissions().mode() & 0o777
+                );
+                bound.push(BoundListener::Unix(listener, path.clone()));
+            }
+        }
+    }
+
+    Ok(bound)
+}
+
+// A socket left behind by a crashed process would make bind fail
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            warn!("Removing stale socket {}", path.display());
+            std::fs::remove_file(path)
+        }
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Serves the app on all listeners until `shutdown` is cancelled. After that
+/// no new connections are accepted and requests in flight get up to
+/// `drain_timeout` to complete. Returns that deadline, for the rest of the
+/// shutdown to keep to the same one.
+pub async fn serve_all(
+    listeners: Vec<BoundListener>,
+    app: Router,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+) -> Instant {
+    let mut tasks = JoinSet::new();
+
+    for listener in listeners {
+        let app = app.clone();
+        let signal = shutdown.clone().cancelled_owned();
+        match listener {
+            BoundListener::Tcp(listener) => {
+                tasks.spawn(async move {
+                    if let Err(e) = axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<ClientAddr>(),
+                    )
+                    .with_graceful_shutdown(signal)
+                    .await
+                    {
+                        error!("TCP listener failed: {}", e);
+                    }
+                });
+            }
+            BoundListener::Tls(listener) => {
+                tasks.spawn(async move {
+                    if let Err(e) = axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<ClientAddr>(),
+                    )
+                    .with_graceful_shutdown(signal)
+                    .await
+                    {
+                        error!("TLS listener failed: {}", e);
+                    }
+                });
+            }
+            BoundListener::Unix(listener, path) => {
+                tasks.spawn(async move {
+                    if let Err(e) = axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<ClientAddr>(),
+                    )
+                    .with_graceful_shutdown(signal)
+                    .await
+                    {
+                        error!("Unix listener failed: {}", e);
+                    }
+                    let _ = std::fs::remove_file(&path);
+                });
+            }
+        }
+    }
+
+    tokio::select! {
+        _ = async { while tasks.join_next().await.is_some() {} } => {
+            return Instant::now() + drain_timeout;
+        }
+        _ = shutdown.cancelled() => {}
+    }
+
+    let deadline = Instant::now() + drain_timeout;
+    info!(
+        "Waiting up to {}s for in-flight requests to finish",
+        drain_timeout.as_secs()
+    );
+    let drained = tokio::time::timeout_at(deadline, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+
+    if drained.is_err() {
+        warn!("Drain timeout reached, aborting remaining connections");
+        tasks.shutdown().await;
+    }
+    deadline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_lists_parse_tcp_and_unix_addresses() {
+        let addrs = parse_listen_list(" 127.0.0.1:3000, [::1]:8443,unix:/run/p4s.sock,").unwrap();
+        assert_eq!(
+            addrs,
+            [
+                ListenAddr::Tcp(([127, 0, 0, 1], 3000).into()),
+                ListenAddr::Tcp("[::1]:8443".parse().unwrap()),
+                ListenAddr::Unix(PathBuf::from("/run/p4s.sock")),
+            ]
+        );
+        assert_eq!(addrs[2].to_string(), "unix:/run/p4s.sock");
+
+        for (list, expected) in [
+            ("", "no listen addresses given"),
+            (" , ", "no listen addresses given"),
+            ("unix:", "unix socket path is empty"),
+            ("localhost:3000", "invalid listen address 'localhost:3000'"),
+            ("127.0.0.1", "invalid listen address '127.0.0.1'"),
+        ] {
+            let err = parse_listen_list(list).unwrap_err();
+            assert!(err.contains(expected), "{:?}: {}", list, err);
+        }
+    }
+
+    #[tokio::test]
+    async fn unix_sockets_replace_stale_ones_but_no_other_files() {
+        let dir = std::env::temp_dir().join(format!("photo4share-listen-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("p4s.sock");
+        let addrs = [ListenAddr::Unix(path.clone())];
+
+        // Left behind by a process that didn't get to clean up
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let bound = bind_all(&addrs, Some(0o660), None, &CancellationToken::new())
+            .await
+            .unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+        drop(bound);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, b"not a socket").unwrap();
+        let err = bind_all(&addrs, None, None, &CancellationToken::new())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,962 @@
+use crate::activity::share_log_dir;
+use crate::config::Config;
+use crate::models::AppState;
+use crate::models::DownloadedEverythingEmail;
+use crate::models::ExpiryReminderEmail;
+use crate::models::GalleryReadyEmail;
+use crate::models::Share;
+use crate::reload::SharedConfig;
+use crate::signed_links::LinkTarget;
+use crate::signed_links::mint_link;
+use crate::tls;
+use askama::Template;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::NaiveDate;
+use chrono::TimeDelta;
+use chrono::Utc;
+use futures_util::future::BoxFuture;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Markers of emails already sent for a share, so none goes out twice.
+const SENT_DIR: &str = ".mail";
+/// Followed by the content hash, so new files bring a new notice once
+/// they are downloaded too.
+const DOWNLOADED_EVERYTHING_MARKER: &str = "downloaded-everything-";
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Expiry reminders are due at most this long after midnight.
+const REMINDER_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Outgoing email, see `[email]` in the config.
+#[derive(Clone, Debug)]
+pub struct MailSettings {
+    pub transport: TransportSettings,
+    /// `From` of every email, e.g. `Studio <studio@example.com>`.
+    pub from: String,
+    /// Gets the notices meant for the photographer.
+    pub photographer: Option<String>,
+    /// How many days before its last day a share's client is reminded.
+    pub reminder_days: u32,
+}
+
+#[derive(Clone, Debug)]
+pub enum TransportSettings {
+    Smtp(SmtpSettings),
+    /// Writes each email as an `.eml` file into the directory instead.
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct SmtpSettings {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS, usually on port 587.
+    StartTls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// No encryption, for a relay on the same host.
+    None,
+}
+
+impl SmtpSecurity {
+    pub fn default_port(self) -> u16 {
+        match self {
+            SmtpSecurity::StartTls => 587,
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::None => 25,
+        }
+    }
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starttls" => Ok(SmtpSecurity::StartTls),
+            "tls" => Ok(SmtpSecurity::Tls),
+            "none" => Ok(SmtpSecurity::None),
+            other => Err(format!(
+                "unknown SMTP security '{}', expected starttls, tls or none",
+                other
+            )),
+        }
+    }
+}
+
+/// One plain-text email.
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl fmt::Display for Email {
+    /// The message as sent: headers, then the body in base64.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+        let now = Utc::now();
+        write!(f, "From: {}\r\n", encode_mailbox(&self.from))?;
+        write!(f, "To: {}\r\n", encode_mailbox(&self.to))?;
+        write!(f, "Subject: {}\r\n", encode_word(&self.subject))?;
+        write!(f, "Date: {}\r\n", now.to_rfc2822())?;
+        write!(
+            f,
+            "Message-ID: <{}.{}@photo4share>\r\n",
+            now.timestamp_millis(),
+            SEQUENCE.fetch_add(1, Ordering::Relaxed)
+        )?;
+        f.write_str("MIME-Version: 1.0\r\n")?;
+        f.write_str("Content-Type: text/plain; charset=utf-8\r\n")?;
+        f.write_str("Content-Transfer-Encoding: base64\r\n\r\n")?;
+        let body = STANDARD.encode(self.body.replace('\n', "\r\n"));
+        for line in body.as_bytes().chunks(76) {
+            f.write_str(std::str::from_utf8(line).expect("base64 is ASCII"))?;
+            f.write_str("\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// RFC 2047 encoded word for header text that isn't plain ASCII.
+fn encode_word(text: &str) -> String {
+    if text.is_ascii() {
+        text.to_string()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(text))
+    }
+}
+
+/// `Name <address>` with the name encoded as needed.
+fn encode_mailbox(mailbox: &str) -> String {
+    match mailbox.split_once('<') {
+        Some((name, rest)) if !name.trim().is_empty() => {
+            format!("{} <{}", encode_word(name.trim()), rest)
+        }
+        _ => mailbox.to_string(),
+    }
+}
+
+/// The bare address of `Name <address>` or `address`.
+pub fn address(mailbox: &str) -> &str {
+    match mailbox.split_once('<') {
+        Some((_, rest)) => rest.trim_end().trim_end_matches('>'),
+        None => mailbox.trim(),
+    }
+}
+
+/// Delivers finished emails. SMTP in production; tests and dry runs can
+/// use the file transport or a transport of their own.
+pub trait MailTransport: Send + Sync {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>>;
+}
+
+pub fn transport(settings: &TransportSettings) -> Box<dyn MailTransport> {
+    match settings {
+        TransportSettings::Smtp(smtp) => Box::new(SmtpTransport(smtp.clone())),
+        TransportSettings::File(dir) => Box::new(FileTransport(dir.clone())),
+    }
+}
+
+pub struct FileTransport(pub PathBuf);
+
+impl MailTransport for FileTransport {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+            let name = format!(
+                "{}-{}.eml",
+                Utc::now().timestamp_millis(),
+                SEQUENCE.fetch_add(1, Ordering::Relaxed)
+            );
+            fs::create_dir_all(&self.0)
+                .await
+                .map_err(|e| e.to_string())?;
+            fs::write(self.0.join(name), email.to_string())
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+pub struct SmtpTransport(pub SmtpSettings);
+
+impl MailTransport for SmtpTransport {
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            tokio::time::timeout(SMTP_TIMEOUT, smtp_send(&self.0, email))
+                .await
+                .map_err(|_| "SMTP server timed out".to_string())?
+        })
+    }
+}
+
+async fn smtp_send(settings: &SmtpSettings, email: &Email) -> Result<(), String> {
+    let stream = TcpStream::connect((settings.host.as_str(), settings.port))
+        .await
+        .map_err(|e| format!("cannot connect to {}: {}", settings.host, e))?;
+    let connector = || -> Result<_, String> {
+        let name = ServerName::try_from(settings.host.clone()).map_err(|e| e.to_string())?;
+        Ok((TlsConnector::from(tls::client_config()?), name))
+    };
+
+    match settings.security {
+        SmtpSecurity::None => smtp_session(stream, true, settings, email).await,
+        SmtpSecurity::Tls => {
+            let (tls, name) = connector()?;
+            let stream = tls
+                .connect(name, stream)
+                .await
+                .map_err(|e| format!("TLS handshake failed: {}", e))?;
+            smtp_session(stream, true, settings, email).await
+        }
+        SmtpSecurity::StartTls => {
+            let mut smtp = Smtp::new(stream);
+            smtp.reply(220).await?;
+            smtp.command("EHLO localhost", 250).await?;
+            smtp.command("STARTTLS", 220).await?;
+            let (tls, name) = connector()?;
+            let stream = tls
+                .connect(name, smtp.stream.into_inner())
+                .await
+                .map_err(|e| format!("TLS handshake failed: {}", e))?;
+            smtp_session(stream, false, settings, email).await
+        }
+    }
+}
+
+/// Everything after the optional TLS upgrade, `greeting` unless the
+/// server already sent it before STARTTLS.
+async fn smtp_session<S>(
+    stream: S,
+    greeting: bool,
+    settings: &SmtpSettings,
+    email: &Email,
+) -> Result<(), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut smtp = Smtp::new(stream);
+    if greeting {
+        smtp.reply(220).await?;
+    }
+    smtp.command("EHLO localhost", 250).await?;
+    if let (Some(username), Some(password)) = (&settings.username, &settings.password) {
+        let credentials = STANDARD.encode(format!("\0{}\0{}", username, password));
+        smtp.command(&format!("AUTH PLAIN {}", credentials), 235)
+            .await?;
+    }
+    smtp.command(&format!("MAIL FROM:<{}>", address(&email.from)), 250)
+        .await?;
+    smtp.command(&format!("RCPT TO:<{}>", address(&email.to)), 250)
+        .await?;
+    smtp.command("DATA", 354).await?;
+    // A line of just "." would end the message early
+    let message = email.to_string().replace("\r\n.", "\r\n..");
+    smtp.command(&format!("{}.", message), 250).await?;
+    // The message is accepted, a rude goodbye doesn't matter
+    let _ = smtp.command("QUIT", 221).await;
+    Ok(())
+}
+
+struct Smtp<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Smtp<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    async fn command(&mut self, line: &str, expected: u16) -> Result<(), String> {
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        stream.flush().await.map_err(|e| e.to_string())?;
+        let verb = line.split(' ').next().unwrap_or_default();
+        self.reply(expected)
+            .await
+            .map_err(|e| format!("{}: {}", verb, e))
+    }
+
+    /// Reads a reply, all lines of it, and checks its code.
+    async fn reply(&mut self, expected: u16) -> Result<(), String> {
+        loop {
+            let mut line = String::new();
+            let read = self
+                .stream
+                .read_line(&mut line)
+                .await
+                .map_err(|e| e.to_string())?;
+            if read == 0 {
+                return Err("connection closed".to_string());
+            }
+            let code: u16 = line.get(..3).and_then(|c| c.parse().ok()).unwrap_or(0);
+            // "250-" continues, "250 " ends the reply
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            return if code == expected {
+                Ok(())
+            } else {
+                Err(format!("server answered {}", line.trim_end()))
+            };
+        }
+    }
+}
+
+/// Renders a template's text, or says which one failed.
+fn render(template: &impl Template) -> Result<String, String> {
+    template
+        .render()
+        .map_err(|e| format!("cannot render email: {}", e))
+}
+
+/// Emails the client of `share` a one-time login link valid for `ttl`,
+/// for the share's first key unless `key` names another. Returns the
+/// address it went to.
+pub async fn send_gallery_ready(
+    config: &Config,
+    transport: &dyn MailTransport,
+    share: &Share,
+    key: Option<String>,
+    ttl: Duration,
+) -> Result<String, String> {
+    let mail = config
+        .email
+        .as_ref()
+        .ok_or("configure [email] to send emails")?;
+    let to = share
+        .email
+        .clone()
+        .ok_or_else(|| format!("{}: set the client's email first", share.name))?;
+    let secret = config
+        .link_secret
+        .as_deref()
+        .ok_or("set server.link_secret (LINK_SECRET) to mint login links")?;
+    let base = config
+        .public_url
+        .as_deref()
+        .ok_or("set server.public_url (PUBLIC_URL) for links in emails")?;
+    if let Some(key) = &key
+        && !share.keys.iter().any(|k| &k.name == key)
+    {
+        return Err(format!("{}: no key named '{}'", share.name, key));
+    }
+
+    let link = mint_link(secret, &share.name, &LinkTarget::Login { key }, ttl, true)
+        .map_err(|e| format!("{}: {}", share.name, e))?;
+    let link_expires = TimeDelta::from_std(ttl)
+        .ok()
+        .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+        .ok_or_else(|| format!("{}: link lifetime is too long", share.name))?;
+    let body = render(&GalleryReadyEmail {
+        greet: share.greet.clone(),
+        share: share.name.clone(),
+        link: format!("{}{}", base, link),
+        link_expires: link_expires.format("%Y-%m-%d %H:%M UTC").to_string(),
+        expires: share.expires,
+    })?;
+    let email = Email {
+        from: mail.from.clone(),
+        to,
+        subject: format!("Ваша галерея «{}» готова", share.name),
+        body,
+    };
+    transport.send(&email).await?;
+    info!("Sent gallery ready email for share '{}'", share.name);
+    Ok(email.to)
+}
+
+/// Reminds the clients of shares whose last day is at most
+/// `reminder_days` after `today`, once per expiry date. Returns how many
+/// reminders went out.
+pub async fn send_expiry_reminders(
+    config: &Config,
+    transport: &dyn MailTransport,
+    today: NaiveDate,
+) -> usize {
+    let Some(mail) = &config.email else {
+        return 0;
+    };
+    let mut sent = 0;
+    for share in &config.shares {
+        let (Some(to), Some(expires)) = (&share.email, share.expires) else {
+            continue;
+        };
+        let days_left = (expires - today).num_days();
+        if days_left < 0 || days_left > i64::from(mail.reminder_days) {
+            continue;
+        }
+        // Moving the expiry date earns the client another reminder
+        let marker = format!("expiry-reminder-{}", expires);
+        if !claim_marker(&share.dir, &marker).await {
+            continue;
+        }
+
+        let email = render(&ExpiryReminderEmail {
+            greet: share.greet.clone(),
+            share: share.name.clone(),
+            expires,
+            url: config.public_url.as_ref().map(|url| format!("{}/", url)),
+        })
+        .map(|body| Email {
+            from: mail.from.clone(),
+            to: to.clone(),
+            subject: format!("Галерея «{}» скоро закриється", share.name),
+            body,
+        });
+        match send_claimed(transport, email, &share.dir, &marker).await {
+            Ok(()) => {
+                info!("Sent expiry reminder for share '{}'", share.name);
+                sent += 1;
+            }
+            Err(e) => warn!(
+                "Cannot send expiry reminder for share '{}': {}",
+                share.name, e
+            ),
+        }
+    }
+    sent
+}
+
+/// Tells the photographer, once, that the client now has every file of
+/// `share`. Called after each completed download; relies on the downloads
+/// the activity log keeps track of to know what was downloaded before.
+pub async fn notify_downloaded_everything(state: &AppState, share: &Arc<Share>, key: Option<&str>) {
+    let config = state.config.load();
+    let (Some(mail), true) = (&config.email, config.activity.enabled) else {
+        return;
+    };
+    let Some(photographer) = &mail.photographer else {
+        return;
+    };
+    let Ok(index) = state.indexes.get(&share.dir).await else {
+        return;
+    };
+    let contents = index.content_hash(false).await;
+    let marker = format!("{}{}", DOWNLOADED_EVERYTHING_MARKER, contents);
+    if fs::try_exists(share.dir.join(SENT_DIR).join(&marker))
+        .await
+        .unwrap_or(false)
+    {
+        return;
+    }
+    let files = index.files();
+    let parts = share.zip.parts(&files).len();
+    let log_dir = share_log_dir(&config.state_dir, &share.name);
+    let (everything, bytes_sent, last_activity) = state
+        .activity
+        .downloads(&log_dir, |downloads| {
+            (
+                downloads.has_everything(&files, &contents, parts),
+                downloads.bytes_sent,
+                downloads.last_activity.clone(),
+            )
+        })
+        .await;
+    if !everything || !claim_marker(&share.dir, &marker).await {
+        return;
+    }
+    remove_markers_except(&share.dir, DOWNLOADED_EVERYTHING_MARKER, &marker).await;
+
+    let email = render(&DownloadedEverythingEmail {
+        share: share.name.clone(),
+        files: files.len(),
+        bytes_sent,
+        key: key.map(str::to_string),
+        time: last_activity.unwrap_or_default(),
+    })
+    .map(|body| Email {
+        from: mail.from.clone(),
+        to: photographer.clone(),
+        subject: format!("Клієнт завантажив усе з «{}»", share.name),
+        body,
+    });
+    let transport = transport(&mail.transport);
+    match send_claimed(&*transport, email, &share.dir, &marker).await {
+        Ok(()) => info!("Told the photographer share '{}' is downloaded", share.name),
+        Err(e) => warn!(
+            "Cannot send the downloaded notice for share '{}': {}",
+            share.name, e
+        ),
+    }
+}
+
+/// Creates the marker of an email about to be sent, false if it exists.
+async fn claim_marker(dir: &Path, name: &str) -> bool {
+    let sent = dir.join(SENT_DIR);
+    if let Err(e) = fs::create_dir_all(&sent).await {
+        warn!("Cannot create {:?}: {}", sent, e);
+        return false;
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(sent.join(name))
+        .await
+        .is_ok()
+}
+
+/// Removes the markers starting with `prefix` but `keep`, those of
+/// contents the share no longer has.
+async fn remove_markers_except(dir: &Path, prefix: &str, keep: &str) {
+    let Ok(mut entries) = fs::read_dir(dir.join(SENT_DIR)).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(prefix) && name != keep {
+            let _ = fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+/// Sends an email whose marker was claimed, releasing the marker again
+/// if it didn't go out so a later attempt can retry.
+async fn send_claimed(
+    transport: &dyn MailTransport,
+    email: Result<Email, String>,
+    dir: &Path,
+    marker: &str,
+) -> Result<(), String> {
+    let sent = match email {
+        Ok(email) => transport.send(&email).await,
+        Err(e) => Err(e),
+    };
+    if sent.is_err() {
+        let _ = fs::remove_file(dir.join(SENT_DIR).join(marker)).await;
+    }
+    sent
+}
+
+/// Checks for due expiry reminders every hour until shutdown.
+pub fn spawn_reminder(config: SharedConfig, shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        loop {
+            let snapshot = config.load_full();
+            if let Some(mail) = &snapshot.email {
+                let transport = transport(&mail.transport);
+                let today = Utc::now().date_naive();
+                send_expiry_reminders(&snapshot, &*transport, today).await;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(REMINDER_INTERVAL) => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ActivitySettings;
+    use crate::config::CacheSettings;
+    use crate::models::Permission;
+    use crate::models::ShareKey;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    /// Keeps what it is given instead of sending it.
+    #[derive(Default)]
+    struct MockTransport {
+        sent: Mutex<Vec<Email>>,
+        fail: bool,
+    }
+
+    impl MailTransport for MockTransport {
+        fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+            Box::pin(async move {
+                if self.fail {
+                    return Err("mock failure".to_string());
+                }
+                self.sent.lock().unwrap().push(email.clone());
+                Ok(())
+            })
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("photo4share-mail-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_config(dir: &Path, expires: Option<NaiveDate>) -> Config {
+        let share = Share {
+            name: "smith".to_string(),
+            dir: dir.to_path_buf(),
+            keys: vec![ShareKey {
+                name: "owner".to_string(),
+                key_hash: crate::auth::hash_key("mail test key of the share"),
+                role: "full".to_string(),
+                permissions: Permission::ALL.to_vec(),
+            }],
+            greet: "Вітаємо!".to_string(),
+            zip: Default::default(),
+            upload: None,
+            email: Some("client@example.com".to_string()),
+            expires,
+        };
+        Config {
+            shares: vec![Arc::new(share)],
+            listen: Vec::new(),
+            unix_socket_mode: None,
+            shutdown_timeout: Duration::from_secs(1),
+            tls: None,
+            cache: CacheSettings {
+                max_total_bytes: None,
+                max_age: None,
+                keep_latest_only: false,
+                eviction_interval: Duration::from_secs(60),
+                prebuild: false,
+            },
+            link_secret: Some("a link secret that is long enough".to_string()),
+            public_url: Some("https://photos.example.com".to_string()),
+            admin_key_hash: None,
+            state_dir: dir.join("state"),
+            activity: ActivitySettings {
+                enabled: true,
+                anonymize: false,
+            },
+            webhooks: Vec::new(),
+            email: Some(MailSettings {
+                transport: TransportSettings::File(dir.join("outgoing")),
+                from: "Студія Світло <studio@example.com>".to_string(),
+                photographer: Some("studio@example.com".to_string()),
+                reminder_days: 3,
+            }),
+        }
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    /// The body of a formatted message, decoded again.
+    fn decoded_body(message: &str) -> String {
+        let (_, body) = message.split_once("\r\n\r\n").unwrap();
+        let bytes = STANDARD.decode(body.replace("\r\n", "")).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn gallery_ready_carries_a_login_link() {
+        let dir = temp_dir("ready");
+        let config = test_config(&dir, Some(date("2030-01-31")));
+        let transport = MockTransport::default();
+
+        let to = send_gallery_ready(
+            &config,
+            &transport,
+            &config.shares[0],
+            None,
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+        assert_eq!(to, "client@example.com");
+
+        let sent = transport.sent.lock().unwrap().clone();
+        let body = &sent[0].body;
+        assert!(body.starts_with("Вітаємо!\n\nВаша галерея «smith» готова"));
+        assert!(body.contains("https://photos.example.com/login/link?share=smith&expires="));
+        assert!(body.contains("2030-01-31"));
+
+        let message = sent[0].to_string();
+        assert!(message.contains("From: =?UTF-8?B?"));
+        assert!(message.contains(" <studio@example.com>\r\n"));
+        assert!(message.contains("Subject: =?UTF-8?B?"));
+        assert_eq!(decoded_body(&message), body.replace('\n', "\r\n"));
+
+        let err = send_gallery_ready(
+            &config,
+            &transport,
+            &config.shares[0],
+            Some("guests".to_string()),
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err, "smith: no key named 'guests'");
+
+        // Fine for the link's u64 expiry, too far for a calendar date
+        let err = send_gallery_ready(
+            &config,
+            &transport,
+            &config.shares[0],
+            None,
+            Duration::from_secs(u64::MAX / 2),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err, "smith: link lifetime is too long");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn expiry_reminders_go_out_once_per_date() {
+        let dir = temp_dir("reminder");
+        let config = test_config(&dir, Some(date("2030-01-31")));
+        let transport = MockTransport::default();
+
+        assert_eq!(
+            send_expiry_reminders(&config, &transport, date("2030-01-27")).await,
+            0
+        );
+        let failing = MockTransport {
+            fail: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            send_expiry_reminders(&config, &failing, date("2030-01-28")).await,
+            0
+        );
+        assert_eq!(
+            send_expiry_reminders(&config, &transport, date("2030-01-28")).await,
+            1
+        );
+        assert_eq!(
+            send_expiry_reminders(&config, &transport, date("2030-01-29")).await,
+            0
+        );
+        assert_eq!(
+            send_expiry_reminders(&config, &transport, date("2030-02-01")).await,
+            0
+        );
+
+        let sent = transport.sent.lock().unwrap().clone();
+        assert_eq!(sent[0].to, "client@example.com");
+        assert!(sent[0].body.contains("до 2030-01-31 включно"));
+        assert!(sent[0].body.contains("https://photos.example.com/"));
+
+        let moved = test_config(&dir, Some(date("2030-03-31")));
+        assert_eq!(
+            send_expiry_reminders(&moved, &transport, date("2030-03-30")).await,
+            1
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloaded_everything_is_told_once_per_contents() {
+        use crate::activity::ActivityEvent;
+        use crate::activity::ActivityLog;
+        use crate::activity::EventKind;
+        use crate::file_index::FileIndexes;
+        use crate::previews::PreviewRenderer;
+        use crate::signed_links::UsedLinks;
+        use crate::upload_utils::UploadQuotas;
+        use crate::webhooks::WebhookOutbox;
+        use crate::zip_builder::ZipBuilder;
+        use crate::zip_cache::ActiveArchives;
+        use arc_swap::ArcSwap;
+
+        let dir = temp_dir("everything");
+        std::fs::write(dir.join("a.jpg"), b"a").unwrap();
+        let config = test_config(&dir, None);
+        let state = AppState {
+            used_links: Arc::new(UsedLinks::new(&config.state_dir)),
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            indexes: Arc::new(FileIndexes::default()),
+            zip_builder: Arc::new(ZipBuilder::new(Arc::new(ActiveArchives::default()))),
+            activity: Arc::new(ActivityLog::default()),
+            webhooks: Arc::new(WebhookOutbox::default()),
+            uploads: Arc::new(UploadQuotas::default()),
+            previews: Arc::new(PreviewRenderer::default()),
+        };
+        let share = state.config.load().shares[0].clone();
+        let download_archive = || async {
+            let index = state.indexes.get(&dir).await.unwrap();
+            let event = ActivityEvent {
+                time: "2030-01-01T12:00:00Z".to_string(),
+                kind: EventKind::ArchiveDownload {
+                    format: "zip".to_string(),
+                    part: None,
+                    contents: Some(index.content_hash(false).await),
+                    parts: 1,
+                    bytes: 10,
+                    complete: true,
+                },
+                key: Some("owner".to_string()),
+                ip: None,
+                user_agent: None,
+            };
+            let log_dir = share_log_dir(&state.config.load().state_dir, &share.name);
+            state.activity.append(&log_dir, &event).await;
+            notify_downloaded_everything(&state, &share, Some("owner")).await;
+        };
+        let told = || std::fs::read_dir(dir.join("outgoing")).map_or(0, |d| d.count());
+
+        download_archive().await;
+        download_archive().await;
+        assert_eq!(told(), 1);
+
+        // The earlier archive lacks the new file, so nothing until it's had too
+        let mut generations = state.indexes.get(&dir).await.unwrap().subscribe();
+        std::fs::write(dir.join("b.jpg"), b"b").unwrap();
+        tokio::time::timeout(Duration::from_secs(10), generations.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        notify_downloaded_everything(&state, &share, Some("owner")).await;
+        assert_eq!(told(), 1);
+        download_archive().await;
+        assert_eq!(told(), 2);
+        let markers: Vec<_> = std::fs::read_dir(dir.join(SENT_DIR)).unwrap().collect();
+        assert_eq!(markers.len(), 1, "the marker of the old contents is gone");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_transport_writes_eml_files() {
+        let dir = temp_dir("file");
+        let outgoing = dir.join("outgoing");
+        let email = Email {
+            from: "studio@example.com".to_string(),
+            to: "client@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "line one\nline two".to_string(),
+        };
+        FileTransport(outgoing.clone()).send(&email).await.unwrap();
+
+        let files: Vec<_> = std::fs::read_dir(&outgoing).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let path = files[0].as_ref().unwrap().path();
+        assert_eq!(path.extension().unwrap(), "eml");
+        let message = std::fs::read_to_string(path).unwrap();
+        assert!(message.starts_with("From: studio@example.com\r\nTo: client@example.com\r\n"));
+        assert!(message.contains("Subject: Hello\r\n"));
+        assert_eq!(decoded_body(&message), "line one\r\nline two");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Plays the server side of an SMTP session and returns what the
+    /// client said.
+    async fn smtp_stand_in(listener: TcpListener) -> Vec<String> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        let mut said = Vec::new();
+        stream
+            .get_mut()
+            .write_all(b"220 stand-in ESMTP\r\n")
+            .await
+            .unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                break;
+            }
+            let line = line.trim_end_matches("\r\n").to_string();
+            let reply: &[u8] = if in_data {
+                if line != "." {
+                    said.push(line);
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("EHLO") {
+                b"250-stand-in\r\n250-AUTH PLAIN\r\n250 8BITMIME\r\n"
+            } else if line.starts_with("AUTH") {
+                b"235 ok\r\n"
+            } else if line == "DATA" {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line == "QUIT" {
+                said.push(line);
+                stream.get_mut().write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            said.push(line);
+            stream.get_mut().write_all(reply).await.unwrap();
+        }
+        said
+    }
+
+    #[tokio::test]
+    async fn smtp_transport_speaks_to_a_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(smtp_stand_in(listener));
+
+        let transport = SmtpTransport(SmtpSettings {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            username: Some("studio".to_string()),
+            password: Some("secret".to_string()),
+        });
+        let email = Email {
+            from: "Studio <studio@example.com>".to_string(),
+            to: "client@example.com".to_string(),
+            subject: "Готово".to_string(),
+            body: "Вітаємо!".to_string(),
+        };
+        transport.send(&email).await.unwrap();
+
+        let said = server.await.unwrap();
+        assert_eq!(said[0], "EHLO localhost");
+        assert_eq!(
+            said[1],
+            format!("AUTH PLAIN {}", STANDARD.encode("\0studio\0secret"))
+        );
+        assert_eq!(said[2], "MAIL FROM:<studio@example.com>");
+        assert_eq!(said[3], "RCPT TO:<client@example.com>");
+        assert_eq!(said[4], "DATA");
+        assert!(said.contains(&"To: client@example.com".to_string()));
+        let blank = said.iter().position(|l| l.is_empty()).unwrap();
+        let end = said.iter().position(|l| l == ".").unwrap();
+        let body = STANDARD.decode(said[blank + 1..end].concat()).unwrap();
+        assert_eq!(String::from_utf8(body).unwrap(), "Вітаємо!");
+    }
+
+    #[test]
+    fn addresses_come_out_of_mailboxes() {
+        assert_eq!(address("Studio <studio@example.com>"), "studio@example.com");
+        assert_eq!(address(" client@example.com "), "client@example.com");
+    }
+}
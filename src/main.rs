@@ -1,23 +1,60 @@
+mod activity;
+mod aes_zip;
 mod auth;
+mod checksums;
+mod cli;
+mod config;
+mod dimensions;
+mod file_index;
 mod file_utils;
+mod listener;
+mod mail;
 mod models;
+mod previews;
+mod reload;
 mod routes;
+mod shutdown;
+mod signed_links;
+mod tar_utils;
+mod tls;
+mod upload_utils;
+mod webhooks;
+mod zip_builder;
+mod zip_cache;
 mod zip_utils;
 
+use crate::activity::ActivityLog;
+use crate::cli::Cli;
+use crate::cli::Command;
+use crate::config::Config;
+use crate::file_index::FileIndexes;
 use crate::models::AppState;
+use crate::previews::PreviewRenderer;
+use crate::signed_links::UsedLinks;
+use crate::upload_utils::UploadQuotas;
+use crate::webhooks::WebhookOutbox;
+use crate::zip_builder::ZipBuilder;
+use crate::zip_cache::ActiveArchives;
+use arc_swap::ArcSwap;
 use axum::Router;
+use axum::extract::DefaultBodyLimit;
 use axum::routing::get;
 use axum::routing::post;
+use clap::Parser;
 use dotenvy::dotenv;
 use routes::static_handler;
-use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
 use tower_cookies::CookieManagerLayer;
-use tracing::{Level, info};
+use tracing::{Level, error, info, warn};
 use tracing_subscriber::{EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     dotenv().ok();
+    let cli = Cli::parse();
 
     tracing_subscriber::registry()
         .with(fmt::layer().with_writer(std::io::stdout))
@@ -28,39 +65,420 @@ async fn main() {
         )
         .init();
 
+    let config_path = config::resolve_path(cli.config.as_deref());
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config_path).await,
+        command => cli::run(command, config_path).await,
+    }
+}
+
+async fn serve(config_path: Option<PathBuf>) -> ExitCode {
     info!("Starting photo4share application");
 
-    let share_dir = env::var("SHARE_DIR").expect("SHARE_DIR not set");
-    let share_key = env::var("SHARE_KEY").expect("SHARE_KEY not set");
-    let greet = env::var("GREET").expect("GREET not set");
+    let config = match config::load(config_path.as_deref()) {
+        Ok(c) => c,
+        Err(errors) => {
+            error!("{}", errors);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match &config_path {
+        Some(path) => info!("Configuration loaded from {:?}", path),
+        None => info!("Configuration loaded from environment"),
+    }
+    for share in &config.shares {
+        info!("Share '{}': {:?}", share.name, share.dir);
+        if let Some(upload) = &share.upload {
+            info!(
+                "Share '{}' accepts uploads, quota: {} bytes, max file: {} bytes",
+                share.name, upload.quota_bytes, upload.max_file_bytes
+            );
+        }
+        if let Err(e) = zip_utils::cleanup_temp_files(&share.dir).await {
+            warn!("Failed to clean ZIP temp files: {}", e);
+        }
+        activity::move_share_log(share, &config.state_dir).await;
+    }
+
+    let shared_config = Arc::new(ArcSwap::from_pointee(config.clone()));
+    let indexes = Arc::new(FileIndexes::default());
+    reload::spawn_config_reloader(config_path.clone(), shared_config.clone(), indexes.clone());
 
-    info!("Configuration loaded, share directory: {}", share_dir);
+    let active_archives = Arc::new(ActiveArchives::default());
+    let shutdown_token = CancellationToken::new();
+    zip_cache::spawn_evictor(
+        shared_config.clone(),
+        indexes.clone(),
+        active_archives.clone(),
+        shutdown_token.clone(),
+    );
+
+    let zip_builder = Arc::new(ZipBuilder::new(active_archives.clone()));
+    zip_builder::spawn_prebuilder(
+        shared_config.clone(),
+        indexes.clone(),
+        zip_builder.clone(),
+        shutdown_token.clone(),
+    );
+
+    let webhooks = Arc::new(WebhookOutbox::default());
+    webhooks::spawn_sender(
+        shared_config.clone(),
+        webhooks.clone(),
+        shutdown_token.clone(),
+    );
+
+    mail::spawn_reminder(shared_config.clone(), shutdown_token.clone());
 
     let state = AppState {
-        share_dir: share_dir.into(),
-        share_key,
-        greet,
+        config: shared_config.clone(),
+        indexes,
+        zip_builder: zip_builder.clone(),
+        used_links: Arc::new(UsedLinks::new(&config.state_dir)),
+        activity: Arc::new(ActivityLog::default()),
+        webhooks,
+        uploads: Arc::new(UploadQuotas::default()),
+        previews: Arc::new(PreviewRenderer::default()),
+    };
+    let app = build_router(state);
+
+    let tls = match config.tls.clone().map(tls::ReloadableTls::load).transpose() {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!("Failed to load the TLS certificate: {}", e);
+            return ExitCode::FAILURE;
+        }
     };
+    if let Some(tls) = &tls {
+        tls::spawn_reloader(tls.clone());
+    }
 
+    info!(
+        "Router configured, binding {} listener(s)",
+        config.listen.len()
+    );
+    let listeners = match listener::bind_all(
+        &config.listen,
+        config.unix_socket_mode,
+        tls.as_ref(),
+        &shutdown_token,
+    )
+    .await
+    {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Failed to bind listeners: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    shutdown::spawn_signal_listener(shutdown_token.clone());
+    // Requests and ZIP builds get the same deadline, not one after the other
+    let deadline =
+        listener::serve_all(listeners, app, shutdown_token, config.shutdown_timeout).await;
+    zip_builder.shutdown(deadline).await;
+
+    cleanup(&shared_config.load()).await;
+    info!("Server shutdown");
+    ExitCode::SUCCESS
+}
+
+fn build_router(state: AppState) -> Router {
     let login_router = Router::new()
         .route("/login", get(routes::show_login_form))
-        .route("/login", post(routes::process_login));
+        .route("/login", post(routes::process_login))
+        .route("/login/link", get(routes::login_with_link))
+        .route("/login/link", post(routes::confirm_login_link));
 
     let downloads_router = Router::new()
         .route("/download-zip", get(routes::download_zip))
-        .route("/download/{filename}", get(routes::download_file));
+        .route("/download-zip/status", get(routes::download_zip_status))
+        .route("/download/{filename}", get(routes::download_file))
+        .route("/preview/{filename}", get(routes::preview_file))
+        .route("/checksums/{name}", get(routes::download_checksums));
+
+    // Bearer tokens or the session cookie, and JSON errors throughout
+    let (api_router, _) = routes::api_router();
+
+    // Upload size is enforced while streaming against the quota instead
+    let upload_router = Router::new()
+        .route("/upload", get(routes::show_upload_form))
+        .route("/upload", post(routes::process_upload))
+        .layer(DefaultBodyLimit::disable());
 
-    let app = Router::new()
+    Router::new()
         .route("/", get(routes::index))
         .merge(login_router)
         .merge(downloads_router)
+        .merge(upload_router)
+        .merge(api_router)
+        .route("/admin", get(routes::admin_page))
         .route("/static/{path}", get(static_handler))
         .fallback(routes::handle_404)
         .with_state(state)
-        .layer(CookieManagerLayer::new());
+        .layer(CookieManagerLayer::new())
+}
 
-    info!("Router configured, starting server on 0.0.0.0:3000");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-    info!("Server shutdown");
+async fn cleanup(config: &Config) {
+    for share in &config.shares {
+        if let Err(e) = zip_utils::cleanup_temp_files(&share.dir).await {
+            warn!("Failed to clean ZIP temp files: {}", e);
+        }
+        activity::move_share_log(share, &config.state_dir).await;
+    }
+}
+
+#[cfg(test)]
+mod test_app;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UploadConfig;
+    use crate::test_app::*;
+    use crate::zip_utils::ZipOptions;
+    use axum::body::Body;
+    use axum::http::Method;
+    use axum::http::Request;
+    use axum::http::StatusCode;
+    use axum::http::header;
+    use serde_json::Value;
+
+    /// Every operation of the served document must be routed in `main` and
+    /// answer only with documented statuses and content types.
+    #[tokio::test]
+    async fn openapi_document_matches_router() {
+        let app = TestApp::builder("openapi")
+            .file(FILE, b"\xff\xd8\xff\xe0 not really a jpeg")
+            .build();
+
+        let response = app.send(Method::GET, routes::OPENAPI_PATH, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let document = json(response).await;
+        let mut paths: Vec<&str> = document["paths"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "/api/v1/admin/activity",
+                "/api/v1/admin/shares/{share}/activity",
+                "/api/v1/archive",
+                "/api/v1/files",
+                "/api/v1/files/{filename}",
+                "/api/v1/files/{filename}/download",
+                "/api/v1/files/{filename}/preview",
+                "/api/v1/selection",
+                "/api/v1/share",
+            ]
+        );
+        assert!(document["paths"]["/api/v1/selection"]["post"].is_object());
+        let part = &document["paths"]["/api/v1/archive"]["get"]["parameters"];
+        assert!(part.as_array().unwrap().iter().any(|p| p["name"] == "part"));
+
+        let schemas = &document["components"]["schemas"];
+        let properties = |schema: &str| -> Vec<String> {
+            let mut names: Vec<String> = schemas[schema]["properties"]
+                .as_object()
+                .unwrap_or_else(|| panic!("no schema {}", schema))
+                .keys()
+                .cloned()
+                .collect();
+            names.sort();
+            names
+        };
+        assert_eq!(properties("ApiError"), ["error", "message", "status"]);
+        assert_eq!(
+            properties("ApiFile"),
+            [
+                "height",
+                "mime_type",
+                "modified",
+                "name",
+                "preview_url",
+                "size",
+                "url",
+                "width"
+            ]
+        );
+        assert_eq!(schemas["ApiFile"]["properties"]["size"]["type"], "integer");
+        assert!(properties("ApiShare").contains(&"archive".to_string()));
+        let schemes = &document["components"]["securitySchemes"];
+        assert_eq!(schemes["bearer"]["scheme"], "bearer");
+        assert_eq!(schemes["session"]["name"], auth::KEY_COOKIE);
+        assert_eq!(schemes["login_link"]["name"], auth::SESSION_COOKIE);
+
+        let paths = document["paths"].as_object().unwrap();
+        assert!(!paths.is_empty());
+        for (path, operations) in paths {
+            let uri = path.replace("{filename}", FILE).replace("{share}", SHARE);
+            let key = if path.contains("/admin/") {
+                ADMIN_KEY
+            } else {
+                KEY
+            };
+            for (method, operation) in operations.as_object().unwrap() {
+                let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+                let documented = operation["responses"].as_object().unwrap();
+
+                let response = app.send(method.clone(), &uri, Some(key)).await;
+                let status = response.status();
+                let content_type = response
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                let Some(documented_status) = documented.get(status.as_str()) else {
+                    panic!("{} {} answered undocumented {}", method, path, status);
+                };
+                let media_types = documented_status["content"].as_object().unwrap();
+                assert!(
+                    media_types.keys().any(|m| content_type.starts_with(m)),
+                    "{} {} answered {} with undocumented {}",
+                    method,
+                    path,
+                    status,
+                    content_type
+                );
+
+                // A view-only key is refused with a documented status
+                let response = app.send(method.clone(), &uri, Some(VIEW_KEY)).await;
+                let status = response.status();
+                assert!(
+                    documented.contains_key(status.as_str()),
+                    "{} {} answered a view-only key with undocumented {}",
+                    method,
+                    path,
+                    status
+                );
+                if path.ends_with("/download") || path.ends_with("/archive") {
+                    assert_eq!(status, StatusCode::FORBIDDEN, "{}", path);
+                }
+
+                let response = app.send(method.clone(), &uri, None).await;
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", path);
+                assert!(documented.contains_key("401"), "{} {}", method, path);
+            }
+        }
+
+        // Anything else under the prefix hits the JSON fallback
+        for uri in ["/api/v1/nope", "/api/v1/files/a/b/c"] {
+            let response = app.send(Method::GET, uri, Some(KEY)).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+        let response = app.send(Method::GET, "/api/v1/nope", Some(KEY)).await;
+        assert_eq!(json(response).await["message"], "No such API endpoint");
+
+        // Rejected requests get the same JSON errors as the handlers' own
+        let rejected = [
+            (
+                Method::DELETE,
+                "/api/v1/share",
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (
+                Method::POST,
+                routes::OPENAPI_PATH,
+                StatusCode::METHOD_NOT_ALLOWED,
+            ),
+            (Method::GET, "/api/v1/files/%FF", StatusCode::BAD_REQUEST),
+            (
+                Method::GET,
+                "/api/v1/archive?part=x",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                Method::GET,
+                "/api/v1/admin/shares/%FF/activity",
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (method, uri, status) in rejected {
+            let key = if uri.contains("/admin/") {
+                ADMIN_KEY
+            } else {
+                KEY
+            };
+            let response = app.send(method.clone(), uri, Some(key)).await;
+            assert_eq!(response.status(), status, "{} {}", method, uri);
+            assert_eq!(
+                json(response).await["status"],
+                status.as_u16(),
+                "{} {}",
+                method,
+                uri
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn only_keys_with_the_upload_permission_reach_the_inbox() {
+        let app = TestApp::builder("inbox")
+            .share(|share| {
+                share.upload = Some(UploadConfig {
+                    quota_bytes: 1024 * 1024,
+                    max_file_bytes: 1024 * 1024,
+                    allowed_extensions: Vec::new(),
+                    notify_command: None,
+                })
+            })
+            .build();
+        let request = |method: Method, key: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/upload")
+                .header(header::COOKIE, key_cookie(key))
+                .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+                .body(Body::from("--X--\r\n"))
+                .unwrap()
+        };
+
+        let response = app.request(request(Method::GET, KEY)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for method in [Method::GET, Method::POST] {
+            let response = app.request(request(method.clone(), VIEW_KEY)).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", method);
+        }
+        assert!(!upload_utils::inbox_dir(&app.dir).exists());
+
+        let response = app.send(Method::GET, "/api/v1/share", Some(VIEW_KEY)).await;
+        assert_eq!(json(response).await["upload_enabled"], false);
+        let response = app.send(Method::GET, "/api/v1/share", Some(KEY)).await;
+        assert_eq!(json(response).await["upload_enabled"], true);
+    }
+
+    #[tokio::test]
+    async fn encrypted_shares_refuse_single_files() {
+        let zip = ZipOptions {
+            password: Some("hunter2".to_string()),
+            ..ZipOptions::default()
+        };
+        let app = TestApp::builder("encrypted")
+            .file(FILE, b"secret")
+            .share(|share| share.zip = zip)
+            .build();
+
+        let uri = format!("/api/v1/files/{}/download", FILE);
+        let response = app.send(Method::GET, &uri, Some(KEY)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            json(response).await["message"],
+            "This share is only available as an encrypted ZIP"
+        );
+
+        let uri = format!("/download/{}", FILE);
+        let response = app.get_with_cookie(&uri, &key_cookie(KEY)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let uri = format!("/api/v1/files/{}", FILE);
+        let response = app.send(Method::GET, &uri, Some(KEY)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json(response).await["url"], Value::Null);
+    }
 }
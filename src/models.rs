@@ -1,12 +1,147 @@
+use crate::activity::ActivityLog;
+use crate::file_index::FileIndexes;
+use crate::previews::PreviewRenderer;
+use crate::reload::SharedConfig;
+use crate::signed_links::UsedLinks;
+use crate::upload_utils::UploadQuotas;
+use crate::webhooks::WebhookOutbox;
+use crate::zip_builder::ZipBuilder;
+use crate::zip_utils::ZipOptions;
 use askama::Template;
+use chrono::NaiveDate;
+use chrono::Utc;
 use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use utoipa::IntoParams;
+use utoipa::ToSchema;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub share_dir: PathBuf,
-    pub share_key: String,
+    pub config: SharedConfig,
+    pub indexes: Arc<FileIndexes>,
+    pub zip_builder: Arc<ZipBuilder>,
+    pub used_links: Arc<UsedLinks>,
+    pub activity: Arc<ActivityLog>,
+    pub webhooks: Arc<WebhookOutbox>,
+    pub uploads: Arc<UploadQuotas>,
+    pub previews: Arc<PreviewRenderer>,
+}
+
+/// A directory delivered to one client, unlocked by its key.
+pub struct Share {
+    pub name: String,
+    pub dir: PathBuf,
+    /// Never empty. The first is the share-level `key`/`key_hash`, if set.
+    pub keys: Vec<ShareKey>,
     pub greet: String,
+    /// How the share's ZIP is built.
+    pub zip: ZipOptions,
+    pub upload: Option<UploadConfig>,
+    /// The client's address, for the "gallery ready" and expiry emails.
+    pub email: Option<String>,
+    /// Last day the share can be opened, in UTC.
+    pub expires: Option<NaiveDate>,
+}
+
+impl Share {
+    /// Past its last day, so no key, session or link opens it any more.
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|last_day| Utc::now().date_naive() > last_day)
+    }
+}
+
+/// One of several keys to a share, e.g. for the couple and for the guests.
+pub struct ShareKey {
+    /// Shown in logs and picked by `login-link create --key`.
+    pub name: String,
+    /// Hash of the access key, see `auth::hash_key`.
+    pub key_hash: [u8; 32],
+    pub role: String,
+    /// What the role allows.
+    pub permissions: Vec<Permission>,
+}
+
+/// Something a share key may allow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// See the file list.
+    List,
+    /// Open web-size previews of images.
+    Preview,
+    /// Download original files.
+    Download,
+    /// Download the whole share as an archive.
+    DownloadZip,
+    Comment,
+    Select,
+    /// Send files to the share's inbox, if it has one.
+    Upload,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 7] = [
+        Permission::List,
+        Permission::Preview,
+        Permission::Download,
+        Permission::DownloadZip,
+        Permission::Comment,
+        Permission::Select,
+        Permission::Upload,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::List => "list",
+            Permission::Preview => "preview",
+            Permission::Download => "download",
+            Permission::DownloadZip => "download_zip",
+            Permission::Comment => "comment",
+            Permission::Select => "select",
+            Permission::Upload => "upload",
+        }
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL
+            .into_iter()
+            .find(|p| p.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown permission '{}', expected one of: {}",
+                    s,
+                    Permission::ALL.map(Permission::as_str).join(", ")
+                )
+            })
+    }
+}
+
+/// Settings for the reverse-share inbox, where clients upload files to us.
+#[derive(Clone)]
+pub struct UploadConfig {
+    /// Maximum total size of everything stored in the inbox.
+    pub quota_bytes: u64,
+    /// Maximum size of a single uploaded file.
+    pub max_file_bytes: u64,
+    /// Lowercase file extensions that may be uploaded; empty allows any.
+    pub allowed_extensions: Vec<String>,
+    /// Command run with the stored file path after each completed upload.
+    pub notify_command: Option<String>,
 }
 
 #[derive(Template)]
@@ -22,11 +157,100 @@ pub struct LoginForm {
     pub csrf_token: String,
 }
 
+/// Asks to confirm a login link, so following it alone doesn't use it up.
+#[derive(Template)]
+#[template(path = "login_link.html")]
+pub struct LoginLinkTemplate {
+    pub share: String,
+    /// The link's query, signature included, for the form to post back.
+    pub query: String,
+    pub csrf_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct LoginLinkForm {
+    pub csrf_token: String,
+}
+
+/// Query of `/login/link` besides the signature.
+#[derive(Deserialize)]
+pub struct LoginLinkQuery {
+    /// Name of the key the link logs in with; the share's first key if unset.
+    pub key: Option<String>,
+}
+
+/// Query of `/download-zip` and `/api/v1/archive`.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ArchiveQuery {
+    /// `zip` (the default), `tar` or `tar.zst`.
+    pub format: Option<String>,
+    /// One ZIP of a split share, counting from 1.
+    pub part: Option<usize>,
+}
+
+/// One ZIP of a share split into parts.
+pub struct ZipPartLink {
+    pub number: usize,
+    pub size_mb: u64,
+}
+
+/// A file on the list page, with the links the key allows.
+pub struct ListedFile {
+    pub name: String,
+    pub download: bool,
+    pub preview: bool,
+}
+
 #[derive(Template)]
 #[template(path = "list.html")]
 pub struct ListTemplate {
-    pub files: Vec<String>,
+    pub files: Vec<ListedFile>,
     pub greet: String,
+    pub can_download: bool,
+    pub can_download_zip: bool,
+    pub upload_enabled: bool,
+    /// The ZIP is AES encrypted and the only archive offered.
+    pub zip_encrypted: bool,
+    /// Empty unless the share is delivered as several ZIPs.
+    pub zip_parts: Vec<ZipPartLink>,
+    pub b3sums: bool,
+}
+
+#[derive(Template)]
+#[template(path = "upload.html")]
+pub struct UploadTemplate {
+    pub error: String,
+    pub uploaded: Vec<String>,
+    pub csrf_token: String,
+    pub allowed_extensions: Vec<String>,
+    pub max_file_mb: u64,
+}
+
+#[derive(Template)]
+#[template(path = "preparing.html")]
+pub struct PreparingTemplate {
+    /// "архів" or "частину N з M".
+    pub title: String,
+    pub percent: u8,
+    /// Query string of the archive being prepared, e.g. `?part=2`.
+    pub query: String,
+}
+
+/// Reply of `/download-zip/status`, polled by the preparing page.
+#[derive(Serialize)]
+pub struct ZipStatus {
+    /// One of `ready`, `queued`, `building` or `failed`.
+    pub state: &'static str,
+    pub percent: u8,
+    pub error: Option<&'static str>,
+}
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+pub struct AdminTemplate {
+    pub summaries: Vec<ActivitySummary>,
+    pub activity_enabled: bool,
 }
 
 #[derive(Template)]
@@ -35,3 +259,152 @@ pub struct ErrorTemplate {
     pub error_code: String,
     pub error_message: String,
 }
+
+/// Body of every `/api/v1` error.
+#[derive(Serialize, ToSchema)]
+pub struct ApiError {
+    pub status: u16,
+    /// Reason phrase of the status, e.g. `Not Found`.
+    pub error: &'static str,
+    pub message: String,
+}
+
+/// Reply of `/api/v1/share`.
+#[derive(Serialize, ToSchema)]
+pub struct ApiShare {
+    pub name: String,
+    pub greet: String,
+    /// Name of the key the request was made with.
+    pub key: String,
+    pub permissions: Vec<Permission>,
+    pub file_count: usize,
+    pub total_size: u64,
+    pub upload_enabled: bool,
+    /// `None` unless the key may download the archive.
+    pub archive: Option<ApiArchive>,
+}
+
+/// How the share can be downloaded as a whole.
+#[derive(Serialize, ToSchema)]
+pub struct ApiArchive {
+    pub encrypted: bool,
+    /// Values of the `format` query of the archive URL.
+    pub formats: Vec<&'static str>,
+    /// `None` when the share is split, download `parts` instead.
+    pub url: Option<String>,
+    pub parts: Vec<ApiArchivePart>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiArchivePart {
+    pub number: usize,
+    pub size: u64,
+    pub url: String,
+}
+
+/// Reply of `/api/v1/files`.
+#[derive(Serialize, ToSchema)]
+pub struct ApiFileList {
+    pub files: Vec<ApiFile>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ApiFile {
+    pub name: String,
+    pub size: u64,
+    /// RFC 3339 in UTC.
+    #[schema(format = DateTime)]
+    pub modified: String,
+    pub mime_type: String,
+    /// Pixel size, for images only.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Download of the original, unless the key may only preview.
+    pub url: Option<String>,
+    /// Web-size JPEG, for images the key may preview.
+    pub preview_url: Option<String>,
+}
+
+/// What a share's activity log says about it, for the admin.
+#[derive(Serialize, ToSchema)]
+pub struct ActivitySummary {
+    pub share: String,
+    pub logins: usize,
+    pub failed_logins: usize,
+    /// Files sent to the end.
+    pub file_downloads: usize,
+    /// Archives and archive parts sent to the end.
+    pub archive_downloads: usize,
+    /// Downloads the client broke off.
+    pub interrupted_downloads: usize,
+    /// Bytes that actually went out, interrupted downloads included.
+    pub bytes_sent: u64,
+    /// Every current file reached the client, one by one or in an archive.
+    pub downloaded_everything: bool,
+    /// RFC 3339 in UTC, `None` until the first event.
+    #[schema(format = DateTime)]
+    pub first_activity: Option<String>,
+    #[schema(format = DateTime)]
+    pub last_activity: Option<String>,
+}
+
+/// Reply of `/api/v1/admin/activity`.
+#[derive(Serialize, ToSchema)]
+pub struct ApiActivityList {
+    pub shares: Vec<ActivitySummary>,
+}
+
+/// Body of `POST /api/v1/selection`.
+#[derive(Deserialize, ToSchema)]
+pub struct SelectionRequest {
+    /// Names from the file list.
+    pub files: Vec<String>,
+    pub note: Option<String>,
+}
+
+/// The latest selection of a key, which replaces that key's earlier one.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ApiSelection {
+    pub files: Vec<String>,
+    pub note: Option<String>,
+    /// Name of the key that submitted it.
+    pub key: String,
+    /// RFC 3339 in UTC.
+    #[schema(format = DateTime)]
+    pub submitted: String,
+}
+
+/// Sent to the client by `photo4share mail ready`.
+#[derive(Template)]
+#[template(path = "email/gallery_ready.txt")]
+pub struct GalleryReadyEmail {
+    pub greet: String,
+    pub share: String,
+    /// One-time login link.
+    pub link: String,
+    pub link_expires: String,
+    pub expires: Option<NaiveDate>,
+}
+
+/// Sent to the client a few days before the share expires.
+#[derive(Template)]
+#[template(path = "email/expiry_reminder.txt")]
+pub struct ExpiryReminderEmail {
+    pub greet: String,
+    pub share: String,
+    pub expires: NaiveDate,
+    /// The gallery's address, if `public_url` is set.
+    pub url: Option<String>,
+}
+
+/// Sent to the photographer once the client has every file.
+#[derive(Template)]
+#[template(path = "email/downloaded_everything.txt")]
+pub struct DownloadedEverythingEmail {
+    pub share: String,
+    pub files: usize,
+    pub bytes_sent: u64,
+    /// Key of the download that completed the set.
+    pub key: Option<String>,
+    pub time: String,
+}
@@ -0,0 +1,244 @@
+use crate::config::Config;
+use crate::file_index::FileEntry;
+use crate::file_index::FileIndexes;
+use image::DynamicImage;
+use image::ImageDecoder;
+use image::ImageReader;
+use image::codecs::jpeg::JpegEncoder;
+use rand::Rng;
+use rand::rng;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
+
+/// Longest side of a preview, enough for a full screen on most displays.
+const PREVIEW_MAX_SIDE: u32 = 1600;
+const PREVIEW_QUALITY: u8 = 82;
+/// Formats previews are made of.
+const PREVIEW_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp", "gif"];
+/// Previews decoded at the same time, across all shares.
+const MAX_CONCURRENT_RENDERS: usize = 2;
+
+pub fn preview_dir(share_dir: &Path) -> PathBuf {
+    share_dir.join(".previews")
+}
+
+/// True for files a web-size preview can be made of.
+pub fn has_preview(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| PREVIEW_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Renders previews on request. Decoding a large photo takes a CPU core and
+/// a few hundred MB, so only a few run at once, and requests for the same
+/// preview wait for one decode.
+pub struct PreviewRenderer {
+    permits: Semaphore,
+    rendering: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Default for PreviewRenderer {
+    fn default() -> Self {
+        Self {
+            permits: Semaphore::new(MAX_CONCURRENT_RENDERS),
+            rendering: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl PreviewRenderer {
+    /// Preview of `entry` as a JPEG, rendered on first use. The name covers
+    /// the file's metadata, so a replaced file never gets its old preview.
+    pub async fn ensure(&self, share_dir: &Path, entry: &FileEntry) -> std::io::Result<PathBuf> {
+        let path = preview_dir(share_dir).join(preview_name(entry));
+        if fs::try_exists(&path).await? {
+            return Ok(path);
+        }
+
+        let lock = self
+            .rendering
+            .lock()
+            .unwrap()
+            .entry(path.clone())
+            .or_default()
+            .clone();
+        let rendered = async {
+            let _rendering = lock.lock().await;
+            // Whoever held the lock may have just rendered it
+            if fs::try_exists(&path).await? {
+                return Ok(());
+            }
+            let _permit = self
+                .permits
+                .acquire()
+                .await
+                .map_err(std::io::Error::other)?;
+            render(share_dir, entry, &path).await
+        }
+        .await;
+        self.rendering.lock().unwrap().remove(&path);
+        rendered.map(|()| path)
+    }
+}
+
+async fn render(share_dir: &Path, entry: &FileEntry, path: &Path) -> std::io::Result<()> {
+    let dir = preview_dir(share_dir);
+    fs::create_dir_all(&dir).await?;
+    let source = share_dir.join(&entry.name);
+    let temp = dir.join(format!(
+        "{}.{:016x}.tmp",
+        preview_name(entry),
+        rng().random::<u64>()
+    ));
+    let target = temp.clone();
+    let rendered = tokio::task::spawn_blocking(move || render_preview(&source, &target))
+        .await
+        .map_err(std::io::Error::other)?;
+    if let Err(e) = rendered {
+        let _ = fs::remove_file(&temp).await;
+        return Err(e);
+    }
+    fs::rename(&temp, path).await
+}
+
+/// Deletes the previews of files that changed or are gone. Returns the
+/// number removed.
+pub async fn prune_previews(share_dir: &Path, files: &[FileEntry]) -> std::io::Result<usize> {
+    let current: Vec<String> = files.iter().map(preview_name).collect();
+    let mut entries = match fs::read_dir(preview_dir(share_dir)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".jpg") && !current.contains(&name) {
+            fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Deletes the previews of changed or removed files in every share of
+/// `config`, now and then from the cache evictor.
+pub async fn prune_stale_previews(config: &Config, indexes: &FileIndexes) {
+    let dirs: HashSet<&Path> = config.shares.iter().map(|s| s.dir.as_path()).collect();
+    for dir in dirs {
+        let Ok(index) = indexes.get(dir).await else {
+            continue;
+        };
+        match prune_previews(dir, &index.files()).await {
+            Ok(0) => {}
+            Ok(removed) => debug!("Removed {} stale preview(s) of {:?}", removed, dir),
+            Err(e) => warn!("Cannot prune previews of {:?}: {}", dir, e),
+        }
+    }
+}
+
+fn preview_name(entry: &FileEntry) -> String {
+    let mtime = entry
+        .modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(entry.name.as_bytes());
+    hasher.update(&entry.size.to_le_bytes());
+    hasher.update(&mtime.to_le_bytes());
+    hasher.update(&entry.inode.to_le_bytes());
+    format!("{}.jpg", &hasher.finalize().to_hex()[..32])
+}
+
+fn render_preview(source: &Path, target: &Path) -> std::io::Result<()> {
+    let mut decoder = ImageReader::open(source)?
+        .with_guessed_format()?
+        .into_decoder()
+        .map_err(std::io::Error::other)?;
+    // Cameras store portrait shots sideways and rely on the EXIF orientation
+    let orientation = decoder.orientation().map_err(std::io::Error::other)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(std::io::Error::other)?;
+    image.apply_orientation(orientation);
+
+    if image.width() > PREVIEW_MAX_SIDE || image.height() > PREVIEW_MAX_SIDE {
+        image = image.thumbnail(PREVIEW_MAX_SIDE, PREVIEW_MAX_SIDE);
+    }
+
+    let writer = BufWriter::new(File::create_new(target)?);
+    JpegEncoder::new_with_quality(writer, PREVIEW_QUALITY)
+        .encode_image(&image.to_rgb8())
+        .map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    fn entry(dir: &Path, name: &str) -> FileEntry {
+        let meta = std::fs::metadata(dir.join(name)).unwrap();
+        FileEntry {
+            name: name.to_string(),
+            size: meta.len(),
+            modified: meta.modified().unwrap(),
+            inode: meta.ino(),
+            changed: meta.ctime() as i128 * 1_000_000_000 + meta.ctime_nsec() as i128,
+        }
+    }
+
+    fn previews_in(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(preview_dir(dir))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_render_and_stale_ones_are_pruned() {
+        let dir = std::env::temp_dir().join(format!("photo4share-previews-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        image::RgbImage::new(8, 8).save(dir.join("a.png")).unwrap();
+        let renderer = PreviewRenderer::default();
+
+        let first = entry(&dir, "a.png");
+        let requests = (0..8).map(|_| renderer.ensure(&dir, &first));
+        let paths = futures_util::future::join_all(requests).await;
+        let path = paths[0].as_ref().unwrap();
+        assert!(paths.iter().all(|p| p.as_ref().unwrap() == path));
+        // One preview, no temporary files of duplicate renders left over
+        assert_eq!(previews_in(&dir), [preview_name(&first)]);
+        assert!(renderer.rendering.lock().unwrap().is_empty());
+
+        image::RgbImage::new(16, 16)
+            .save(dir.join("a.png"))
+            .unwrap();
+        let second = entry(&dir, "a.png");
+        let new_path = renderer.ensure(&dir, &second).await.unwrap();
+        assert_ne!(&new_path, path);
+        assert_eq!(
+            prune_previews(&dir, std::slice::from_ref(&second))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(previews_in(&dir), [preview_name(&second)]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
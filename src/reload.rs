@@ -0,0 +1,152 @@
+use crate::config;
+use crate::config::Config;
+use crate::file_index::FileIndexes;
+use arc_swap::ArcSwap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+use tracing::{error, info, warn};
+
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Configuration shared with every request. Handlers `load()` a snapshot, so
+/// a reload never changes the config in the middle of a request.
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+/// Reloads the config file on SIGHUP and whenever it is modified.
+pub fn spawn_config_reloader(
+    path: Option<PathBuf>,
+    shared: SharedConfig,
+    indexes: Arc<FileIndexes>,
+) {
+    let Some(path) = path else {
+        info!("No config file in use, configuration reload disabled");
+        return;
+    };
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => Some(s),
+        Err(e) => {
+            warn!(
+                "Cannot listen for SIGHUP, config reload on signal disabled: {}",
+                e
+            );
+            None
+        }
+    };
+
+    tokio::spawn(async move {
+        let modified = |path: &PathBuf| -> Option<SystemTime> {
+            std::fs::metadata(path).ok()?.modified().ok()
+        };
+        let mut last_seen = modified(&path);
+        let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            let triggered_by_signal = tokio::select! {
+                _ = interval.tick() => false,
+                Some(_) = async {
+                    match hangup.as_mut() {
+                        Some(s) => s.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => true,
+            };
+
+            let current = modified(&path);
+            if !triggered_by_signal && current == last_seen {
+                continue;
+            }
+            last_seen = current;
+
+            reload(&path, &shared, &indexes).await;
+        }
+    });
+}
+
+/// Validates the file and swaps it in. On failure the running config stays.
+pub async fn reload(path: &Path, shared: &SharedConfig, indexes: &FileIndexes) {
+    let new = match config::load(Some(path)) {
+        Ok(c) => c,
+        Err(errors) => {
+            error!(
+                "Config reload rejected, keeping the current one. {}",
+                errors
+            );
+            return;
+        }
+    };
+
+    let old = shared.load();
+    if new.listen != old.listen
+        || new.unix_socket_mode != old.unix_socket_mode
+        || new.tls != old.tls
+        || new.shutdown_timeout != old.shutdown_timeout
+        || new.state_dir != old.state_dir
+    {
+        warn!(
+            "Server settings changed in {:?}, they take effect after a restart",
+            path
+        );
+    }
+
+    info!(
+        "Reloaded configuration from {:?}: {} share(s)",
+        path,
+        new.shares.len()
+    );
+    let new = Arc::new(new);
+    shared.store(new.clone());
+    let dirs: HashSet<&Path> = new.shares.iter().map(|s| s.dir.as_path()).collect();
+    indexes.retain(&dirs).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_toml(dir: &Path, greet: &str) -> String {
+        format!(
+            "[[shares]]\nname = \"s1\"\ndir = \"{}\"\nkey = \"a key long enough\"\ngreet = \"{}\"\n",
+            dir.display(),
+            greet
+        )
+    }
+
+    #[tokio::test]
+    async fn invalid_files_keep_the_running_config() {
+        let dir = std::env::temp_dir().join(format!("photo4share-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("photo4share.toml");
+        std::fs::write(&path, config_toml(&dir, "Hello")).unwrap();
+        let shared: SharedConfig =
+            Arc::new(ArcSwap::from_pointee(config::load(Some(&path)).unwrap()));
+        let indexes = FileIndexes::default();
+        let greet = |shared: &SharedConfig| shared.load().shares[0].greet.clone();
+
+        for broken in [
+            "[[shares]\n".to_string(),
+            config_toml(&dir.join("missing"), "Broken"),
+            config_toml(&dir, "Broken").replace("a key long enough", "short"),
+        ] {
+            std::fs::write(&path, broken).unwrap();
+            reload(&path, &shared, &indexes).await;
+            assert_eq!(greet(&shared), "Hello");
+        }
+
+        std::fs::write(&path, config_toml(&dir, "Welcome back")).unwrap();
+        reload(&path, &shared, &indexes).await;
+        assert_eq!(greet(&shared), "Welcome back");
+
+        std::fs::remove_file(&path).unwrap();
+        reload(&path, &shared, &indexes).await;
+        assert_eq!(greet(&shared), "Welcome back");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
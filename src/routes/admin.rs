@@ -0,0 +1,36 @@
+use crate::activity::summarize_shares;
+use crate::auth::is_admin;
+use crate::file_utils::error_response;
+use crate::models::AdminTemplate;
+use crate::models::AppState;
+use askama::Template;
+use axum::extract::State;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::response::IntoResponse;
+use axum::response::Redirect;
+use axum::response::Response;
+use tower_cookies::Cookies;
+
+/// Activity of every share at a glance, for the photographer. The admin
+/// key is entered in the same login form as share keys.
+pub async fn admin_page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+) -> Response {
+    if !is_admin(&headers, &cookies, &state) {
+        return Redirect::to("/login").into_response();
+    }
+
+    let config = state.config.load();
+    let template = AdminTemplate {
+        summaries: summarize_shares(&state, &config.shares).await,
+        activity_enabled: config.activity.enabled,
+    };
+    match template.render() {
+        Ok(html) => Html(html).into_response(),
+        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Template error"),
+    }
+}
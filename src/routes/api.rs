@@ -0,0 +1,683 @@
+use super::files::archive_format;
+use super::files::check_part;
+use super::files::check_unencrypted;
+use super::files::serve_cached_zip;
+use super::files::serve_preview;
+use super::files::serve_share_file;
+use crate::activity::ClientInfo;
+use crate::activity::EventKind;
+use crate::activity::archive_event;
+use crate::activity::file_event;
+use crate::activity::record;
+use crate::activity::summarize_shares;
+use crate::activity::track_download;
+use crate::auth::KEY_COOKIE;
+use crate::auth::Principal;
+use crate::auth::SESSION_COOKIE;
+use crate::auth::authenticated_api_share;
+use crate::auth::is_admin;
+use crate::dimensions::Dimensions;
+use crate::file_index::FileEntry;
+use crate::file_index::ShareIndex;
+use crate::file_utils::URL_COMPONENT;
+use crate::models::ActivitySummary;
+use crate::models::ApiActivityList;
+use crate::models::ApiArchive;
+use crate::models::ApiArchivePart;
+use crate::models::ApiError;
+use crate::models::ApiFile;
+use crate::models::ApiFileList;
+use crate::models::ApiSelection;
+use crate::models::ApiShare;
+use crate::models::AppState;
+use crate::models::ArchiveQuery;
+use crate::models::Permission;
+use crate::models::SelectionRequest;
+use crate::previews::has_preview;
+use crate::tar_utils::serve_tar;
+use axum::Json;
+use axum::Router;
+use axum::extract::Path;
+use axum::extract::Query;
+use axum::extract::State;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::rejection::PathRejection;
+use axum::extract::rejection::QueryRejection;
+use axum::http::HeaderMap;
+use axum::http::StatusCode;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::routing::any;
+use axum::routing::get;
+use chrono::DateTime;
+use chrono::SecondsFormat;
+use chrono::Utc;
+use percent_encoding::utf8_percent_encode;
+use rand::Rng;
+use rand::rng;
+use std::sync::Arc;
+use tower_cookies::Cookies;
+use tracing::{info, warn};
+use utoipa::Modify;
+use utoipa::OpenApi;
+use utoipa::PartialSchema;
+use utoipa::ToSchema;
+use utoipa::openapi::KnownFormat;
+use utoipa::openapi::ObjectBuilder;
+use utoipa::openapi::RefOr;
+use utoipa::openapi::Schema;
+use utoipa::openapi::SchemaFormat;
+use utoipa::openapi::Type;
+use utoipa::openapi::security::ApiKey;
+use utoipa::openapi::security::ApiKeyValue;
+use utoipa::openapi::security::HttpAuthScheme;
+use utoipa::openapi::security::HttpBuilder;
+use utoipa::openapi::security::SecurityScheme;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+/// Where the OpenAPI document of the API is served, without authentication.
+pub const OPENAPI_PATH: &str = "/api/v1/openapi.json";
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "photo4share",
+        description = "Listing and downloading the files of a share.",
+        license(name = "MIT", identifier = "MIT"),
+    ),
+    modifiers(&ShareKeyAuth),
+    security(("bearer" = []), ("session" = []), ("login_link" = [])),
+)]
+struct ApiDoc;
+
+/// The share key as a bearer token, or the cookie set by the login form or
+/// a login link.
+struct ShareKeyAuth;
+
+impl Modify for ShareKeyAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(KEY_COOKIE))),
+        );
+        components.add_security_scheme(
+            "login_link",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new(SESSION_COOKIE))),
+        );
+    }
+}
+
+/// The `/api/v1` routes together with their OpenAPI document. Both come from
+/// the same handler annotations, and the document is served at
+/// `OPENAPI_PATH`.
+pub fn api_router() -> (Router<AppState>, utoipa::openapi::OpenApi) {
+    let v1 = OpenApiRouter::new()
+        .routes(routes!(api_share_info))
+        .routes(routes!(api_list_files))
+        .routes(routes!(api_file_info))
+        .routes(routes!(api_download_file))
+        .routes(routes!(api_preview_file))
+        .routes(routes!(api_download_archive))
+        .routes(routes!(api_submit_selection))
+        .routes(routes!(api_admin_activity))
+        .routes(routes!(api_admin_share_activity));
+    let (router, openapi) = OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .nest("/api/v1", v1)
+        .split_for_parts();
+
+    let spec = openapi
+        .to_pretty_json()
+        .expect("OpenAPI document is serializable");
+    let router = router
+        .route(
+            OPENAPI_PATH,
+            get(move || async move { ([(header::CONTENT_TYPE, "application/json")], spec) }),
+        )
+        .route("/api/v1/{*path}", any(api_not_found))
+        .method_not_allowed_fallback(api_method_not_allowed);
+    (router, openapi)
+}
+
+/// Raw bytes of a file or archive in the OpenAPI document.
+struct Binary;
+
+impl PartialSchema for Binary {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .format(Some(SchemaFormat::KnownFormat(KnownFormat::Binary)))
+            .into()
+    }
+}
+
+impl ToSchema for Binary {}
+
+/// Error reply of the API, JSON in place of the HTML `error_response`.
+pub fn api_error(status: StatusCode, message: &str) -> Response {
+    let body = ApiError {
+        status: status.as_u16(),
+        error: status.canonical_reason().unwrap_or("Error"),
+        message: message.to_string(),
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Key and share index of an API request, or the error reply if the key
+/// lacks `permission`.
+async fn api_context(
+    state: &AppState,
+    headers: &HeaderMap,
+    cookies: &Cookies,
+    permission: Option<Permission>,
+) -> Result<(Principal, Arc<ShareIndex>), Response> {
+    let Some(principal) = authenticated_api_share(headers, cookies, state) else {
+        return Err(api_error(
+            StatusCode::UNAUTHORIZED,
+            "Send the share key as a bearer token or log in first",
+        ));
+    };
+    if let Some(permission) = permission {
+        principal
+            .check(permission)
+            .map_err(|(status, message)| api_error(status, message))?;
+    }
+    match state.indexes.get(&principal.share.dir).await {
+        Ok(index) => Ok((principal, index)),
+        Err(_) => Err(api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to read dir",
+        )),
+    }
+}
+
+/// Metadata of the share and how to download it as a whole.
+#[utoipa::path(
+    get,
+    path = "/share",
+    responses(
+        (status = 200, description = "The share", body = ApiShare),
+        (status = 401, description = "Missing or wrong share key", body = ApiError),
+        (status = 500, description = "Server error", body = ApiError),
+    ),
+)]
+pub async fn api_share_info(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+) -> Response {
+    let (principal, index) = match api_context(&state, &headers, &cookies, None).await {
+        Ok(context) => context,
+        Err(response) => return response,
+    };
+    let share = &principal.share;
+
+    let files = index.files();
+    let parts = share.zip.parts(&files);
+    let archive = principal.can(Permission::DownloadZip).then(|| ApiArchive {
+        encrypted: share.zip.password.is_some(),
+        formats: if share.zip.password.is_some() {
+            vec!["zip"]
+        } else {
+            vec!["zip", "tar", "tar.zst"]
+        },
+        url: (parts.len() <= 1).then(|| "/api/v1/archive".to_string()),
+        parts: if parts.len() > 1 {
+            parts
+                .into_iter()
+                .enumerate()
+                .map(|(i, range)| ApiArchivePart {
+                    number: i + 1,
+                    size: files[range].iter().map(|f| f.size).sum(),
+                    url: format!("/api/v1/archive?part={}", i + 1),
+                })
+                .collect()
+        } else {
+            Vec::new()
+        },
+    });
+
+    Json(ApiShare {
+        name: share.name.clone(),
+        greet: share.greet.clone(),
+        key: principal.key().name.clone(),
+        permissions: principal.key().permissions.clone(),
+        file_count: files.len(),
+        total_size: files.iter().map(|f| f.size).sum(),
+        upload_enabled: share.upload.is_some() && principal.can(Permission::Upload),
+        archive,
+    })
+    .into_response()
+}
+
+/// Every file of the share.
+#[utoipa::path(
+    get,
+    path = "/files",
+    responses(
+        (status = 200, description = "Files sorted by name", body = ApiFileList),
+        (status = 401, description = "Missing or wrong share key", body = ApiError),
+        (status = 403, description = "The key may not do this", body = ApiError),
+        (status = 500, description = "Server error", body = ApiError),
+    ),
+)]
+pub async fn api_list_files(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+) -> Response {
+    let (principal, index) =
+        match api_context(&state, &headers, &cookies, Some(Permission::List)).await {
+            Ok(context) => context,
+            Err(response) => return response,
+        };
+
+    let files = index.files();
+    let dimensions = index.dimensions(&files).await;
+    let files = files
+        .iter()
+        .zip(dimensions)
+        .map(|(entry, dimensions)| api_file(&principal, entry, dimensions))
+        .collect();
+    Json(ApiFileList { files }).into_response()
+}
+
+/// One file of the share.
+#[utoipa::path(
+    get,
+    path = "/files/{filename}",
+    params(("filename" = String, Path)),
+    responses(
+        (status = 200, description = "The file", body = ApiFile),
+        (status = 400, description = "Invalid request", body = ApiError),
+        (status = 401, description = "Missing or wrong share key", body = ApiError),
+        (status = 403, description = "The key may not do this", body = ApiError),
+        (status = 404, description = "No such file", body = ApiError),
+        (status = 500, description = "Server error", body = ApiError),
+    ),
+)]
+pub async fn api_file_info(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+    filename: Result<Path<String>, PathRejection>,
+) -> Response {
+    let (principal, index) =
+        match api_context(&state, &headers, &cookies, Some(Permission::List)).await {
+            Ok(context) => context,
+            Err(response) => return response,
+        };
+    let Ok(Path(filename)) = filename else {
+        return api_error(StatusCode::BAD_REQUEST, "Invalid path");
+    };
+
+    let files = index.files();
+    let Ok(i) = files.binary_search_by(|f| f.name.as_str().cmp(&filename)) else {
+        return api_error(StatusCode::NOT_FOUND, "No such file");
+    };
+    let entry = std::slice::from_ref(&files[i]);
+    let dimensions = index.dimensions(entry).await;
+    Json(api_file(&principal, &entry[0], dimensions[0])).into_response()
+}
+
+fn api_file(principal: &Principal, entry: &FileEntry, dimensions: Option<Dimensions>) -> ApiFile {
+    let path = format!(
+        "/api/v1/files/{}",
+        utf8_percent_encode(&entry.name, URL_COMPONENT)
+    );
+    ApiFile {
+        name: entry.name.clone(),
+        size: entry.size,
+        modified: DateTime::<Utc>::from(entry.modified).to_rfc3339_opts(SecondsFormat::Secs, true),
+        mime_type: mime_guess::from_path(&entry.name)
+            .first_or_octet_stream()
+            .to_string(),
+        width: dimensions.map(|d| d.width),
+        height: dimensions.map(|d| d.height),
+        url: (principal.can(Permission::Download) && principal.share.zip.password.is_none())
+            .then(|| format!("{}/download", path)),
+        preview_url: (principal.can(Permission::Preview) && has_preview(&entry.name))
+            .then(|| format!("{}/preview", path)),
+    }
+}
+
+/// Contents of one file of the share.
+#[utoipa::path(
+    get,
+    path = "/files/{filename}/download",
+    params(("filename" = String, Path)),
+    responses(
+        (status = 200, description = "Contents of the file", content_type = "application/octet-stream", body = Binary),
+        (status = 400, description = "Invalid request", body = ApiError),
+        (status = 401, description = "Missing or wrong share key", body = ApiError),
+        (status = 403, description = "The key may not do this, or the share is only available as an encrypted ZIP", body = ApiError),
+        (status = 404, description = "No such file", body = ApiError),
+        (status = 500, description = "Server error", body = ApiError),
+    ),
+)]
+pub async fn api_download_file(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+    client: ClientInfo,
+    filename: Result<Path<String>, PathRejection>,
+) -> Response {
+    let (principal, index) =
+        match api_context(&state, &headers, &cookies, Some(Permission::Download)).await {
+            Ok(context) => context,
+            Err(response) => return response,
+        };
+    let Ok(Path(filename)) = filename else {
+        return api_error(StatusCode::BAD_REQUEST, "Invalid path");
+    };
+    info!("API file download requested: {}", filename);
+    if let Err((status, message)) = check_unencrypted(&principal.share) {
+        return api_error(status, message);
+    }
+    let response = serve_share_file(&index, &filename)
+        .await
+        .unwrap_or_else(|(status, message)| api_error(status, message));
+    track_download(
+        &state,
+        &principal.share,
+        Some(&principal.key().name),
+        &client,
+        file_event(&index, &filename),
+        response,
+    )
+}
+
+/// Web-size JPEG of an image of the share, rendered on first request.
+#[utoipa::path(
+    get,
+    path = "/files/{filename}/preview",
+    params(("filename" = String, Path)),
+    responses(
+        (status = 200, description = "The preview", content_type = "image/jpeg", body = Binary),
+        (status = 400, description = "Invalid request", body = ApiError),
+        (status = 401, description = "Missing or wrong share key", body = ApiError),
+        (status = 403, description = "The key may not do this", body = ApiError),
+        (status = 404, description = "No such image", body = ApiError),
+        (status = 500, description = "Server error", body = ApiError),
+    ),
+)]
+pub async fn api_preview_file(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+    filename: Result<Path<String>, PathRejection>,
+) -> Response {
+    let (principal, _) =
+        match api_context(&state, &headers, &cookies, Some(Permission::Preview)).await {
+            Ok(context) => context,
+            Err(response) => return response,
+        };
+    let Ok(Path(filename)) = filename else {
+        return api_error(StatusCode::BAD_REQUEST, "Invalid path");
+    };
+    serve_preview(&state, &principal.share, &filename)
+        .await
+        .unwrap_or_else(|(status, message)| api_error(status, message))
+}
+
+/// The share's ZIP or tarball. Unlike the page, a ZIP still being built is
+/// waited for rather than answered with a progress page.
+#[utoipa::path(
+    get,
+    path = "/archive",
+    params(ArchiveQuery),
+    responses(
+        (status = 200, description = "The archive", content(
+            (Binary = "application/zip"),
+            (Binary = "application/x-tar"),
+            (Binary = "application/zstd"),
+        )),
+        (status = 400, description = "Invalid request", body = ApiError),
+        (status = 401, description = "Missing or wrong share key", body = ApiError),
+        (status = 403, description = "Format not offered for this share, or the key may not do this", body = ApiError),
+        (status = 404, description = "No such file or part", body = ApiError),
+        (status = 500, description = "Server error", body = ApiError),
+    ),
+)]
+pub async fn api_download_archive(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+    client: ClientInfo,
+    query: Result<Query<ArchiveQuery>, QueryRejection>,
+) -> Response {
+    let (principal, index) =
+        match api_context(&state, &headers, &cookies, Some(Permission::DownloadZip)).await {
+            Ok(context) => context,
+            Err(response) => return response,
+        };
+    let share = &principal.share;
+    let Ok(Query(query)) = query else {
+        return api_error(StatusCode::BAD_REQUEST, "Invalid query");
+    };
+    let kind = archive_event(&index, share, query.format.as_deref(), query.part).await;
+    let track = |response| {
+        track_download(
+            &state,
+            share,
+            Some(&principal.key().name),
+            &client,
+            kind,
+            response,
+        )
+    };
+
+    let tar_format = match archive_format(share, query.format.as_deref()) {
+        Ok(format) => format,
+        Err((status, message)) => return api_error(status, message),
+    };
+    if let Some(format) = tar_format {
+        info!("Streaming {} of share '{}'", format.extension(), share.name);
+        return track(serve_tar(share.dir.clone(), index.files(), format));
+    }
+
+    let part = match check_part(&index, &share.zip, query.part) {
+        Ok(part) => part,
+        Err(message) => return api_error(StatusCode::NOT_FOUND, message),
+    };
+    match state.zip_builder.wait(&index, &share.zip, part).await {
+        Ok(archive) => track(
+            serve_cached_zip(archive, &index, &share.zip, part)
+                .await
+                .unwrap_or_else(|(status, message)| api_error(status, message)),
+        ),
+        Err(message) => api_error(StatusCode::INTERNAL_SERVER_ERROR, message),
+    }
+}
+
+/// The clients' picks of files, stored hidden in the share as
+/// `<key name>.json`, each replacing the same key's earlier one. Key names
+/// are letters, digits, '-' and '_' only, so they are safe as file names.
+const SELECTIONS_DIR: &str = ".selections";
+/// Room for a few sentences to the photographer.
+const MAX_NOTE_CHARS: usize = 2000;
+
+/// Submits the client's selection of files, e.g. for retouching or prints.
+/// Each key keeps one selection, replaced by its next submission.
+#[utoipa::path(
+    post,
+    path = "/selection",
+    request_body = SelectionRequest,
+    responses(
+        (status = 200, description = "The stored selection", body = ApiSelection),
+        (status = 400, description = "Invalid selection", body = ApiError),
+        (status = 401, description = "Missing or wrong share key", body = ApiError),
+        (status = 403, description = "The key may not do this", body = ApiError),
+        (status = 500, description = "Server error", body = ApiError),
+    ),
+)]
+pub async fn api_submit_selection(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+    client: ClientInfo,
+    body: Result<Json<SelectionRequest>, JsonRejection>,
+) -> Response {
+    let (principal, index) =
+        match api_context(&state, &headers, &cookies, Some(Permission::Select)).await {
+            Ok(context) => context,
+            Err(response) => return response,
+        };
+    let Ok(Json(request)) = body else {
+        return api_error(StatusCode::BAD_REQUEST, "Send the selection as JSON");
+    };
+
+    let files = index.files();
+    let mut selected = Vec::with_capacity(request.files.len());
+    for name in request.files {
+        if files
+            .binary_search_by(|f| f.name.as_str().cmp(&name))
+            .is_err()
+        {
+            return api_error(StatusCode::BAD_REQUEST, &format!("No such file: {}", name));
+        }
+        if !selected.contains(&name) {
+            selected.push(name);
+        }
+    }
+    if selected.is_empty() {
+        return api_error(StatusCode::BAD_REQUEST, "Select at least one file");
+    }
+    let note = request.note.filter(|note| !note.trim().is_empty());
+    if note
+        .as_ref()
+        .is_some_and(|note| note.chars().count() > MAX_NOTE_CHARS)
+    {
+        return api_error(StatusCode::BAD_REQUEST, "The note is too long");
+    }
+
+    let share = &principal.share;
+    let selection = ApiSelection {
+        files: selected,
+        note,
+        key: principal.key().name.clone(),
+        submitted: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    };
+    let stored = async {
+        let json = serde_json::to_vec_pretty(&selection).map_err(std::io::Error::other)?;
+        let dir = share.dir.join(SELECTIONS_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        // Unique, so two submissions of one key don't write the same file
+        let temp = dir.join(format!(
+            ".{}.{:016x}.tmp",
+            selection.key,
+            rng().random::<u64>()
+        ));
+        let written = match tokio::fs::write(&temp, json).await {
+            Ok(()) => tokio::fs::rename(&temp, dir.join(format!("{}.json", selection.key))).await,
+            Err(e) => Err(e),
+        };
+        if written.is_err() {
+            let _ = tokio::fs::remove_file(&temp).await;
+        }
+        written
+    }
+    .await;
+    if let Err(e) = stored {
+        warn!(
+            "Cannot store the selection of share '{}': {}",
+            share.name, e
+        );
+        return api_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to store the selection",
+        );
+    }
+
+    info!(
+        "Selection of {} file(s) submitted for share '{}'",
+        selection.files.len(),
+        share.name
+    );
+    let kind = EventKind::SelectionSubmitted {
+        files: selection.files.clone(),
+        note: selection.note.clone(),
+    };
+    record(&state, share, Some(&selection.key), &client, kind).await;
+    Json(selection).into_response()
+}
+
+/// Activity summaries of every share. Needs the admin key instead of a
+/// share key.
+#[utoipa::path(
+    get,
+    path = "/admin/activity",
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "Summaries in configured order", body = ApiActivityList),
+        (status = 401, description = "Missing or wrong admin key", body = ApiError),
+    ),
+)]
+pub async fn api_admin_activity(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+) -> Response {
+    if !is_admin(&headers, &cookies, &state) {
+        return api_error(
+            StatusCode::UNAUTHORIZED,
+            "Send the admin key as a bearer token",
+        );
+    }
+    let config = state.config.load();
+    let shares = summarize_shares(&state, &config.shares).await;
+    Json(ApiActivityList { shares }).into_response()
+}
+
+/// Activity summary of one share. Needs the admin key.
+#[utoipa::path(
+    get,
+    path = "/admin/shares/{share}/activity",
+    params(("share" = String, Path)),
+    security(("bearer" = [])),
+    responses(
+        (status = 200, description = "The summary", body = ActivitySummary),
+        (status = 400, description = "Invalid request", body = ApiError),
+        (status = 401, description = "Missing or wrong admin key", body = ApiError),
+        (status = 404, description = "No such share", body = ApiError),
+    ),
+)]
+pub async fn api_admin_share_activity(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    cookies: Cookies,
+    name: Result<Path<String>, PathRejection>,
+) -> Response {
+    if !is_admin(&headers, &cookies, &state) {
+        return api_error(
+            StatusCode::UNAUTHORIZED,
+            "Send the admin key as a bearer token",
+        );
+    }
+    let Ok(Path(name)) = name else {
+        return api_error(StatusCode::BAD_REQUEST, "Invalid path");
+    };
+    let config = state.config.load();
+    let Some(share) = config.shares.iter().find(|s| s.name == name) else {
+        return api_error(StatusCode::NOT_FOUND, "No such share");
+    };
+    let mut summaries = summarize_shares(&state, std::slice::from_ref(share)).await;
+    Json(summaries.remove(0)).into_response()
+}
+
+pub async fn api_not_found() -> Response {
+    api_error(StatusCode::NOT_FOUND, "No such API endpoint")
+}
+
+pub async fn api_method_not_allowed() -> Response {
+    api_error(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "Method not allowed for this endpoint",
+    )
+}
@@ -1,10 +1,31 @@
-use crate::auth::verify_cookie_key;
+use crate::activity::ClientInfo;
+use crate::activity::EventKind;
+use crate::activity::record;
+use crate::activity::record_unattributed;
+use crate::auth::ADMIN_COOKIE;
+use crate::auth::KEY_COOKIE;
+use crate::auth::Principal;
+use crate::auth::SESSION_COOKIE;
+use crate::auth::authenticated_share;
+use crate::auth::find_share_by_key;
+use crate::auth::hash_key;
+use crate::config::Config;
 use crate::file_utils::error_response;
 use crate::models::AppState;
 use crate::models::LoginForm;
+use crate::models::LoginLinkForm;
+use crate::models::LoginLinkQuery;
+use crate::models::LoginLinkTemplate;
 use crate::models::LoginTemplate;
+use crate::signed_links::LinkQuery;
+use crate::signed_links::LinkTarget;
+use crate::signed_links::SESSION_TTL;
+use crate::signed_links::mint_session;
+use crate::signed_links::verify_link;
 use askama::Template;
 use axum::extract::Form;
+use axum::extract::Query;
+use axum::extract::RawQuery;
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::response::Html;
@@ -17,24 +38,20 @@ use rand::Rng;
 use rand::rng;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
+use subtle::ConstantTimeEq;
 use tower_cookies::Cookie as TowerCookie;
 use tower_cookies::Cookies;
+use tracing::info;
+use tracing::warn;
 
 pub async fn show_login_form(State(state): State<AppState>, cookies: Cookies) -> impl IntoResponse {
-    if verify_cookie_key(&cookies, &state.share_key) {
+    if authenticated_share(&cookies, &state).is_some() {
         return Redirect::to("/").into_response();
     }
 
-    let token = generate_csrf_token();
-    let mut csrf_cookie = TowerCookie::new("csrf_token", token.clone());
-    csrf_cookie.set_http_only(true);
-    csrf_cookie.set_secure(true);
-    csrf_cookie.set_same_site(tower_cookies::cookie::SameSite::Strict);
-    cookies.add(csrf_cookie);
-
     let template = LoginTemplate {
         error: "".to_string(),
-        csrf_token: token,
+        csrf_token: issue_csrf_token(&cookies),
     };
     match template.render() {
         Ok(html) => Html(html).into_response(),
@@ -59,65 +76,267 @@ fn generate_csrf_token() -> String {
     URL_SAFE.encode(combined)
 }
 
+/// Generates a fresh CSRF token and stores it in the `csrf_token` cookie.
+pub fn issue_csrf_token(cookies: &Cookies) -> String {
+    let token = generate_csrf_token();
+    let mut csrf_cookie = TowerCookie::new("csrf_token", token.clone());
+    csrf_cookie.set_http_only(true);
+    csrf_cookie.set_secure(true);
+    csrf_cookie.set_same_site(tower_cookies::cookie::SameSite::Strict);
+    cookies.add(csrf_cookie);
+    token
+}
+
+/// Checks a submitted CSRF token against the one stored in the cookie.
+pub fn verify_csrf_token(cookies: &Cookies, provided: &str) -> bool {
+    match cookies.get("csrf_token") {
+        Some(stored) => crate::auth::verify_user_sent_key(provided, stored.value()),
+        None => false,
+    }
+}
+
 pub async fn process_login(
     State(state): State<AppState>,
     cookies: Cookies,
+    client: ClientInfo,
     Form(form): Form<LoginForm>,
 ) -> Response {
-    if verify_cookie_key(&cookies, &state.share_key) {
+    if authenticated_share(&cookies, &state).is_some() {
         return Redirect::to("/").into_response();
     }
 
-    let stored_token = cookies.get("csrf_token").map(|c| c.value().to_string());
-    match stored_token {
-        Some(token) if token == form.csrf_token => {
-            // CSRF token is valid, proceed with login
-            if crate::auth::verify_user_sent_key(&form.key, &state.share_key) {
-                // Clear CSRF token after successful verification
-                cookies.remove(TowerCookie::new("csrf_token", ""));
-
-                let mut cookie = TowerCookie::new("share_key", form.key);
-                cookie.set_http_only(true);
-                cookie.set_secure(true);
-                cookie.set_same_site(tower_cookies::cookie::SameSite::Strict);
-                cookies.add(cookie);
-
-                Redirect::to("/").into_response()
-            } else {
-                let new_token = generate_csrf_token();
-                let mut csrf_cookie = TowerCookie::new("csrf_token", new_token.clone());
-                csrf_cookie.set_http_only(true);
-                csrf_cookie.set_secure(true);
-                csrf_cookie.set_same_site(tower_cookies::cookie::SameSite::Strict);
-                cookies.add(csrf_cookie);
-
-                let template = LoginTemplate {
-                    error: "Хибний ключ доступу. Впевніться що скопіювали його повністю без жодних додаткових символів та пробілів".to_string(),
-                    csrf_token: new_token,
-                };
-                match template.render() {
-                    Ok(html) => Html(html).into_response(),
-                    Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Template error"),
-                }
-            }
+    if verify_csrf_token(&cookies, &form.csrf_token) {
+        // CSRF token is valid, proceed with login
+        let config = state.config.load();
+        if let Some(admin_hash) = config.admin_key_hash
+            && bool::from(hash_key(&form.key).ct_eq(&admin_hash))
+        {
+            info!("Admin login from {:?}", client.ip);
+            cookies.remove(TowerCookie::new("csrf_token", ""));
+            let mut cookie = TowerCookie::new(ADMIN_COOKIE, form.key);
+            cookie.set_http_only(true);
+            cookie.set_secure(true);
+            cookie.set_same_site(tower_cookies::cookie::SameSite::Strict);
+            cookies.add(cookie);
+            return Redirect::to("/admin").into_response();
         }
-        _ => {
-            // CSRF token is invalid
-            let new_token = generate_csrf_token();
-            let mut csrf_cookie = TowerCookie::new("csrf_token", new_token.clone());
-            csrf_cookie.set_http_only(true);
-            csrf_cookie.set_secure(true);
-            csrf_cookie.set_same_site(tower_cookies::cookie::SameSite::Strict);
-            cookies.add(csrf_cookie);
 
+        if let Some(principal) = find_share_by_key(&config.shares, &form.key) {
+            info!(
+                "Successful login to share '{}' with key '{}' ({})",
+                principal.share.name,
+                principal.key().name,
+                principal.key().role
+            );
+            record(
+                &state,
+                &principal.share,
+                Some(&principal.key().name),
+                &client,
+                EventKind::Login {
+                    via: "form".to_string(),
+                },
+            )
+            .await;
+
+            // Clear CSRF token after successful verification
+            cookies.remove(TowerCookie::new("csrf_token", ""));
+
+            let mut cookie = TowerCookie::new(KEY_COOKIE, form.key);
+            cookie.set_http_only(true);
+            cookie.set_secure(true);
+            cookie.set_same_site(tower_cookies::cookie::SameSite::Strict);
+            cookies.add(cookie);
+
+            Redirect::to("/").into_response()
+        } else {
+            // A wrong key doesn't say whose share it was meant for
+            warn!("Failed login from {:?}", client.ip);
+            record_unattributed(
+                &state,
+                &client,
+                EventKind::LoginFailed {
+                    reason: "wrong key".to_string(),
+                },
+            )
+            .await;
             let template = LoginTemplate {
-                error: "Помилка безпеки: недійсний маркер CSRF. Спробуйте знову.".to_string(),
-                csrf_token: new_token,
+                error: "Хибний ключ доступу. Впевніться що скопіювали його повністю без жодних додаткових символів та пробілів".to_string(),
+                csrf_token: issue_csrf_token(&cookies),
             };
             match template.render() {
                 Ok(html) => Html(html).into_response(),
                 Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Template error"),
             }
         }
+    } else {
+        // CSRF token is invalid
+        let template = LoginTemplate {
+            error: "Помилка безпеки: недійсний маркер CSRF. Спробуйте знову.".to_string(),
+            csrf_token: issue_csrf_token(&cookies),
+        };
+        match template.render() {
+            Ok(html) => Html(html).into_response(),
+            Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Template error"),
+        }
+    }
+}
+
+/// Share and key a login link is valid for, or `None` if it isn't.
+fn verify_login_link(
+    config: &Config,
+    link: &LinkQuery,
+    login: &LoginLinkQuery,
+) -> Option<Principal> {
+    let secret = config.link_secret.as_deref()?;
+    let target = LinkTarget::Login {
+        key: login.key.clone(),
+    };
+    let name = verify_link(secret, link, &target).ok()?;
+    let share = config
+        .shares
+        .iter()
+        .find(|s| s.name == name && !s.is_expired())?;
+    // A link minted without a key logs in with the share's first one
+    let key = match &login.key {
+        Some(name) => share.keys.iter().position(|k| &k.name == name)?,
+        None => 0,
+    };
+    Some(Principal::new(share.clone(), key))
+}
+
+/// Shows the page confirming a one-time link from `photo4share login-link`.
+/// Mail scanners and link previews fetch the link too, so only the form on
+/// the page uses it up.
+pub async fn login_with_link(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Query(link): Query<LinkQuery>,
+    Query(login): Query<LoginLinkQuery>,
+    RawQuery(query): RawQuery,
+) -> Response {
+    if authenticated_share(&cookies, &state).is_some() {
+        return Redirect::to("/").into_response();
+    }
+
+    let config = state.config.load();
+    let (Some(principal), Some(nonce)) = (verify_login_link(&config, &link, &login), &link.nonce)
+    else {
+        return login_link_failed(&cookies);
+    };
+    if !matches!(state.used_links.is_used(nonce).await, Ok(false)) {
+        return login_link_failed(&cookies);
+    }
+
+    let template = LoginLinkTemplate {
+        share: principal.share.name.clone(),
+        query: query.unwrap_or_default(),
+        csrf_token: issue_csrf_token(&cookies),
+    };
+    match template.render() {
+        Ok(html) => Html(html).into_response(),
+        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Template error"),
+    }
+}
+
+/// Logs in with a link confirmed on the page of `login_with_link`. A link
+/// that doesn't work leaves the key form as the way in.
+pub async fn confirm_login_link(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    client: ClientInfo,
+    Query(link): Query<LinkQuery>,
+    Query(login): Query<LoginLinkQuery>,
+    RawQuery(query): RawQuery,
+    Form(form): Form<LoginLinkForm>,
+) -> Response {
+    if authenticated_share(&cookies, &state).is_some() {
+        return Redirect::to("/").into_response();
+    }
+    if !verify_csrf_token(&cookies, &form.csrf_token) {
+        // Back to the confirmation with a fresh token
+        let query = query.unwrap_or_default();
+        return Redirect::to(&format!("/login/link?{}", query)).into_response();
+    }
+
+    let config = state.config.load();
+    let Some(secret) = config.link_secret.as_deref() else {
+        return login_link_failed(&cookies);
+    };
+    let (Some(principal), Some(nonce), Some(expires)) = (
+        verify_login_link(&config, &link, &login),
+        &link.nonce,
+        link.expires,
+    ) else {
+        let reason = "invalid or expired login link".to_string();
+        record_unattributed(&state, &client, EventKind::LoginFailed { reason }).await;
+        return login_link_failed(&cookies);
+    };
+    let share = &principal.share;
+
+    match state.used_links.consume(nonce, expires).await {
+        Ok(true) => {}
+        Ok(false) => {
+            // The link is genuine, so the attempt belongs to this share
+            let reason = "login link already used".to_string();
+            record(
+                &state,
+                share,
+                Some(&principal.key().name),
+                &client,
+                EventKind::LoginFailed { reason },
+            )
+            .await;
+            return login_link_failed(&cookies);
+        }
+        Err(e) => {
+            warn!(
+                "Cannot record used login link for share '{}': {}",
+                share.name, e
+            );
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to check the link",
+            );
+        }
+    }
+    cookies.remove(TowerCookie::new("csrf_token", ""));
+    info!(
+        "Login link used for share '{}' with key '{}'",
+        share.name,
+        principal.key().name
+    );
+    record(
+        &state,
+        share,
+        Some(&principal.key().name),
+        &client,
+        EventKind::Login {
+            via: "link".to_string(),
+        },
+    )
+    .await;
+
+    // Lax, so links to the gallery from a mail client keep the session
+    let mut cookie = TowerCookie::new(SESSION_COOKIE, mint_session(secret, &principal));
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_same_site(tower_cookies::cookie::SameSite::Lax);
+    cookie.set_max_age(tower_cookies::cookie::time::Duration::seconds(
+        SESSION_TTL.as_secs() as i64,
+    ));
+    cookies.add(cookie);
+
+    Redirect::to("/").into_response()
+}
+
+fn login_link_failed(cookies: &Cookies) -> Response {
+    let template = LoginTemplate {
+        error: "Посилання для входу недійсне, застаріле або вже використане. Введіть ключ доступу або попросіть нове посилання.".to_string(),
+        csrf_token: issue_csrf_token(cookies),
+    };
+    match template.render() {
+        Ok(html) => (StatusCode::FORBIDDEN, Html(html)).into_response(),
+        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Template error"),
     }
 }
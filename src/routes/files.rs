@@ -1,162 +1,535 @@
-use crate::auth::verify_cookie_key;
+use crate::activity::ClientInfo;
+use crate::activity::archive_event;
+use crate::activity::file_event;
+use crate::activity::on_body_end;
+use crate::activity::track_download;
+use crate::auth::Principal;
+use crate::auth::authenticated_share;
+use crate::checksums::B3SUMS;
+use crate::checksums::SHA256SUMS;
+use crate::checksums::manifest;
+use crate::file_index::ShareIndex;
 use crate::file_utils::error_response;
-use crate::file_utils::should_include_file;
 use crate::file_utils::validate_path;
 use crate::models::AppState;
-use crate::zip_utils::calculate_directory_hash;
+use crate::models::ArchiveQuery;
+use crate::models::Permission;
+use crate::models::PreparingTemplate;
+use crate::models::Share;
+use crate::models::ZipStatus;
+use crate::previews::has_preview;
+use crate::signed_links::LinkClaim;
+use crate::signed_links::LinkQuery;
+use crate::signed_links::LinkTarget;
+use crate::signed_links::verify_link;
+use crate::tar_utils::TarFormat;
+use crate::tar_utils::serve_tar;
+use crate::zip_builder::BuildState;
+use crate::zip_builder::BuildStatus;
+use crate::zip_cache::ArchiveLease;
+use crate::zip_utils::ZipOptions;
 use crate::zip_utils::serve_zip_file;
-use async_zip::Compression;
-use async_zip::ZipEntryBuilder;
-use async_zip::base::write::ZipFileWriter;
+use crate::zip_utils::zip_part_numbers;
+use askama::Template;
+use axum::Json;
 use axum::body::Body;
 use axum::extract::Path as AxumPath;
+use axum::extract::Query;
 use axum::extract::State;
+use axum::http::HeaderMap;
 use axum::http::StatusCode;
+use axum::http::header;
+use axum::response::Html;
 use axum::response::IntoResponse;
 use axum::response::Redirect;
 use axum::response::Response;
-use std::io::Cursor;
-use tokio::fs;
+use std::sync::Arc;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
 use tokio_util::io::ReaderStream;
 use tower_cookies::Cookies;
 use tracing::info;
+use tracing::warn;
 
 pub async fn download_file(
     State(state): State<AppState>,
     cookies: Cookies,
+    client: ClientInfo,
     AxumPath(filename): AxumPath<String>,
+    Query(link): Query<LinkQuery>,
 ) -> Response {
     info!("File download requested: {}", filename);
-    if !verify_cookie_key(&cookies, &state.share_key) {
-        return Redirect::to("/login").into_response();
+    let (share, key) = if link.is_signed() {
+        let share = match linked_share(&state, &link, LinkTarget::File(filename.clone())).await {
+            Ok(share) => share,
+            Err(response) => return response,
+        };
+        (share, None)
+    } else {
+        match permitted_share(&cookies, &state, Permission::Download).await {
+            Ok(principal) => {
+                let key = principal.key().name.clone();
+                (principal.share, Some(key))
+            }
+            Err(response) => return response,
+        }
+    };
+    if let Err((status, message)) = check_unencrypted(&share) {
+        return error_response(status, message);
     }
+    let claim = match claim_link(&state, &share, &link).await {
+        Ok(claim) => claim,
+        Err(response) => return response,
+    };
+
+    let index = match state.indexes.get(&share.dir).await {
+        Ok(index) => index,
+        Err(_) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read dir"),
+    };
+    let response = serve_share_file(&index, &filename)
+        .await
+        .unwrap_or_else(|(status, message)| error_response(status, message));
+    let response = track_download(
+        &state,
+        &share,
+        key.as_deref(),
+        &client,
+        file_event(&index, &filename),
+        response,
+    );
+    commit_when_sent(claim, response)
+}
 
-    let filepath = match validate_path(&state.share_dir, &filename).await {
+/// Streams one file of a share as an attachment. Only files in the share's
+/// index are served, never the hidden ones kept next to them.
+pub(crate) async fn serve_share_file(
+    index: &ShareIndex,
+    filename: &str,
+) -> Result<Response, (StatusCode, &'static str)> {
+    if index
+        .files()
+        .binary_search_by(|f| f.name.as_str().cmp(filename))
+        .is_err()
+    {
+        return Err((StatusCode::NOT_FOUND, "File not found"));
+    }
+    let filepath = match validate_path(index.dir(), filename).await {
         Ok(Some(path)) => path,
-        Ok(None) => return error_response(StatusCode::BAD_REQUEST, "Invalid file requested"),
-        Err(_) => {
-            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "File system error");
-        }
+        Ok(None) => return Err((StatusCode::BAD_REQUEST, "Invalid file requested")),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "File system error")),
     };
 
-    match File::open(&filepath).await {
-        Ok(file) => {
-            let stream = ReaderStream::new(file);
-            match Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "application/octet-stream")
-                .header(
-                    "Content-Disposition",
-                    format!("attachment; filename=\"{}\"", filename),
-                )
-                .body(Body::from_stream(stream))
-            {
-                Ok(response) => response,
-                Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Response error"),
+    let file = File::open(&filepath)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to open file"))?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/octet-stream")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .body(Body::from_stream(ReaderStream::new(file)))
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Response error"))
+}
+
+pub async fn download_zip(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    client: ClientInfo,
+    headers: HeaderMap,
+    Query(query): Query<ArchiveQuery>,
+    Query(link): Query<LinkQuery>,
+) -> Response {
+    let (share, key) = if link.is_signed() {
+        let target = LinkTarget::Archive {
+            format: query.format.clone(),
+            part: query.part,
+        };
+        match linked_share(&state, &link, target).await {
+            Ok(share) => (share, None),
+            Err(response) => return response,
+        }
+    } else {
+        match permitted_share(&cookies, &state, Permission::DownloadZip).await {
+            Ok(principal) => {
+                let key = principal.key().name.clone();
+                (principal.share, Some(key))
             }
+            Err(response) => return response,
         }
-        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to open file"),
+    };
+    let tar_format = match archive_format(&share, query.format.as_deref()) {
+        Ok(format) => format,
+        Err((status, message)) => return error_response(status, message),
+    };
+
+    let index = match state.indexes.get(&share.dir).await {
+        Ok(index) => index,
+        Err(_) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read dir"),
+    };
+    let kind = archive_event(&index, &share, query.format.as_deref(), query.part).await;
+    let track = |response| track_download(&state, &share, key.as_deref(), &client, kind, response);
+
+    if let Some(format) = tar_format {
+        let claim = match claim_link(&state, &share, &link).await {
+            Ok(claim) => claim,
+            Err(response) => return response,
+        };
+        info!("Streaming {} of share '{}'", format.extension(), share.name);
+        let response = track(serve_tar(share.dir.clone(), index.files(), format));
+        return commit_when_sent(claim, response);
     }
-}
 
-pub async fn download_zip(State(state): State<AppState>, cookies: Cookies) -> Response {
-    if !verify_cookie_key(&cookies, &state.share_key) {
-        return Redirect::to("/login").into_response();
+    let part = match check_part(&index, &share.zip, query.part) {
+        Ok(part) => part,
+        Err(message) => return error_response(StatusCode::NOT_FOUND, message),
+    };
+
+    // Download managers and curl can't follow the progress page, they wait
+    // for the build instead. So do signed links, the page's redirect would
+    // lose the signature.
+    if link.is_signed() || !accepts_html(&headers) {
+        let archive = match state.zip_builder.wait(&index, &share.zip, part).await {
+            Ok(archive) => archive,
+            Err(message) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, message),
+        };
+        let claim = match claim_link(&state, &share, &link).await {
+            Ok(claim) => claim,
+            Err(response) => return response,
+        };
+        let response = track(
+            serve_cached_zip(archive, &index, &share.zip, part)
+                .await
+                .unwrap_or_else(|(status, message)| error_response(status, message)),
+        );
+        return commit_when_sent(claim, response);
+    }
+
+    match state.zip_builder.request(&index, &share.zip, part).await {
+        BuildStatus::Ready(archive) => track(
+            serve_cached_zip(archive, &index, &share.zip, part)
+                .await
+                .unwrap_or_else(|(status, message)| error_response(status, message)),
+        ),
+        BuildStatus::Building(progress) => {
+            let count = share.zip.parts(&index.files()).len();
+            let template = PreparingTemplate {
+                title: match part {
+                    Some(part) => format!("частину {} з {}", part, count),
+                    None => "архів".to_string(),
+                },
+                percent: progress.percent(),
+                query: part.map(|p| format!("?part={}", p)).unwrap_or_default(),
+            };
+            match template.render() {
+                Ok(html) => Html(html).into_response(),
+                Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Template error"),
+            }
+        }
+        BuildStatus::Failed(message) => error_response(StatusCode::INTERNAL_SERVER_ERROR, message),
     }
+}
 
-    // Setup zip cache directory
-    let zip_dir = state.share_dir.join(".zipcache");
-    let _ = fs::create_dir_all(&zip_dir).await;
+/// Logged-in browser whose key has `permission`, or else the login
+/// redirect or error page.
+async fn permitted_share(
+    cookies: &Cookies,
+    state: &AppState,
+    permission: Permission,
+) -> Result<Principal, Response> {
+    let Some(principal) = authenticated_share(cookies, state) else {
+        return Err(Redirect::to("/login").into_response());
+    };
+    principal
+        .check(permission)
+        .map_err(|(status, message)| error_response(status, message))?;
+    Ok(principal)
+}
 
-    // Get directory hash for cache filename
-    let hash = match calculate_directory_hash(&state.share_dir).await {
-        Ok(h) => h,
-        Err(_) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Hashing failed"),
+/// Web-size JPEG of an image, for keys that may only look at the photos.
+pub async fn preview_file(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    AxumPath(filename): AxumPath<String>,
+) -> Response {
+    let share = match permitted_share(&cookies, &state, Permission::Preview).await {
+        Ok(principal) => principal.share,
+        Err(response) => return response,
     };
+    serve_preview(&state, &share, &filename)
+        .await
+        .unwrap_or_else(|(status, message)| error_response(status, message))
+}
 
-    let cached_zip = zip_dir.join(format!("{}.zip", hash));
+/// Renders the preview on first use and streams it inline.
+pub(crate) async fn serve_preview(
+    state: &AppState,
+    share: &Share,
+    filename: &str,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let index = state
+        .indexes
+        .get(&share.dir)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read dir"))?;
+    let files = index.files();
+    let entry = files
+        .binary_search_by(|f| f.name.as_str().cmp(filename))
+        .ok()
+        .map(|i| &files[i])
+        .filter(|entry| has_preview(&entry.name))
+        .ok_or((StatusCode::NOT_FOUND, "No preview of this file"))?;
 
-    // Return cached zip if it exists
-    if cached_zip.exists() {
-        if let Ok(file) = File::open(&cached_zip).await {
-            return serve_zip_file(file);
+    let path = state
+        .previews
+        .ensure(&share.dir, entry)
+        .await
+        .map_err(|e| {
+            warn!(
+                "Preview of {:?} in share '{}' failed: {}",
+                filename, share.name, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to render the preview",
+            )
+        })?;
+    let file = File::open(&path)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to open file"))?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/jpeg")
+        .header(header::CACHE_CONTROL, "private, max-age=86400")
+        .body(Body::from_stream(ReaderStream::new(file)))
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Response error"))
+}
+
+/// Share of a signed download link, or the error page if the link isn't
+/// valid for `target`.
+async fn linked_share(
+    state: &AppState,
+    link: &LinkQuery,
+    target: LinkTarget,
+) -> Result<Arc<Share>, Response> {
+    let config = state.config.load();
+    let Some(secret) = &config.link_secret else {
+        return Err(error_response(
+            StatusCode::FORBIDDEN,
+            "Download links are disabled",
+        ));
+    };
+    let name = match verify_link(secret, link, &target) {
+        Ok(name) => name,
+        Err(message) => return Err(error_response(StatusCode::FORBIDDEN, message)),
+    };
+    match config
+        .shares
+        .iter()
+        .find(|s| s.name == name && !s.is_expired())
+    {
+        Some(share) => {
+            info!("Signed link to {:?} of share '{}' used", target, share.name);
+            Ok(share.clone())
         }
+        None => Err(error_response(
+            StatusCode::GONE,
+            "This share is no longer available",
+        )),
     }
+}
 
-    // Create a new zip file
-    let temp_path = zip_dir.join(format!("{}.tmp", hash));
+/// Holds a single-use link for the download about to start, see
+/// `commit_when_sent`. `None` for sessions and reusable links.
+async fn claim_link(
+    state: &AppState,
+    share: &Share,
+    link: &LinkQuery,
+) -> Result<Option<LinkClaim>, Response> {
+    let (true, Some(nonce), Some(expires)) = (link.is_signed(), &link.nonce, link.expires) else {
+        return Ok(None);
+    };
+    match state.used_links.claim(nonce, expires).await {
+        Ok(Some(claim)) => Ok(Some(claim)),
+        Ok(None) => Err(error_response(
+            StatusCode::GONE,
+            "This download link has already been used",
+        )),
+        Err(e) => {
+            warn!("Cannot record used link for share '{}': {}", share.name, e);
+            Err(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to check the download link",
+            ))
+        }
+    }
+}
 
-    // Get and sort the files
-    let mut entries = match fs::read_dir(&state.share_dir).await {
-        Ok(e) => e,
-        Err(_) => {
-            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read dir");
+/// Uses up a claimed link once `response` went out in full. An error reply
+/// or a download that breaks off leaves the link working.
+fn commit_when_sent(claim: Option<LinkClaim>, response: Response) -> Response {
+    let Some(claim) = claim else {
+        return response;
+    };
+    if !response.status().is_success() {
+        return response;
+    }
+    on_body_end(response, move |_, complete| {
+        if !complete {
+            return;
         }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(e) = claim.commit().await {
+                    warn!("Cannot record used download link: {}", e);
+                }
+            });
+        }
+    })
+}
+
+/// The tarball asked for by the `format` query, `None` for the ZIP.
+pub(crate) fn archive_format(
+    share: &Share,
+    format: Option<&str>,
+) -> Result<Option<TarFormat>, (StatusCode, &'static str)> {
+    let format = match format {
+        None | Some("zip") => return Ok(None),
+        Some("tar") => TarFormat::Tar,
+        Some("tar.zst") => TarFormat::TarZstd,
+        Some(_) => return Err((StatusCode::BAD_REQUEST, "Unknown archive format")),
     };
+    // A plain tarball would defeat the point of encrypting the ZIP
+    check_unencrypted(share)?;
+    Ok(Some(format))
+}
 
-    let mut files = Vec::new();
-    while let Ok(Some(entry)) = entries.next_entry().await {
-        let path = entry.path();
-        if let Ok(true) = should_include_file(&state.share_dir, &path).await {
-            files.push(path);
-        }
+/// Files of a share with an encrypted ZIP only leave the server inside it.
+pub(crate) fn check_unencrypted(share: &Share) -> Result<(), (StatusCode, &'static str)> {
+    match share.zip.password {
+        Some(_) => Err((
+            StatusCode::FORBIDDEN,
+            "This share is only available as an encrypted ZIP",
+        )),
+        None => Ok(()),
     }
+}
 
-    files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
-
-    // Create an in-memory buffer for the ZIP
-    let mut buffer = Vec::new();
-    let cursor = Cursor::new(&mut buffer);
-    let mut zip = ZipFileWriter::with_tokio(cursor);
+/// Streams an archive found by the builder, keeping its lease until the
+/// last byte is sent. `part` names the file after the part it is.
+pub(crate) async fn serve_cached_zip(
+    lease: ArchiveLease,
+    index: &ShareIndex,
+    options: &ZipOptions,
+    part: Option<usize>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let part = part.map(|part| (part, options.parts(&index.files()).len()));
+    match File::open(lease.path()).await {
+        Ok(file) => Ok(serve_zip_file(file, lease, part)),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "ZIP read error")),
+    }
+}
 
-    // Add files to the zip
-    for path in files {
-        let filename = match path.file_name().and_then(|n| n.to_str()) {
-            Some(name) => name,
-            None => continue,
-        };
+/// Checks the requested part against how the share is delivered: a split
+/// share needs a part number, any other share must not get one.
+pub(crate) fn check_part(
+    index: &ShareIndex,
+    options: &ZipOptions,
+    requested: Option<usize>,
+) -> Result<Option<usize>, &'static str> {
+    let parts = zip_part_numbers(index, options);
+    if parts.contains(&requested) {
+        Ok(requested)
+    } else if requested.is_none() {
+        Err("This share is split into parts, download them one by one")
+    } else {
+        Err("No such part")
+    }
+}
 
-        let mut file = match File::open(&path).await {
-            Ok(f) => f,
-            Err(_) => continue,
-        };
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
 
-        let mut contents = Vec::new();
-        if file.read_to_end(&mut contents).await.is_err() {
-            continue;
-        }
+/// Checksum manifest of every file in the share, as SHA256SUMS or B3SUMS.
+pub async fn download_checksums(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    AxumPath(name): AxumPath<String>,
+) -> Response {
+    let share = match permitted_share(&cookies, &state, Permission::List).await {
+        Ok(principal) => principal.share,
+        Err(response) => return response,
+    };
 
-        // Create entry for the file
-        let entry = ZipEntryBuilder::new(filename.into(), Compression::Stored);
+    let blake3 = match name.as_str() {
+        SHA256SUMS => false,
+        B3SUMS if share.zip.b3sums => true,
+        _ => return error_response(StatusCode::NOT_FOUND, "Page not found"),
+    };
 
-        if let Err(_) = zip.write_entry_whole(entry, &contents).await {
-            continue;
+    let index = match state.indexes.get(&share.dir).await {
+        Ok(index) => index,
+        Err(_) => return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to read dir"),
+    };
+    let digests = match index.digests(&index.files()).await {
+        Ok(digests) => digests,
+        Err(_) => {
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash files");
         }
-    }
+    };
 
-    // Finalize the zip
-    if let Err(_) = zip.close().await {
-        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create ZIP");
-    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        manifest(&digests, blake3),
+    )
+        .into_response()
+}
 
-    // Write the buffer to a file
-    if let Err(_) = fs::write(&temp_path, buffer).await {
-        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to write ZIP");
+/// Progress of the share's archive, for the preparing page.
+pub async fn download_zip_status(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    Query(query): Query<ArchiveQuery>,
+) -> Response {
+    let Some(principal) = authenticated_share(&cookies, &state) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if !principal.can(Permission::DownloadZip) {
+        return StatusCode::FORBIDDEN.into_response();
     }
+    let share = principal.share;
 
-    // Rename the temporary file to the final cached ZIP
-    if let Err(_) = fs::rename(&temp_path, &cached_zip).await {
-        return error_response(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Failed to save ZIP cache",
-        );
-    }
+    let index = match state.indexes.get(&share.dir).await {
+        Ok(index) => index,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let Ok(part) = check_part(&index, &share.zip, query.part) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
 
-    // Serve the ZIP file
-    match File::open(&cached_zip).await {
-        Ok(file) => serve_zip_file(file),
-        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "ZIP read error"),
-    }
+    let status = match state.zip_builder.request(&index, &share.zip, part).await {
+        BuildStatus::Ready(_) => ZipStatus {
+            state: "ready",
+            percent: 100,
+            error: None,
+        },
+        BuildStatus::Building(progress) => ZipStatus {
+            state: match progress.state() {
+                BuildState::Queued => "queued",
+                _ => "building",
+            },
+            percent: progress.percent(),
+            error: None,
+        },
+        BuildStatus::Failed(message) => ZipStatus {
+            state: "failed",
+            percent: 0,
+            error: Some(message),
+        },
+    };
+    Json(status).into_response()
 }
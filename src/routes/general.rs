@@ -1,9 +1,12 @@
-use crate::auth::verify_cookie_key;
+use crate::auth::authenticated_share;
 use crate::file_utils::error_response;
-use crate::file_utils::should_include_file;
 use crate::models::AppState;
 use crate::models::ErrorTemplate;
 use crate::models::ListTemplate;
+use crate::models::ListedFile;
+use crate::models::Permission;
+use crate::models::ZipPartLink;
+use crate::previews::has_preview;
 use askama::Template;
 use axum::extract::Path;
 use axum::extract::State;
@@ -12,39 +15,68 @@ use axum::http::header;
 use axum::response::Html;
 use axum::response::IntoResponse;
 use axum::response::Response;
-use mime_guess;
 use rust_embed::RustEmbed;
-use tokio::fs;
 use tower_cookies::Cookies;
 
 pub async fn index(State(state): State<AppState>, cookies: Cookies) -> Response {
-    if !verify_cookie_key(&cookies, &state.share_key) {
+    let Some(principal) = authenticated_share(&cookies, &state) else {
         return axum::response::Redirect::to("/login").into_response();
+    };
+    if let Err((status, message)) = principal.check(Permission::List) {
+        return error_response(status, message);
     }
+    let share = &principal.share;
+    let can_download = principal.can(Permission::Download) && share.zip.password.is_none();
+    // Originals are the better preview, so it's only linked for view-only keys
+    let can_preview = principal.can(Permission::Preview) && !can_download;
 
-    let mut entries = match fs::read_dir(&state.share_dir).await {
-        Ok(o) => o,
+    let index = match state.indexes.get(&share.dir).await {
+        Ok(index) => index,
         Err(e) => {
             return error_response(
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Can't read directory: {}", e.to_string()).as_str(),
+                format!("Can't read directory: {}", e).as_str(),
             );
         }
     };
 
-    let mut files = vec![];
-    while let Ok(Some(entry)) = entries.next_entry().await {
-        let path = entry.path();
-        if let Ok(true) = should_include_file(&state.share_dir, &path).await {
-            if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                files.push(name.to_string());
-            }
-        }
-    }
+    let entries = index.files();
+    let files = entries
+        .iter()
+        .map(|f| ListedFile {
+            name: f.name.clone(),
+            download: can_download,
+            preview: can_preview && has_preview(&f.name),
+        })
+        .collect();
+
+    let parts = share.zip.parts(&entries);
+    let zip_parts = if parts.len() > 1 {
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(i, range)| ZipPartLink {
+                number: i + 1,
+                size_mb: entries[range]
+                    .iter()
+                    .map(|f| f.size)
+                    .sum::<u64>()
+                    .div_ceil(1024 * 1024),
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
 
     let template = ListTemplate {
         files,
-        greet: state.greet,
+        greet: share.greet.clone(),
+        can_download,
+        can_download_zip: principal.can(Permission::DownloadZip),
+        upload_enabled: share.upload.is_some() && principal.can(Permission::Upload),
+        zip_encrypted: share.zip.password.is_some(),
+        zip_parts,
+        b3sums: share.zip.b3sums,
     };
     match template.render() {
         Ok(html) => Html(html).into_response(),
@@ -1,7 +1,13 @@
+mod admin;
+mod api;
 mod auth;
 mod files;
 mod general;
+mod upload;
 
+pub use admin::*;
+pub use api::*;
 pub use auth::*;
 pub use files::*;
 pub use general::*;
+pub use upload::*;
@@ -0,0 +1,238 @@
+use crate::auth::authenticated_share;
+use crate::file_utils::error_response;
+use crate::models::AppState;
+use crate::models::Permission;
+use crate::models::Share;
+use crate::models::UploadConfig;
+use crate::models::UploadTemplate;
+use crate::routes::issue_csrf_token;
+use crate::routes::verify_csrf_token;
+use crate::upload_utils::Reservation;
+use crate::upload_utils::claim_destination;
+use crate::upload_utils::inbox_dir;
+use crate::upload_utils::is_allowed_extension;
+use crate::upload_utils::notify_upload;
+use crate::upload_utils::sanitize_upload_name;
+use askama::Template;
+use axum::extract::Multipart;
+use axum::extract::State;
+use axum::extract::multipart::Field;
+use axum::http::StatusCode;
+use axum::response::Html;
+use axum::response::IntoResponse;
+use axum::response::Redirect;
+use axum::response::Response;
+use rand::Rng;
+use rand::rng;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tower_cookies::Cookies;
+use tracing::{error, info, warn};
+
+/// Share of a logged-in browser whose key may upload, or else the login
+/// redirect or error page.
+async fn uploading_share(cookies: &Cookies, state: &AppState) -> Result<Arc<Share>, Response> {
+    let Some(principal) = authenticated_share(cookies, state) else {
+        return Err(Redirect::to("/login").into_response());
+    };
+    principal
+        .check(Permission::Upload)
+        .map_err(|(status, message)| error_response(status, message))?;
+    Ok(principal.share)
+}
+
+pub async fn show_upload_form(State(state): State<AppState>, cookies: Cookies) -> Response {
+    let share = match uploading_share(&cookies, &state).await {
+        Ok(share) => share,
+        Err(response) => return response,
+    };
+    let Some(config) = &share.upload else {
+        return error_response(StatusCode::NOT_FOUND, "Uploads are disabled");
+    };
+
+    render_upload_page(config, &cookies, "", vec![])
+}
+
+pub async fn process_upload(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    mut multipart: Multipart,
+) -> Response {
+    let share = match uploading_share(&cookies, &state).await {
+        Ok(share) => share,
+        Err(response) => return response,
+    };
+    let Some(config) = &share.upload else {
+        return error_response(StatusCode::NOT_FOUND, "Uploads are disabled");
+    };
+
+    // The form places the CSRF token before the files, so it can be checked
+    // before anything is written to disk
+    let csrf_valid = match multipart.next_field().await {
+        Ok(Some(field)) if field.name() == Some("csrf_token") => match field.text().await {
+            Ok(token) => verify_csrf_token(&cookies, &token),
+            Err(_) => false,
+        },
+        _ => false,
+    };
+    if !csrf_valid {
+        return render_upload_page(
+            config,
+            &cookies,
+            "Помилка безпеки: недійсний маркер CSRF. Спробуйте знову.",
+            vec![],
+        );
+    }
+
+    let inbox = inbox_dir(&share.dir);
+    if let Err(e) = fs::create_dir_all(&inbox).await {
+        error!("Failed to create inbox {:?}: {}", inbox, e);
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "File system error");
+    }
+
+    let mut uploaded = vec![];
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(f)) => f,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Malformed upload: {}", e);
+                return render_upload_page(
+                    config,
+                    &cookies,
+                    "Завантаження перервано. Спробуйте ще раз.",
+                    uploaded,
+                );
+            }
+        };
+
+        // Empty file inputs still produce a part without a filename
+        let Some(raw_name) = field.file_name().filter(|n| !n.is_empty()) else {
+            continue;
+        };
+        let Some(name) = sanitize_upload_name(raw_name) else {
+            warn!("Rejected upload with unsafe name: {}", raw_name);
+            return render_upload_page(config, &cookies, "Недопустима назва файлу.", uploaded);
+        };
+        if !is_allowed_extension(config, &name) {
+            return render_upload_page(
+                config,
+                &cookies,
+                &format!("Тип файлу не дозволено: {}", name),
+                uploaded,
+            );
+        }
+
+        let reservation = match state.uploads.begin(&inbox).await {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Failed to read inbox {:?}: {}", inbox, e);
+                return error_response(StatusCode::INTERNAL_SERVER_ERROR, "File system error");
+            }
+        };
+        match store_field(field, &inbox, &name, config, reservation).await {
+            Ok(Some((path, size))) => {
+                info!("Stored upload {:?} ({} bytes)", path, size);
+                notify_upload(config, &path, size);
+                uploaded.push(name);
+            }
+            Ok(None) => {
+                return render_upload_page(
+                    config,
+                    &cookies,
+                    &format!("Файл {} перевищує допустимий розмір або квоту.", name),
+                    uploaded,
+                );
+            }
+            Err(e) => {
+                error!("Failed to store upload {}: {}", name, e);
+                return render_upload_page(
+                    config,
+                    &cookies,
+                    "Не вдалося зберегти файл. Спробуйте ще раз.",
+                    uploaded,
+                );
+            }
+        }
+    }
+
+    render_upload_page(config, &cookies, "", uploaded)
+}
+
+/// Streams a multipart field into the inbox. Returns `Ok(None)` when the
+/// field grows beyond the file size limit or the quota.
+async fn store_field(
+    mut field: Field<'_>,
+    inbox: &Path,
+    name: &str,
+    config: &UploadConfig,
+    mut reservation: Reservation,
+) -> std::io::Result<Option<(PathBuf, u64)>> {
+    let suffix: u64 = rng().random();
+    let mut partial = PartialFile(Some(inbox.join(format!(".partial-{:016x}", suffix))));
+    let mut file = File::create(partial.path()).await?;
+    let mut written: u64 = 0;
+
+    while let Some(chunk) = field.chunk().await.map_err(std::io::Error::other)? {
+        written += chunk.len() as u64;
+        if written > config.max_file_bytes
+            || !reservation.grow(chunk.len() as u64, config.quota_bytes)
+        {
+            return Ok(None);
+        }
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await?;
+    drop(file);
+
+    let destination = claim_destination(inbox, name).await?;
+    if let Err(e) = fs::rename(partial.path(), &destination).await {
+        let _ = fs::remove_file(&destination).await;
+        return Err(e);
+    }
+    partial.0 = None;
+    reservation.keep();
+    Ok(Some((destination, written)))
+}
+
+/// Removes an unfinished upload, also when the client breaks off and the
+/// handler is dropped mid-stream.
+struct PartialFile(Option<PathBuf>);
+
+impl PartialFile {
+    fn path(&self) -> &Path {
+        self.0.as_deref().expect("partial file was already stored")
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn render_upload_page(
+    config: &UploadConfig,
+    cookies: &Cookies,
+    error: &str,
+    uploaded: Vec<String>,
+) -> Response {
+    let template = UploadTemplate {
+        error: error.to_string(),
+        uploaded,
+        csrf_token: issue_csrf_token(cookies),
+        allowed_extensions: config.allowed_extensions.clone(),
+        max_file_mb: config.max_file_bytes / (1024 * 1024),
+    };
+    match template.render() {
+        Ok(html) => Html(html).into_response(),
+        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Template error"),
+    }
+}
@@ -0,0 +1,23 @@
+use tokio::signal::unix::SignalKind;
+use tokio::signal::unix::signal;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Cancels `token` on the first SIGTERM or SIGINT.
+pub fn spawn_signal_listener(token: CancellationToken) {
+    tokio::spawn(async move {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Cannot listen for SIGTERM: {}", e);
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+            _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        }
+        token.cancel();
+    });
+}
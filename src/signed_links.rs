@@ -0,0 +1,743 @@
+use crate::auth::Principal;
+use crate::file_utils::URL_COMPONENT;
+use crate::models::Share;
+use crate::models::ShareKey;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::Hmac;
+use hmac::Mac;
+use percent_encoding::percent_decode_str;
+use percent_encoding::utf8_percent_encode;
+use rand::Rng;
+use rand::rng;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Lifetime of a link minted without an explicit one.
+pub const DEFAULT_LINK_TTL: Duration = Duration::from_secs(72 * 3600);
+/// Markers of single-use links already followed, in the state directory.
+/// Nonces are random, so one directory serves every share.
+const USED_LINKS_DIR: &str = "used-links";
+/// How often markers of expired links are cleared away.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+/// Bumped whenever the signed message changes, invalidating older links.
+const LINK_VERSION: &str = "link-v1";
+const SESSION_VERSION: &str = "session-v2";
+/// How long a browser stays logged in after following a login link.
+pub const SESSION_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// What a signed link downloads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkTarget {
+    File(String),
+    /// `format` and `part` as in the `/download-zip` query.
+    Archive {
+        format: Option<String>,
+        part: Option<usize>,
+    },
+    /// Logs the browser in with the named key, or the share's first one.
+    /// Always single-use.
+    Login {
+        key: Option<String>,
+    },
+}
+
+impl LinkTarget {
+    /// Path and query of the download, without the signature.
+    fn url(&self) -> String {
+        match self {
+            LinkTarget::File(name) => {
+                format!("/download/{}", utf8_percent_encode(name, URL_COMPONENT))
+            }
+            LinkTarget::Archive { format, part } => {
+                let mut query = Vec::new();
+                if let Some(format) = format {
+                    query.push(format!(
+                        "format={}",
+                        utf8_percent_encode(format, URL_COMPONENT)
+                    ));
+                }
+                if let Some(part) = part {
+                    query.push(format!("part={}", part));
+                }
+                if query.is_empty() {
+                    "/download-zip".to_string()
+                } else {
+                    format!("/download-zip?{}", query.join("&"))
+                }
+            }
+            LinkTarget::Login { key: None } => "/login/link".to_string(),
+            LinkTarget::Login { key: Some(key) } => format!(
+                "/login/link?key={}",
+                utf8_percent_encode(key, URL_COMPONENT)
+            ),
+        }
+    }
+
+    fn signed_fields(&self) -> String {
+        match self {
+            LinkTarget::File(name) => format!("file\n{}", name),
+            LinkTarget::Archive { format, part } => format!(
+                "archive\n{}\n{}",
+                format.as_deref().unwrap_or("zip"),
+                part.map(|p| p.to_string()).unwrap_or_default()
+            ),
+            LinkTarget::Login { key: None } => "login".to_string(),
+            LinkTarget::Login { key: Some(key) } => format!("login\n{}", key),
+        }
+    }
+}
+
+/// Signature part of a link's query, next to the download's own parameters.
+#[derive(Deserialize, Default)]
+pub struct LinkQuery {
+    pub share: Option<String>,
+    pub expires: Option<u64>,
+    pub nonce: Option<String>,
+    pub sig: Option<String>,
+}
+
+impl LinkQuery {
+    /// True if the request came through a signed link rather than a session.
+    pub fn is_signed(&self) -> bool {
+        self.sig.is_some()
+    }
+}
+
+/// Reads the signature fields out of a link's query string.
+pub fn parse_link_query(query: &str) -> LinkQuery {
+    let mut parsed = LinkQuery::default();
+    for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+        let value = percent_decode_str(value).decode_utf8_lossy().into_owned();
+        match key {
+            "share" => parsed.share = Some(value),
+            "expires" => parsed.expires = value.parse().ok(),
+            "nonce" => parsed.nonce = Some(value),
+            "sig" => parsed.sig = Some(value),
+            _ => {}
+        }
+    }
+    parsed
+}
+
+/// Path and query of a link to `target` in `share`, valid for `ttl`.
+pub fn mint_link(
+    secret: &str,
+    share: &str,
+    target: &LinkTarget,
+    ttl: Duration,
+    single_use: bool,
+) -> Result<String, &'static str> {
+    let expires = unix_now()
+        .checked_add(ttl.as_secs())
+        .ok_or("link lifetime is too long")?;
+    let nonce = single_use.then(|| URL_SAFE_NO_PAD.encode(rng().random::<[u8; 16]>()));
+    let signature = sign(secret, share, target, expires, nonce.as_deref());
+
+    let url = target.url();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let mut link = format!(
+        "{}{}share={}&expires={}",
+        url,
+        separator,
+        utf8_percent_encode(share, URL_COMPONENT),
+        expires
+    );
+    if let Some(nonce) = nonce {
+        link.push_str(&format!("&nonce={}", nonce));
+    }
+    link.push_str(&format!("&sig={}", signature));
+    Ok(link)
+}
+
+/// Checks the signature and expiry of a link to `target`, returning the
+/// name of the share it was minted for.
+pub fn verify_link<'q>(
+    secret: &str,
+    query: &'q LinkQuery,
+    target: &LinkTarget,
+) -> Result<&'q str, &'static str> {
+    let (Some(share), Some(expires), Some(sig)) = (&query.share, query.expires, &query.sig) else {
+        return Err("Incomplete download link");
+    };
+    let signature = URL_SAFE_NO_PAD
+        .decode(sig)
+        .map_err(|_| "Invalid download link")?;
+
+    let mut mac = new_mac(secret);
+    mac.update(message(share, target, expires, query.nonce.as_deref()).as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| "Invalid download link")?;
+
+    // Checked after the signature, so the reply doesn't tell forgers apart
+    if unix_now() > expires {
+        return Err("This download link has expired");
+    }
+    Ok(share)
+}
+
+fn sign(
+    secret: &str,
+    share: &str,
+    target: &LinkTarget,
+    expires: u64,
+    nonce: Option<&str>,
+) -> String {
+    let mut mac = new_mac(secret);
+    mac.update(message(share, target, expires, nonce).as_bytes());
+    URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+fn new_mac(secret: &str) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length")
+}
+
+fn message(share: &str, target: &LinkTarget, expires: u64, nonce: Option<&str>) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}",
+        LINK_VERSION,
+        share,
+        target.signed_fields(),
+        expires,
+        nonce.unwrap_or_default()
+    )
+}
+
+/// Value of the session cookie set by a login link,
+/// `{share}:{key}:{expires}:{sig}`. The signature covers the key's hash, so
+/// rotating or removing the key logs the browser out.
+pub fn mint_session(secret: &str, principal: &Principal) -> String {
+    let expires = unix_now() + SESSION_TTL.as_secs();
+    let mut mac = new_mac(secret);
+    mac.update(session_message(&principal.share, principal.key(), expires).as_bytes());
+    format!(
+        "{}:{}:{}:{}",
+        principal.share.name,
+        principal.key().name,
+        expires,
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    )
+}
+
+/// The share and key a session cookie is valid for.
+pub fn verify_session(secret: &str, value: &str, shares: &[Arc<Share>]) -> Option<Principal> {
+    let mut fields = value.splitn(4, ':');
+    let (name, key, expires, sig) = (
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    );
+    let expires: u64 = expires.parse().ok()?;
+    let share = shares.iter().find(|s| s.name == name && !s.is_expired())?;
+    let index = share.keys.iter().position(|k| k.name == key)?;
+
+    let mut mac = new_mac(secret);
+    mac.update(session_message(share, &share.keys[index], expires).as_bytes());
+    mac.verify_slice(&URL_SAFE_NO_PAD.decode(sig).ok()?).ok()?;
+    (unix_now() <= expires).then(|| Principal::new(share.clone(), index))
+}
+
+fn session_message(share: &Share, key: &ShareKey, expires: u64) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}",
+        SESSION_VERSION,
+        share.name,
+        key.name,
+        expires,
+        blake3::Hash::from_bytes(key.key_hash).to_hex()
+    )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Remembers the nonces of single-use links that were followed, as one
+/// marker file per nonce holding the link's expiry. Markers are created
+/// atomically, so the server and `login-link revoke` never undo each
+/// other's, and a restart doesn't revive a link.
+pub struct UsedLinks {
+    dir: PathBuf,
+    /// Nonces of downloads still being sent. A second client is refused
+    /// meanwhile, but the link stays valid if the download breaks off.
+    in_flight: Mutex<HashSet<String>>,
+    /// Unix time of the last sweep for markers of expired links.
+    pruned: AtomicU64,
+}
+
+impl UsedLinks {
+    /// Keeps the markers in `state_dir`, created on first use.
+    pub fn new(state_dir: &Path) -> Self {
+        Self {
+            dir: state_dir.join(USED_LINKS_DIR),
+            in_flight: Mutex::new(HashSet::new()),
+            pruned: AtomicU64::new(0),
+        }
+    }
+
+    /// Marks the link's nonce as used. False if it already was, or if a
+    /// download through it is still running.
+    pub async fn consume(&self, nonce: &str, expires: u64) -> std::io::Result<bool> {
+        if self.in_flight.lock().unwrap().contains(nonce) {
+            return Ok(false);
+        }
+        self.record(nonce, expires).await
+    }
+
+    /// Marks a nonce as used for good, for revoking a link whose expiry
+    /// isn't known.
+    pub async fn revoke(&self, nonce: &str) -> std::io::Result<bool> {
+        self.record(nonce, u64::MAX).await
+    }
+
+    /// Holds the link for a download, which `LinkClaim::commit` marks as
+    /// used once the body was sent in full. `None` if the link was used
+    /// or is being downloaded already.
+    pub async fn claim(
+        self: &Arc<Self>,
+        nonce: &str,
+        expires: u64,
+    ) -> std::io::Result<Option<LinkClaim>> {
+        if self.is_used(nonce).await? || !self.in_flight.lock().unwrap().insert(nonce.to_string()) {
+            return Ok(None);
+        }
+        Ok(Some(LinkClaim {
+            links: self.clone(),
+            nonce: nonce.to_string(),
+            expires,
+        }))
+    }
+
+    /// True if the link was followed or revoked.
+    pub async fn is_used(&self, nonce: &str) -> std::io::Result<bool> {
+        fs::try_exists(self.marker(nonce)?).await
+    }
+
+    fn marker(&self, nonce: &str) -> std::io::Result<PathBuf> {
+        // Minted nonces are URL-safe base64, so anything else is no file name
+        let valid = !nonce.is_empty()
+            && nonce
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "malformed link nonce",
+            ));
+        }
+        Ok(self.dir.join(nonce))
+    }
+
+    async fn record(&self, nonce: &str, expires: u64) -> std::io::Result<bool> {
+        let marker = self.marker(nonce)?;
+        self.prune().await;
+        fs::create_dir_all(&self.dir).await?;
+        let mut file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&marker)
+            .await
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        file.write_all(expires.to_string().as_bytes()).await?;
+        Ok(true)
+    }
+
+    /// Removes the markers of expired links, which are refused anyway. Runs
+    /// at most once per `PRUNE_INTERVAL`.
+    async fn prune(&self) {
+        let now = unix_now();
+        let last = self.pruned.load(Ordering::Relaxed);
+        if now < last.saturating_add(PRUNE_INTERVAL.as_secs())
+            || self
+                .pruned
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+
+        let Ok(mut entries) = fs::read_dir(&self.dir).await else {
+            return;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            // Markers that can't be read are kept, better used than revived
+            let expired = fs::read_to_string(entry.path())
+                .await
+                .ok()
+                .and_then(|contents| contents.trim().parse::<u64>().ok())
+                .is_some_and(|expires| expires < now);
+            if expired {
+                let _ = fs::remove_file(entry.path()).await;
+            }
+        }
+    }
+}
+
+/// A single-use link held by a running download. Dropped without
+/// `commit`, the link can be followed again.
+pub struct LinkClaim {
+    links: Arc<UsedLinks>,
+    nonce: String,
+    expires: u64,
+}
+
+impl LinkClaim {
+    /// Marks the link as used.
+    pub async fn commit(self) -> std::io::Result<bool> {
+        self.links.record(&self.nonce, self.expires).await
+    }
+}
+
+impl Drop for LinkClaim {
+    fn drop(&mut self) {
+        self.links.in_flight.lock().unwrap().remove(&self.nonce);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use crate::test_app::FILE;
+    use crate::test_app::KEY;
+    use crate::test_app::LINK_SECRET;
+    use crate::test_app::SHARE;
+    use crate::test_app::TestApp;
+    use crate::test_app::csrf_of;
+    use crate::test_app::eventually;
+    use crate::test_app::key_cookie;
+    use crate::test_app::test_share;
+    use axum::body::Body;
+    use axum::body::to_bytes;
+    use axum::http::Method;
+    use axum::http::Request;
+    use axum::http::StatusCode;
+    use axum::http::header;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn query(link: &str) -> LinkQuery {
+        parse_link_query(link.split_once('?').unwrap().1)
+    }
+
+    #[test]
+    fn links_verify_only_for_their_target() {
+        let file = LinkTarget::File("IMG 0001.jpg".to_string());
+        let link = mint_link(SECRET, "smith", &file, DEFAULT_LINK_TTL, false).unwrap();
+        assert!(link.starts_with("/download/IMG%200001.jpg?share=smith&"));
+        assert_eq!(verify_link(SECRET, &query(&link), &file), Ok("smith"));
+
+        let other = LinkTarget::File("IMG 0002.jpg".to_string());
+        assert!(verify_link(SECRET, &query(&link), &other).is_err());
+        assert!(verify_link("another secret of enough length!!", &query(&link), &file).is_err());
+
+        let archive = LinkTarget::Archive {
+            format: None,
+            part: Some(2),
+        };
+        let link = mint_link(SECRET, "smith", &archive, DEFAULT_LINK_TTL, true).unwrap();
+        assert!(link.starts_with("/download-zip?part=2&share=smith&"));
+        let parsed = query(&link);
+        assert!(parsed.nonce.is_some());
+        assert_eq!(verify_link(SECRET, &parsed, &archive), Ok("smith"));
+
+        let whole = LinkTarget::Archive {
+            format: None,
+            part: None,
+        };
+        assert!(verify_link(SECRET, &parsed, &whole).is_err());
+
+        let guests = LinkTarget::Login {
+            key: Some("guests".to_string()),
+        };
+        let link = mint_link(SECRET, "smith", &guests, DEFAULT_LINK_TTL, true).unwrap();
+        assert!(link.starts_with("/login/link?key=guests&share=smith&"));
+        assert_eq!(verify_link(SECRET, &query(&link), &guests), Ok("smith"));
+        let owner = LinkTarget::Login { key: None };
+        assert!(verify_link(SECRET, &query(&link), &owner).is_err());
+    }
+
+    #[test]
+    fn tampered_and_expired_links_are_refused() {
+        let file = LinkTarget::File("a.jpg".to_string());
+        let mut parsed =
+            query(&mint_link(SECRET, "smith", &file, DEFAULT_LINK_TTL, false).unwrap());
+
+        parsed.expires = parsed.expires.map(|e| e + 1);
+        assert_eq!(
+            verify_link(SECRET, &parsed, &file),
+            Err("Invalid download link")
+        );
+
+        let expires = unix_now() - 1;
+        parsed.expires = Some(expires);
+        parsed.sig = Some(sign(SECRET, "smith", &file, expires, None));
+        assert_eq!(
+            verify_link(SECRET, &parsed, &file),
+            Err("This download link has expired")
+        );
+
+        parsed.sig = None;
+        assert_eq!(
+            verify_link(SECRET, &parsed, &file),
+            Err("Incomplete download link")
+        );
+    }
+
+    #[test]
+    fn sessions_end_when_the_key_changes() {
+        let share = |guest_key: &str| {
+            let key = |name: &str, key: &str| ShareKey {
+                name: name.to_string(),
+                key_hash: crate::auth::hash_key(key),
+                role: "view".to_string(),
+                permissions: Vec::new(),
+            };
+            Arc::new(Share {
+                name: "smith".to_string(),
+                dir: std::env::temp_dir(),
+                keys: vec![
+                    key("owner", "owner key of the share"),
+                    key("guests", guest_key),
+                ],
+                greet: String::new(),
+                zip: Default::default(),
+                upload: None,
+                email: None,
+                expires: None,
+            })
+        };
+        let shares = vec![share("first key of the guests")];
+        let session = mint_session(SECRET, &Principal::new(shares[0].clone(), 1));
+        assert!(session.starts_with("smith:guests:"));
+        let principal = verify_session(SECRET, &session, &shares).unwrap();
+        assert_eq!(principal.key().name, "guests");
+
+        let forged = session.replacen("smith:guests:", "smith:owner:", 1);
+        assert!(verify_session(SECRET, &forged, &shares).is_none());
+
+        let rotated = vec![share("second key of the guests")];
+        assert!(verify_session(SECRET, &session, &rotated).is_none());
+    }
+
+    #[test]
+    fn overlong_lifetimes_are_refused() {
+        let file = LinkTarget::File("a.jpg".to_string());
+        let ttl = Duration::from_secs(u64::MAX - 10);
+        assert!(mint_link(SECRET, "smith", &file, ttl, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn nonces_are_consumed_once() {
+        let dir = std::env::temp_dir().join(format!("photo4share-links-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let used = UsedLinks::new(&dir);
+        let expires = unix_now() + 60;
+
+        assert!(used.consume("first", expires).await.unwrap());
+        assert!(used.consume("second", expires).await.unwrap());
+        assert!(!used.consume("first", expires).await.unwrap());
+        // Survives a restart
+        assert!(
+            !UsedLinks::new(&dir)
+                .consume("second", expires)
+                .await
+                .unwrap()
+        );
+
+        // Revoked by nonce alone
+        assert!(used.revoke("third").await.unwrap());
+        assert!(!used.consume("third", expires).await.unwrap());
+        assert!(used.consume("../escape", expires).await.is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn claims_are_used_up_only_when_committed() {
+        let dir = std::env::temp_dir().join(format!("photo4share-claims-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let used = Arc::new(UsedLinks::new(&dir));
+        let expires = unix_now() + 60;
+
+        // A broken off download leaves the link working
+        let claim = used.claim("nonce", expires).await.unwrap().unwrap();
+        assert!(used.claim("nonce", expires).await.unwrap().is_none());
+        assert!(!used.consume("nonce", expires).await.unwrap());
+        drop(claim);
+
+        let claim = used.claim("nonce", expires).await.unwrap().unwrap();
+        assert!(claim.commit().await.unwrap());
+        assert!(used.claim("nonce", expires).await.unwrap().is_none());
+        assert!(!used.consume("nonce", expires).await.unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_marks_of_a_nonce_succeed_once() {
+        let dir = std::env::temp_dir().join(format!("photo4share-marks-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // Separate instances, like the server and the CLI
+        let server = UsedLinks::new(&dir);
+        let cli = UsedLinks::new(&dir);
+        let expires = unix_now() + 60;
+
+        for i in 0..20 {
+            let nonce = format!("nonce{}", i);
+            let (a, b) = tokio::join!(server.consume(&nonce, expires), cli.revoke(&nonce));
+            assert!(a.unwrap() ^ b.unwrap(), "{}", nonce);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn markers_of_expired_links_are_pruned() {
+        let dir = std::env::temp_dir().join(format!("photo4share-prune-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let used = UsedLinks::new(&dir);
+        let now = unix_now();
+
+        assert!(used.consume("expired", now - 1).await.unwrap());
+        assert!(used.consume("valid", now + 60).await.unwrap());
+        assert!(used.revoke("revoked").await.unwrap());
+        used.pruned.store(0, Ordering::Relaxed);
+        assert!(used.consume("another", now + 60).await.unwrap());
+
+        assert!(!used.is_used("expired").await.unwrap());
+        assert!(used.is_used("valid").await.unwrap());
+        assert!(used.is_used("revoked").await.unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn app_link(target: &LinkTarget, single_use: bool) -> String {
+        mint_link(LINK_SECRET, SHARE, target, DEFAULT_LINK_TTL, single_use).unwrap()
+    }
+
+    #[tokio::test]
+    async fn single_use_links_survive_broken_off_downloads() {
+        let app = TestApp::builder("single")
+            .file(FILE, vec![7; 1 << 20])
+            .build();
+        let link = app_link(&LinkTarget::File(FILE.to_string()), true);
+
+        // Dropped before the body was read, like a client that went away
+        let response = app.send(Method::GET, &link, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        drop(response);
+
+        let response = app.send(Method::GET, &link, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), 1 << 20);
+
+        // Recorded in the background once the body is sent, and kept with
+        // the server's state rather than in the share
+        let used = app.state_dir().join(USED_LINKS_DIR);
+        assert!(eventually(async || used.exists()).await);
+        assert!(!app.dir.join(USED_LINKS_DIR).exists());
+        let response = app.send(Method::GET, &link, None).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
+    #[tokio::test]
+    async fn login_links_are_used_up_by_the_confirmation_only() {
+        let app = TestApp::builder("login").build();
+        let link = app_link(&LinkTarget::Login { key: None }, true);
+
+        // Fetched by a mail scanner first, then by the client
+        let response = app.send(Method::GET, &link, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.send(Method::GET, &link, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let (csrf_cookie, token) = csrf_of(response).await;
+
+        let confirm = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri(&link)
+                .header(header::COOKIE, &csrf_cookie)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(format!("csrf_token={}", token)))
+                .unwrap()
+        };
+        let response = app.request(confirm()).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let session = format!("{}=", auth::SESSION_COOKIE);
+        assert!(
+            response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .any(|c| c.to_str().unwrap().starts_with(&session))
+        );
+
+        let response = app.request(confirm()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app.send(Method::GET, &link, None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn expired_shares_open_with_no_key_session_or_link() {
+        let today = chrono::Utc::now().date_naive();
+        let file_link = app_link(&LinkTarget::File(FILE.to_string()), false);
+        let login_link = app_link(&LinkTarget::Login { key: None }, true);
+
+        // Open through its last day, closed the day after
+        for (last_day, open) in [(today, true), (today.pred_opt().unwrap(), false)] {
+            let app = TestApp::builder("expired")
+                .file(FILE, b"photo")
+                .share(|share| share.expires = Some(last_day))
+                .build();
+            let share = Arc::new(test_share(&app.dir));
+            let session = mint_session(LINK_SECRET, &Principal::new(share, 0));
+            let session = format!("{}={}", auth::SESSION_COOKIE, session);
+
+            let statuses = [
+                app.send(Method::GET, "/api/v1/share", Some(KEY)).await,
+                app.get_with_cookie("/api/v1/share", &key_cookie(KEY)).await,
+                app.get_with_cookie("/api/v1/share", &session).await,
+                app.send(Method::GET, &file_link, None).await,
+                app.send(Method::GET, &login_link, None).await,
+            ]
+            .map(|response| response.status());
+            let expected = if open {
+                [StatusCode::OK; 5]
+            } else {
+                [
+                    StatusCode::UNAUTHORIZED,
+                    StatusCode::UNAUTHORIZED,
+                    StatusCode::UNAUTHORIZED,
+                    StatusCode::GONE,
+                    StatusCode::FORBIDDEN,
+                ]
+            };
+            assert_eq!(statuses, expected, "last day {}", last_day);
+        }
+    }
+}
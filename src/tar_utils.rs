@@ -0,0 +1,229 @@
+use crate::file_index::FileEntry;
+use crate::file_utils::error_response;
+use async_compression::tokio::bufread::ZstdEncoder;
+use axum::body::Body;
+use axum::http::StatusCode;
+use axum::response::Response;
+use futures_util::StreamExt;
+use futures_util::stream;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::io::BufReader;
+use tokio::sync::oneshot;
+use tokio_util::io::ReaderStream;
+use tokio_util::io::SyncIoBridge;
+use tracing::debug;
+
+/// Buffer between the thread writing the tarball and the response stream.
+const PIPE_CAPACITY: usize = 256 * 1024;
+
+/// Tarballs offered next to the cached ZIP. They are never cached, tar needs
+/// no central directory so it is streamed while it is being written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TarFormat {
+    Tar,
+    TarZstd,
+}
+
+impl TarFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            TarFormat::Tar => "tar",
+            TarFormat::TarZstd => "tar.zst",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            TarFormat::Tar => "application/x-tar",
+            TarFormat::TarZstd => "application/zstd",
+        }
+    }
+}
+
+/// Streams a tarball of `files`, which should come from the share's index
+/// so every format has the same contents in the same order.
+pub fn serve_tar(dir: PathBuf, files: Arc<Vec<FileEntry>>, format: TarFormat) -> Response {
+    let (reader, writer) = tokio::io::duplex(PIPE_CAPACITY);
+
+    // The tar crate is synchronous, so it runs on a blocking thread and
+    // fails with a broken pipe as soon as the client goes away
+    let writer = SyncIoBridge::new(writer);
+    let (result_tx, result_rx) = oneshot::channel();
+    tokio::task::spawn_blocking(move || {
+        let result = write_tar(writer, &dir, &files);
+        if let Err(e) = &result {
+            debug!("Tar stream of {:?} ended early: {}", dir, e);
+        }
+        let _ = result_tx.send(result);
+    });
+
+    // The pipe ends the same way whether or not the tarball was finished, so
+    // a failed write errors the body and the client sees it cut short
+    let failure = stream::once(async move {
+        match result_rx.await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(Err(e)),
+            Err(_) => Some(Err(std::io::Error::other("tar writer stopped"))),
+        }
+    })
+    .filter_map(std::future::ready);
+
+    let body = match format {
+        TarFormat::Tar => Body::from_stream(ReaderStream::new(reader).chain(failure)),
+        TarFormat::TarZstd => Body::from_stream(
+            ReaderStream::new(ZstdEncoder::new(BufReader::new(reader))).chain(failure),
+        ),
+    };
+
+    let today = chrono::Local::now();
+    let filename = format!("{}_files.{}", today.format("%d.%m.%y"), format.extension());
+
+    match Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", format.content_type())
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .body(body)
+    {
+        Ok(response) => response,
+        Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Response error"),
+    }
+}
+
+fn write_tar(
+    writer: impl Write,
+    dir: &std::path::Path,
+    files: &[FileEntry],
+) -> std::io::Result<()> {
+    let mut tar = tar::Builder::new(writer);
+
+    for entry in files {
+        // Removed since it was indexed, skip it like the ZIP build does
+        let Ok(file) = std::fs::File::open(dir.join(&entry.name)) else {
+            continue;
+        };
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(entry.size);
+        header.set_mode(0o644);
+        header.set_mtime(
+            entry
+                .modified
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+
+        // The header promises the indexed size, so a file that changed since
+        // is cut or zero-padded to it rather than corrupting the stream
+        let data = file
+            .take(entry.size)
+            .chain(std::io::repeat(0))
+            .take(entry.size);
+        tar.append_data(&mut header, &entry.name, data)?;
+    }
+
+    tar.into_inner()?.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_index::ShareIndex;
+    use async_compression::tokio::bufread::ZstdDecoder;
+    use axum::body::to_bytes;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn tarballs_decode_to_the_share_files() {
+        let dir = std::env::temp_dir().join(format!("photo4share-tar-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let large: Vec<u8> = (0..PIPE_CAPACITY * 2 + 3)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        let expected = [
+            ("a.jpg", large.clone()),
+            ("b.txt", b"notes".to_vec()),
+            ("empty.raw", Vec::new()),
+            ("Фото 1.jpg", b"cyrillic".to_vec()),
+        ];
+        for (name, contents) in &expected {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+        let index = ShareIndex::load(&dir).await.unwrap();
+
+        for format in [TarFormat::Tar, TarFormat::TarZstd] {
+            let response = serve_tar(dir.clone(), index.files(), format);
+            assert_eq!(
+                response.headers()["content-type"],
+                format.content_type(),
+                "{:?}",
+                format
+            );
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let tarball = match format {
+                TarFormat::Tar => body.to_vec(),
+                TarFormat::TarZstd => {
+                    let mut decoded = Vec::new();
+                    ZstdDecoder::new(&body[..])
+                        .read_to_end(&mut decoded)
+                        .await
+                        .unwrap();
+                    decoded
+                }
+            };
+
+            let mut archive = tar::Archive::new(&tarball[..]);
+            let mut entries = Vec::new();
+            for entry in archive.entries().unwrap() {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().to_string();
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents).unwrap();
+                entries.push((name, contents));
+            }
+            let names: Vec<&str> = entries.iter().map(|(n, _)| n.as_str()).collect();
+            let files = index.files();
+            let indexed: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+            assert_eq!(names, indexed, "{:?}", format);
+            for (name, contents) in &expected {
+                let found = entries.iter().find(|(n, _)| n == name).unwrap();
+                assert_eq!(&found.1, contents, "{} in {:?}", name, format);
+            }
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_writes_error_the_body() {
+        let dir = std::env::temp_dir().join(format!("photo4share-tar-fail-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a.jpg"), b"photo").unwrap();
+        let mut files = ShareIndex::load(&dir).await.unwrap().files().to_vec();
+        // Opening a directory works, reading it doesn't
+        let mut unreadable = files[0].clone();
+        unreadable.name = "sub".to_string();
+        files.push(unreadable);
+        let files = Arc::new(files);
+
+        for format in [TarFormat::Tar, TarFormat::TarZstd] {
+            let response = serve_tar(dir.clone(), files.clone(), format);
+            assert!(
+                to_bytes(response.into_body(), usize::MAX).await.is_err(),
+                "{:?}",
+                format
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
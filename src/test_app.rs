@@ -0,0 +1,226 @@
+//! The whole router serving one share from a scratch directory, for tests
+//! that go through HTTP like a client would.
+
+use crate::activity::ActivityLog;
+use crate::auth;
+use crate::auth::hash_key;
+use crate::build_router;
+use crate::config::ActivitySettings;
+use crate::config::CacheSettings;
+use crate::config::Config;
+use crate::file_index::FileIndexes;
+use crate::models::AppState;
+use crate::models::Permission;
+use crate::models::Share;
+use crate::models::ShareKey;
+use crate::previews::PreviewRenderer;
+use crate::signed_links::UsedLinks;
+use crate::upload_utils::UploadQuotas;
+use crate::webhooks::WebhookOutbox;
+use crate::zip_builder::ZipBuilder;
+use crate::zip_cache::ActiveArchives;
+use crate::zip_utils::ZipOptions;
+use arc_swap::ArcSwap;
+use axum::Router;
+use axum::body::Body;
+use axum::body::to_bytes;
+use axum::http::Method;
+use axum::http::Request;
+use axum::http::header;
+use axum::response::Response;
+use serde_json::Value;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+
+/// Key of the share's owner, who may do everything.
+pub const KEY: &str = "the owner's share key";
+/// Key of the guests, who may only look.
+pub const VIEW_KEY: &str = "the guests' view-only key";
+pub const ADMIN_KEY: &str = "the studio's admin key";
+pub const LINK_SECRET: &str = "a link secret that is long enough";
+pub const SHARE: &str = "default";
+/// Name used for the file most tests put in the share.
+pub const FILE: &str = "photo.jpg";
+
+/// A router for the share, which is removed with its directory on drop.
+pub struct TestApp {
+    pub dir: PathBuf,
+    pub router: Router,
+}
+
+pub struct TestAppBuilder {
+    dir: PathBuf,
+    share: Share,
+}
+
+impl TestApp {
+    /// Starts a share in an empty directory named after `name`.
+    pub fn builder(name: &str) -> TestAppBuilder {
+        let dir = std::env::temp_dir().join(format!("photo4share-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TestAppBuilder {
+            share: test_share(&dir),
+            dir,
+        }
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        self.dir.join(".state")
+    }
+
+    pub async fn request(&self, request: Request<Body>) -> Response {
+        self.router.clone().oneshot(request).await.unwrap()
+    }
+
+    /// Sends `key`, if any, as a bearer token like API clients do.
+    pub async fn send(&self, method: Method, uri: &str, key: Option<&str>) -> Response {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(key) = key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", key));
+        }
+        self.request(request.body(Body::empty()).unwrap()).await
+    }
+
+    /// Sends `cookie` like a browser does.
+    pub async fn get_with_cookie(&self, uri: &str, cookie: &str) -> Response {
+        let request = Request::builder()
+            .uri(uri)
+            .header(header::COOKIE, cookie)
+            .body(Body::empty())
+            .unwrap();
+        self.request(request).await
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl TestAppBuilder {
+    pub fn file(self, name: &str, contents: impl AsRef<[u8]>) -> Self {
+        std::fs::write(self.dir.join(name), contents).unwrap();
+        self
+    }
+
+    pub fn share(mut self, edit: impl FnOnce(&mut Share)) -> Self {
+        edit(&mut self.share);
+        self
+    }
+
+    pub fn build(self) -> TestApp {
+        let state_dir = self.dir.join(".state");
+        let config = Config {
+            shares: vec![Arc::new(self.share)],
+            listen: Vec::new(),
+            unix_socket_mode: None,
+            shutdown_timeout: Duration::from_secs(1),
+            tls: None,
+            cache: CacheSettings {
+                max_total_bytes: None,
+                max_age: None,
+                keep_latest_only: false,
+                eviction_interval: Duration::from_secs(60),
+                prebuild: false,
+            },
+            link_secret: Some(LINK_SECRET.to_string()),
+            public_url: None,
+            admin_key_hash: Some(hash_key(ADMIN_KEY)),
+            state_dir: state_dir.clone(),
+            activity: ActivitySettings {
+                enabled: true,
+                anonymize: false,
+            },
+            webhooks: Vec::new(),
+            email: None,
+        };
+        let router = build_router(AppState {
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            indexes: Arc::new(FileIndexes::default()),
+            zip_builder: Arc::new(ZipBuilder::new(Arc::new(ActiveArchives::default()))),
+            used_links: Arc::new(UsedLinks::new(&state_dir)),
+            activity: Arc::new(ActivityLog::default()),
+            webhooks: Arc::new(WebhookOutbox::default()),
+            uploads: Arc::new(UploadQuotas::default()),
+            previews: Arc::new(PreviewRenderer::default()),
+        });
+        TestApp {
+            dir: self.dir,
+            router,
+        }
+    }
+}
+
+/// The share in `dir` with the owner's and the guests' keys.
+pub fn test_share(dir: &Path) -> Share {
+    Share {
+        name: SHARE.to_string(),
+        dir: dir.to_path_buf(),
+        keys: vec![
+            ShareKey {
+                name: "owner".to_string(),
+                key_hash: hash_key(KEY),
+                role: "full".to_string(),
+                permissions: Permission::ALL.to_vec(),
+            },
+            ShareKey {
+                name: "guests".to_string(),
+                key_hash: hash_key(VIEW_KEY),
+                role: "view".to_string(),
+                permissions: vec![Permission::List, Permission::Preview],
+            },
+        ],
+        greet: String::new(),
+        zip: ZipOptions::default(),
+        upload: None,
+        email: None,
+        expires: None,
+    }
+}
+
+/// The cookie a login with `key` sets.
+pub fn key_cookie(key: &str) -> String {
+    format!("{}={}", auth::KEY_COOKIE, key)
+}
+
+pub async fn json(response: Response) -> Value {
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// The CSRF cookie a form page set and the token it carries.
+pub async fn csrf_of(response: Response) -> (String, String) {
+    let cookie = response.headers()[header::SET_COOKIE]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+    let page = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let page = String::from_utf8(page.to_vec()).unwrap();
+    let token = page
+        .split("name=\"csrf_token\" value=\"")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap()
+        .to_string();
+    (cookie, token)
+}
+
+/// Waits for something a response body triggers once it is sent, like a
+/// log line or a used link's marker.
+pub async fn eventually(mut done: impl AsyncFnMut() -> bool) -> bool {
+    for _ in 0..100 {
+        if done().await {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    false
+}
@@ -0,0 +1,314 @@
+use crate::models::UploadConfig;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::fs;
+use tokio::fs::OpenOptions;
+use tokio::io;
+use tokio::process::Command;
+use tracing::{error, info, warn};
+
+/// Uploads land in a hidden subfolder, so `should_include_file` keeps them
+/// out of listings, ZIPs and direct downloads.
+pub fn inbox_dir(share_dir: &Path) -> PathBuf {
+    share_dir.join(".inbox")
+}
+
+/// Total size of the regular files currently stored in the inbox.
+pub async fn inbox_usage(inbox: &Path) -> io::Result<u64> {
+    let mut entries = match fs::read_dir(inbox).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut total = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let meta = entry.metadata().await?;
+        if meta.is_file() {
+            total += meta.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Inbox usage shared by the uploads running at the same time, so together
+/// they cannot go over the quota.
+#[derive(Default)]
+pub struct UploadQuotas {
+    inboxes: Mutex<HashMap<PathBuf, InboxUsage>>,
+}
+
+#[derive(Default)]
+struct InboxUsage {
+    /// Bytes stored plus bytes reserved by running uploads.
+    used: u64,
+    /// Running uploads; `used` is read from disk again once there are none.
+    active: usize,
+    /// Counts finished uploads, so a scan that raced one is not trusted.
+    finished: u64,
+}
+
+impl UploadQuotas {
+    /// Starts an upload into `inbox`, whose bytes are then reserved chunk by
+    /// chunk with `Reservation::grow`.
+    pub async fn begin(self: &Arc<Self>, inbox: &Path) -> io::Result<Reservation> {
+        loop {
+            let finished = {
+                let mut inboxes = self.inboxes.lock().unwrap();
+                let usage = inboxes.entry(inbox.to_path_buf()).or_default();
+                if usage.active > 0 {
+                    usage.active += 1;
+                    return Ok(self.reservation(inbox));
+                }
+                usage.finished
+            };
+
+            // Files may have been removed by hand since the last upload
+            let stored = inbox_usage(inbox).await?;
+
+            let mut inboxes = self.inboxes.lock().unwrap();
+            let usage = inboxes.entry(inbox.to_path_buf()).or_default();
+            if usage.active > 0 || usage.finished == finished {
+                if usage.active == 0 {
+                    usage.used = stored;
+                }
+                usage.active += 1;
+                return Ok(self.reservation(inbox));
+            }
+        }
+    }
+
+    fn reservation(self: &Arc<Self>, inbox: &Path) -> Reservation {
+        Reservation {
+            quotas: self.clone(),
+            inbox: inbox.to_path_buf(),
+            reserved: 0,
+            kept: false,
+        }
+    }
+}
+
+/// Bytes of one upload counted against its inbox's quota. Released when
+/// dropped, unless the upload was kept.
+pub struct Reservation {
+    quotas: Arc<UploadQuotas>,
+    inbox: PathBuf,
+    reserved: u64,
+    kept: bool,
+}
+
+impl Reservation {
+    /// Reserves `bytes` more, or returns false if that would exceed `quota`.
+    pub fn grow(&mut self, bytes: u64, quota: u64) -> bool {
+        let mut inboxes = self.quotas.inboxes.lock().unwrap();
+        let usage = inboxes.entry(self.inbox.clone()).or_default();
+        match usage.used.checked_add(bytes) {
+            Some(used) if used <= quota => {
+                usage.used = used;
+                self.reserved += bytes;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The upload was stored, so its bytes stay counted.
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut inboxes = self.quotas.inboxes.lock().unwrap();
+        let usage = inboxes.entry(self.inbox.clone()).or_default();
+        if !self.kept {
+            usage.used = usage.used.saturating_sub(self.reserved);
+        }
+        usage.active -= 1;
+        usage.finished += 1;
+    }
+}
+
+/// Reduces a client-supplied filename to a safe, single path component.
+pub fn sanitize_upload_name(name: &str) -> Option<String> {
+    // Browsers may send full paths (old IE) or folder-relative ones
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains("..")
+        || name.chars().any(|c| c.is_control())
+    {
+        return None;
+    }
+
+    Some(name.to_string())
+}
+
+pub fn is_allowed_extension(config: &UploadConfig, name: &str) -> bool {
+    if config.allowed_extensions.is_empty() {
+        return true;
+    }
+
+    match Path::new(name).extension().and_then(|e| e.to_str()) {
+        Some(ext) => config
+            .allowed_extensions
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+/// Claims a name in the inbox that no earlier or concurrent upload has,
+/// as an empty file the upload is then renamed over.
+pub async fn claim_destination(inbox: &Path, name: &str) -> io::Result<PathBuf> {
+    let stamp = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let mut candidate = inbox.join(format!("{}_{}", stamp, name));
+    let mut counter = 1;
+    loop {
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+            .await
+        {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                candidate = inbox.join(format!("{}_{}_{}", stamp, counter, name));
+                counter += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Runs the configured notification command in the background.
+pub fn notify_upload(config: &UploadConfig, path: &Path, size: u64) {
+    let Some(command) = config.notify_command.clone() else {
+        return;
+    };
+    let path = path.to_path_buf();
+
+    tokio::spawn(async move {
+        let result = Command::new(&command)
+            .arg(&path)
+            .env("PHOTO4SHARE_UPLOAD_PATH", &path)
+            .env("PHOTO4SHARE_UPLOAD_SIZE", size.to_string())
+            .status()
+            .await;
+
+        match result {
+            Ok(status) if status.success() => {
+                info!("Upload hook finished for {:?}", path);
+            }
+            Ok(status) => warn!("Upload hook exited with {} for {:?}", status, path),
+            Err(e) => error!("Failed to run upload hook {}: {}", command, e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn temp_inbox(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "photo4share-upload-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn unsafe_names_are_rejected() {
+        for name in [
+            "",
+            "  ",
+            ".htaccess",
+            "folder/",
+            "a..b.jpg",
+            "bell\u{7}.jpg",
+            "C:\\photos\\..",
+        ] {
+            assert_eq!(sanitize_upload_name(name), None, "{:?}", name);
+        }
+        assert_eq!(
+            sanitize_upload_name("C:\\Users\\me\\IMG_1.jpg").as_deref(),
+            Some("IMG_1.jpg")
+        );
+        assert_eq!(
+            sanitize_upload_name("holiday/IMG 2.JPG ").as_deref(),
+            Some("IMG 2.JPG")
+        );
+    }
+
+    #[test]
+    fn extensions_are_matched_case_insensitively() {
+        let config = UploadConfig {
+            quota_bytes: 100,
+            max_file_bytes: 100,
+            allowed_extensions: vec!["jpg".to_string(), "cr2".to_string()],
+            notify_command: None,
+        };
+        assert!(is_allowed_extension(&config, "a.JPG"));
+        assert!(is_allowed_extension(&config, "raw.cr2"));
+        assert!(!is_allowed_extension(&config, "a.jpg.exe"));
+        assert!(!is_allowed_extension(&config, "jpg"));
+    }
+
+    #[tokio::test]
+    async fn clashing_names_get_their_own_destination() {
+        let inbox = temp_inbox("clash");
+        let claims = (0..16).map(|_| {
+            let inbox = inbox.clone();
+            tokio::spawn(async move { claim_destination(&inbox, "IMG_1.jpg").await.unwrap() })
+        });
+        let mut paths = HashSet::new();
+        for claim in claims {
+            let path = claim.await.unwrap();
+            assert!(path.to_string_lossy().ends_with("IMG_1.jpg"));
+            assert!(paths.insert(path));
+        }
+        assert_eq!(std::fs::read_dir(&inbox).unwrap().count(), 16);
+        std::fs::remove_dir_all(&inbox).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_uploads_share_the_quota() {
+        let inbox = temp_inbox("quota");
+        std::fs::write(inbox.join("stored.jpg"), [0u8; 40]).unwrap();
+        let quotas = Arc::new(UploadQuotas::default());
+
+        let mut first = quotas.begin(&inbox).await.unwrap();
+        let mut second = quotas.begin(&inbox).await.unwrap();
+        assert!(first.grow(30, 100));
+        assert!(second.grow(30, 100));
+        assert!(!first.grow(1, 100));
+
+        // A broken-off upload gives its bytes back, a stored one keeps them
+        drop(second);
+        assert!(first.grow(20, 100));
+        std::fs::write(inbox.join("first.jpg"), [0u8; 50]).unwrap();
+        first.keep();
+        let mut third = quotas.begin(&inbox).await.unwrap();
+        assert!(third.grow(10, 100));
+        assert!(!third.grow(1, 100));
+        drop(third);
+
+        // Without uploads running, usage is read from disk again
+        std::fs::remove_file(inbox.join("stored.jpg")).unwrap();
+        let mut fourth = quotas.begin(&inbox).await.unwrap();
+        assert!(fourth.grow(50, 100));
+        assert!(!fourth.grow(1, 100));
+
+        std::fs::remove_dir_all(&inbox).unwrap();
+    }
+}
@@ -0,0 +1,855 @@
+use crate::activity::ActivityEvent;
+use crate::activity::EventKind;
+use crate::models::AppState;
+use crate::models::Share;
+use crate::reload::SharedConfig;
+use crate::tls;
+use axum::body::Bytes;
+use axum::http::Request;
+use axum::http::StatusCode;
+use axum::http::Uri;
+use axum::http::header;
+use chrono::Utc;
+use hmac::Hmac;
+use hmac::Mac;
+use http_body_util::Full;
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Deliveries waiting to be sent, one JSON file each, under a share's dir.
+const OUTBOX_DIR: &str = ".webhooks/outbox";
+/// Deliveries given up on, kept for a look by hand.
+const FAILED_DIR: &str = ".webhooks/failed";
+/// Created by the first login, so later ones aren't reported as first.
+const OPENED_MARKER: &str = ".webhooks/opened";
+
+/// Header with `sha256=` and the hex HMAC of the body under the secret.
+pub const SIGNATURE_HEADER: &str = "x-photo4share-signature";
+const EVENT_HEADER: &str = "x-photo4share-event";
+const DELIVERY_HEADER: &str = "x-photo4share-delivery";
+
+const MAX_ATTEMPTS: u32 = 12;
+const FIRST_RETRY: Duration = Duration::from_secs(30);
+const MAX_RETRY: Duration = Duration::from_secs(3600);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Outboxes are rescanned this often even if nothing new was queued.
+const IDLE_POLL: Duration = Duration::from_secs(600);
+
+/// Something a webhook can be told about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WebhookEvent {
+    Login,
+    /// A single file went out to the last byte.
+    DownloadComplete,
+    /// An archive or one of its parts went out to the last byte.
+    ArchiveComplete,
+    SelectionSubmitted,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 4] = [
+        WebhookEvent::Login,
+        WebhookEvent::DownloadComplete,
+        WebhookEvent::ArchiveComplete,
+        WebhookEvent::SelectionSubmitted,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::Login => "login",
+            WebhookEvent::DownloadComplete => "download.complete",
+            WebhookEvent::ArchiveComplete => "archive.complete",
+            WebhookEvent::SelectionSubmitted => "selection.submitted",
+        }
+    }
+
+    fn of(kind: &EventKind) -> Option<Self> {
+        match kind {
+            EventKind::Login { .. } => Some(WebhookEvent::Login),
+            EventKind::FileDownload { complete: true, .. } => Some(WebhookEvent::DownloadComplete),
+            EventKind::ArchiveDownload { complete: true, .. } => {
+                Some(WebhookEvent::ArchiveComplete)
+            }
+            EventKind::SelectionSubmitted { .. } => Some(WebhookEvent::SelectionSubmitted),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        WebhookEvent::ALL
+            .into_iter()
+            .find(|e| e.as_str() == s)
+            .ok_or_else(|| {
+                format!(
+                    "unknown event '{}', expected one of: {}",
+                    s,
+                    WebhookEvent::ALL.map(WebhookEvent::as_str).join(", ")
+                )
+            })
+    }
+}
+
+/// An endpoint that is POSTed signed JSON about gallery events.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    pub url: Uri,
+    /// Key of the `x-photo4share-signature` HMAC.
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    /// Names of the shares reported on, `None` for all of them.
+    pub shares: Option<Vec<String>>,
+}
+
+impl Webhook {
+    fn wants(&self, event: WebhookEvent, share: &str) -> bool {
+        self.events.contains(&event)
+            && self
+                .shares
+                .as_ref()
+                .is_none_or(|shares| shares.iter().any(|s| s == share))
+    }
+}
+
+/// A queued POST. The secret is looked up in the current configuration
+/// when sending, so none ends up in a share's directory.
+#[derive(Debug, Serialize, Deserialize)]
+struct Delivery {
+    url: String,
+    event: String,
+    body: String,
+    attempts: u32,
+    /// Unix time in seconds.
+    next_attempt: i64,
+    last_error: Option<String>,
+}
+
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// Wakes the sender when something was queued.
+#[derive(Default)]
+pub struct WebhookOutbox {
+    wake: Notify,
+}
+
+/// Queues `event` for every webhook that wants it. Like the activity log
+/// this is best effort and never fails the request it came from.
+pub async fn enqueue(state: &AppState, share: &Share, event: &ActivityEvent) {
+    let config = state.config.load();
+    if queue(&share.dir, &share.name, &config.webhooks, event).await > 0 {
+        state.webhooks.wake.notify_one();
+    }
+}
+
+async fn queue(dir: &Path, share: &str, webhooks: &[Webhook], event: &ActivityEvent) -> usize {
+    let Some(kind) = WebhookEvent::of(&event.kind) else {
+        return 0;
+    };
+    let targets: Vec<&Webhook> = webhooks.iter().filter(|w| w.wants(kind, share)).collect();
+    if targets.is_empty() {
+        return 0;
+    }
+
+    let data = match &event.kind {
+        EventKind::Login { via } => json!({
+            "via": via,
+            "first": first_login(dir).await,
+        }),
+        EventKind::FileDownload { file, bytes, .. } => json!({ "file": file, "bytes": bytes }),
+        EventKind::ArchiveDownload {
+            format,
+            part,
+            bytes,
+            ..
+        } => json!({ "format": format, "part": part, "bytes": bytes }),
+        EventKind::SelectionSubmitted { files, note } => json!({ "files": files, "note": note }),
+        EventKind::LoginFailed { .. } => return 0,
+    };
+
+    let mut queued = 0;
+    for webhook in targets {
+        // Sorts in the order queued, which is the order of sending
+        let id = format!(
+            "{}-{:08x}",
+            Utc::now().timestamp_millis(),
+            SEQUENCE.fetch_add(1, Ordering::Relaxed)
+        );
+        let payload = json!({
+            "id": id,
+            "event": kind.as_str(),
+            "time": event.time,
+            "share": share,
+            "key": event.key,
+            "data": data,
+        });
+        let delivery = Delivery {
+            url: webhook.url.to_string(),
+            event: kind.as_str().to_string(),
+            body: payload.to_string(),
+            attempts: 0,
+            next_attempt: 0,
+            last_error: None,
+        };
+        match write_delivery(&dir.join(OUTBOX_DIR), &id, &delivery).await {
+            Ok(()) => queued += 1,
+            Err(e) => warn!("Cannot queue webhook {} in {:?}: {}", kind, dir, e),
+        }
+    }
+    queued
+}
+
+/// True the first time a share is logged into since webhooks were set up.
+async fn first_login(dir: &Path) -> bool {
+    let marker = dir.join(OPENED_MARKER);
+    if let Some(parent) = marker.parent() {
+        let _ = fs::create_dir_all(parent).await;
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&marker)
+        .await
+        .is_ok()
+}
+
+/// Written aside and renamed, so the sender never reads half a file.
+async fn write_delivery(outbox: &Path, id: &str, delivery: &Delivery) -> std::io::Result<()> {
+    fs::create_dir_all(outbox).await?;
+    let temp = outbox.join(format!(".{}.tmp", id));
+    let json = serde_json::to_vec(delivery).map_err(std::io::Error::other)?;
+    fs::write(&temp, json).await?;
+    fs::rename(&temp, outbox.join(format!("{}.json", id))).await
+}
+
+/// Sends everything queued until shutdown, retrying failures with
+/// exponential backoff. Deliveries left over from a previous run go first.
+pub fn spawn_sender(config: SharedConfig, outbox: Arc<WebhookOutbox>, shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        loop {
+            let snapshot = config.load_full();
+            let dirs: Vec<PathBuf> = snapshot.shares.iter().map(|s| s.dir.clone()).collect();
+            let now = Utc::now().timestamp();
+            let wait = match deliver_due(&snapshot.webhooks, &dirs, now).await {
+                Some(next) => Duration::from_secs(next.saturating_sub(now).max(1) as u64),
+                None => IDLE_POLL,
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait.min(IDLE_POLL)) => {}
+                _ = outbox.wake.notified() => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+    });
+}
+
+/// A delivery read from an outbox, with where it lives.
+struct Pending {
+    dir: PathBuf,
+    name: String,
+    delivery: Delivery,
+}
+
+/// Attempts every delivery due at `now`, oldest first. Each endpoint is sent
+/// to on its own, so one that is down only holds up its own deliveries.
+/// Returns when the next of those still waiting is due.
+async fn deliver_due(webhooks: &[Webhook], dirs: &[PathBuf], now: i64) -> Option<i64> {
+    let mut next: Option<i64> = None;
+    let mut due: BTreeMap<String, Vec<Pending>> = BTreeMap::new();
+    // Endpoints backing off after a failure get nothing newer either
+    let mut backing_off = HashSet::new();
+    for dir in dirs {
+        let outbox = dir.join(OUTBOX_DIR);
+        let mut names = match pending_deliveries(&outbox).await {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Cannot read webhook outbox {:?}: {}", outbox, e);
+                continue;
+            }
+        };
+        names.sort();
+
+        for name in names {
+            let path = outbox.join(&name);
+            let delivery: Delivery = match fs::read(&path).await {
+                Ok(json) => match serde_json::from_slice(&json) {
+                    Ok(delivery) => delivery,
+                    Err(e) => {
+                        warn!("Dropping unreadable webhook delivery {:?}: {}", path, e);
+                        let _ = fs::remove_file(&path).await;
+                        continue;
+                    }
+                },
+                Err(_) => continue,
+            };
+            if delivery.next_attempt > now {
+                next = earliest(next, delivery.next_attempt);
+                due.remove(&delivery.url);
+                backing_off.insert(delivery.url);
+                continue;
+            }
+            if backing_off.contains(&delivery.url) {
+                continue;
+            }
+            due.entry(delivery.url.clone()).or_default().push(Pending {
+                dir: dir.clone(),
+                name,
+                delivery,
+            });
+        }
+    }
+
+    let endpoints = due.into_iter().map(|(url, pending)| async move {
+        match webhooks.iter().find(|w| w.url.to_string() == url) {
+            Some(webhook) => deliver_to(webhook, pending, now).await,
+            None => {
+                info!(
+                    "Dropping {} webhook delivery(s) to {}, it is no longer configured",
+                    pending.len(),
+                    url
+                );
+                for p in pending {
+                    let _ = fs::remove_file(p.dir.join(OUTBOX_DIR).join(&p.name)).await;
+                }
+                None
+            }
+        }
+    });
+    for endpoint_next in futures_util::future::join_all(endpoints).await {
+        next = endpoint_next.map_or(next, |n| earliest(next, n));
+    }
+    next
+}
+
+/// Sends the due deliveries of one endpoint in order. After a failure the
+/// rest wait for its retry, rather than each timing out in turn.
+async fn deliver_to(webhook: &Webhook, pending: Vec<Pending>, now: i64) -> Option<i64> {
+    let mut retry_at = None;
+    for Pending {
+        dir,
+        name,
+        mut delivery,
+    } in pending
+    {
+        if let Some(retry_at) = retry_at {
+            return Some(retry_at);
+        }
+        let outbox = dir.join(OUTBOX_DIR);
+        let path = outbox.join(&name);
+        let id = name.trim_end_matches(".json");
+        delivery.attempts += 1;
+        match send(webhook, id, &delivery).await {
+            Ok(()) => {
+                debug!("Delivered webhook {} to {}", delivery.event, delivery.url);
+                let _ = fs::remove_file(&path).await;
+            }
+            Err(e) if delivery.attempts >= MAX_ATTEMPTS => {
+                warn!(
+                    "Giving up on webhook {} to {} after {} attempts: {}",
+                    delivery.event, delivery.url, delivery.attempts, e
+                );
+                delivery.last_error = Some(e);
+                let failed = dir.join(FAILED_DIR);
+                if write_delivery(&failed, id, &delivery).await.is_ok() {
+                    let _ = fs::remove_file(&path).await;
+                }
+            }
+            Err(e) => {
+                let delay = retry_delay(delivery.attempts);
+                warn!(
+                    "Webhook {} to {} failed, retrying in {}s: {}",
+                    delivery.event,
+                    delivery.url,
+                    delay.as_secs(),
+                    e
+                );
+                delivery.next_attempt = now + delay.as_secs() as i64;
+                delivery.last_error = Some(e);
+                if let Err(e) = write_delivery(&outbox, id, &delivery).await {
+                    warn!("Cannot update webhook delivery {:?}: {}", path, e);
+                }
+                retry_at = Some(delivery.next_attempt);
+            }
+        }
+    }
+    retry_at
+}
+
+fn earliest(next: Option<i64>, at: i64) -> Option<i64> {
+    Some(next.map_or(at, |n| n.min(at)))
+}
+
+async fn pending_deliveries(outbox: &Path) -> std::io::Result<Vec<String>> {
+    let mut entries = match fs::read_dir(outbox).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(name) = entry.file_name().to_str()
+            && name.ends_with(".json")
+            && !name.starts_with('.')
+        {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// 30 seconds after the first failure, doubling up to an hour.
+fn retry_delay(attempts: u32) -> Duration {
+    FIRST_RETRY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_RETRY)
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `body`.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256={}", hex)
+}
+
+/// POSTs the delivery once. Anything but a 2xx answer is a failure.
+async fn send(webhook: &Webhook, id: &str, delivery: &Delivery) -> Result<(), String> {
+    let request = Request::post(webhook.url.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(
+            header::USER_AGENT,
+            concat!("photo4share/", env!("CARGO_PKG_VERSION")),
+        )
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, id)
+        .header(
+            SIGNATURE_HEADER,
+            signature(&webhook.secret, delivery.body.as_bytes()),
+        )
+        .body(Full::new(Bytes::from(delivery.body.clone())))
+        .map_err(|e| e.to_string())?;
+
+    let status = tokio::time::timeout(REQUEST_TIMEOUT, post(&webhook.url, request))
+        .await
+        .map_err(|_| "timed out".to_string())??;
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(format!("answered {}", status))
+    }
+}
+
+/// One request over a fresh connection, on the hyper and rustls the server
+/// already uses. Deliveries are rare, so a pooling client like reqwest
+/// would only add dependencies. Certificates are checked against the
+/// system roots, see `tls::client_config`.
+async fn post(url: &Uri, mut request: Request<Full<Bytes>>) -> Result<StatusCode, String> {
+    let https = url.scheme_str() == Some("https");
+    let (Some(host), Some(authority)) = (url.host(), url.authority()) else {
+        return Err("URL has no host".to_string());
+    };
+    let port = url.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    // HTTP/1.1 wants the Host header, but an origin-form request line
+    let host_header = authority.as_str().parse().map_err(|_| "invalid host")?;
+    request.headers_mut().insert(header::HOST, host_header);
+    *request.uri_mut() = url
+        .path_and_query()
+        .map_or("/", |p| p.as_str())
+        .parse()
+        .map_err(|_| "invalid path")?;
+
+    let stream = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| format!("cannot connect: {}", e))?;
+    if !https {
+        return exchange(stream, request).await;
+    }
+    let connector = TlsConnector::from(tls::client_config()?);
+    let name = ServerName::try_from(host.to_string()).map_err(|e| e.to_string())?;
+    let stream = connector
+        .connect(name, stream)
+        .await
+        .map_err(|e| format!("TLS handshake failed: {}", e))?;
+    exchange(stream, request).await
+}
+
+async fn exchange<S>(stream: S, request: Request<Full<Bytes>>) -> Result<StatusCode, String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(connection);
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(response.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::hash_key;
+    use crate::models::Permission;
+    use crate::models::ShareKey;
+    use crate::test_app::FILE;
+    use crate::test_app::KEY;
+    use crate::test_app::TestApp;
+    use crate::test_app::VIEW_KEY;
+    use crate::test_app::json;
+    use axum::Router;
+    use axum::body::Body;
+    use axum::extract::State;
+    use axum::http::HeaderMap;
+    use axum::http::Method;
+    use axum::http::Request;
+    use axum::http::header;
+    use axum::routing::post;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Received requests, and how many to fail before answering 200.
+    #[derive(Clone, Default)]
+    struct StandIn {
+        received: Arc<Mutex<Vec<(HeaderMap, String)>>>,
+        failures: Arc<AtomicUsize>,
+    }
+
+    async fn receive(
+        State(stand_in): State<StandIn>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        stand_in.received.lock().unwrap().push((headers, body));
+        let failing = stand_in
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if failing {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        }
+    }
+
+    async fn start_stand_in(failures: usize) -> (StandIn, Uri) {
+        let stand_in = StandIn::default();
+        stand_in.failures.store(failures, Ordering::SeqCst);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/hooks/crm?source=test",
+            listener.local_addr().unwrap()
+        );
+        let app = Router::new()
+            .route("/hooks/crm", post(receive))
+            .with_state(stand_in.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (stand_in, url.parse().unwrap())
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "photo4share-webhooks-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn webhook(url: Uri, events: Vec<WebhookEvent>) -> Webhook {
+        Webhook {
+            url,
+            secret: "stand-in secret".to_string(),
+            events,
+            shares: None,
+        }
+    }
+
+    fn event(kind: EventKind) -> ActivityEvent {
+        ActivityEvent {
+            time: "2025-06-01T12:00:00Z".to_string(),
+            kind,
+            key: Some("owner".to_string()),
+            ip: None,
+            user_agent: None,
+        }
+    }
+
+    fn login() -> ActivityEvent {
+        event(EventKind::Login {
+            via: "form".to_string(),
+        })
+    }
+
+    fn outbox_len(dir: &Path, sub: &str) -> usize {
+        std::fs::read_dir(dir.join(sub)).map_or(0, |d| d.count())
+    }
+
+    #[tokio::test]
+    async fn signed_payloads_are_retried_until_accepted() {
+        let (stand_in, url) = start_stand_in(1).await;
+        let dir = temp_dir("retry");
+        let webhooks = [webhook(url, WebhookEvent::ALL.to_vec())];
+        let dirs = [dir.clone()];
+
+        assert_eq!(queue(&dir, "smith", &webhooks, &login()).await, 1);
+        let next = deliver_due(&webhooks, &dirs, 1000).await;
+        assert_eq!(next, Some(1000 + FIRST_RETRY.as_secs() as i64));
+        assert_eq!(outbox_len(&dir, OUTBOX_DIR), 1);
+
+        // Not due yet, so nothing is sent
+        assert_eq!(deliver_due(&webhooks, &dirs, 1001).await, next);
+        assert_eq!(stand_in.received.lock().unwrap().len(), 1);
+
+        assert_eq!(deliver_due(&webhooks, &dirs, next.unwrap()).await, None);
+        assert_eq!(outbox_len(&dir, OUTBOX_DIR), 0);
+
+        let received = stand_in.received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let (headers, body) = &received[1];
+        assert_eq!(headers[EVENT_HEADER], "login");
+        assert_eq!(
+            headers[SIGNATURE_HEADER],
+            signature("stand-in secret", body.as_bytes())
+        );
+        assert_eq!(received[0].1, *body, "a retry resends the same payload");
+        let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(payload["share"], "smith");
+        assert_eq!(payload["key"], "owner");
+        assert_eq!(payload["data"]["via"], "form");
+        assert_eq!(payload["data"]["first"], true);
+        assert_eq!(payload["id"], headers[DELIVERY_HEADER].to_str().unwrap());
+        drop(received);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_wanted_and_completed_events_are_queued() {
+        let (stand_in, url) = start_stand_in(0).await;
+        let dir = temp_dir("filter");
+        let mut hook = webhook(
+            url,
+            vec![WebhookEvent::Login, WebhookEvent::DownloadComplete],
+        );
+        hook.shares = Some(vec!["smith".to_string()]);
+        let webhooks = [hook];
+
+        let download = |complete| {
+            event(EventKind::FileDownload {
+                file: "a.jpg".to_string(),
+                version: None,
+                bytes: 10,
+                complete,
+            })
+        };
+        assert_eq!(queue(&dir, "smith", &webhooks, &download(false)).await, 0);
+        assert_eq!(queue(&dir, "jones", &webhooks, &download(true)).await, 0);
+        let archive = event(EventKind::ArchiveDownload {
+            format: "zip".to_string(),
+            part: None,
+            contents: Some("v1".to_string()),
+            parts: 1,
+            bytes: 10,
+            complete: true,
+        });
+        assert_eq!(queue(&dir, "smith", &webhooks, &archive).await, 0);
+        assert_eq!(queue(&dir, "smith", &webhooks, &download(true)).await, 1);
+        assert_eq!(queue(&dir, "smith", &webhooks, &login()).await, 1);
+        assert_eq!(queue(&dir, "smith", &webhooks, &login()).await, 1);
+
+        deliver_due(
+            &webhooks,
+            std::slice::from_ref(&dir),
+            Utc::now().timestamp(),
+        )
+        .await;
+        let received = stand_in.received.lock().unwrap();
+        let payloads: Vec<serde_json::Value> = received
+            .iter()
+            .map(|(_, body)| serde_json::from_str(body).unwrap())
+            .collect();
+        assert_eq!(payloads.len(), 3);
+        assert_eq!(payloads[0]["event"], "download.complete");
+        assert_eq!(payloads[0]["data"]["file"], "a.jpg");
+        assert_eq!(payloads[1]["data"]["first"], true);
+        assert_eq!(payloads[2]["data"]["first"], false);
+        drop(received);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_given_up_after_the_last_attempt() {
+        let (stand_in, url) = start_stand_in(usize::MAX).await;
+        let dir = temp_dir("give-up");
+        let webhooks = [webhook(url, vec![WebhookEvent::Login])];
+        let dirs = [dir.clone()];
+
+        queue(&dir, "smith", &webhooks, &login()).await;
+        let mut now = 0;
+        while let Some(next) = deliver_due(&webhooks, &dirs, now).await {
+            now = next;
+        }
+        assert_eq!(
+            stand_in.received.lock().unwrap().len(),
+            MAX_ATTEMPTS as usize
+        );
+        assert_eq!(outbox_len(&dir, OUTBOX_DIR), 0);
+        assert_eq!(outbox_len(&dir, FAILED_DIR), 1);
+
+        // Deliveries of a webhook removed from the config are dropped
+        queue(&dir, "smith", &webhooks, &login()).await;
+        assert_eq!(deliver_due(&[], &dirs, now).await, None);
+        assert_eq!(outbox_len(&dir, OUTBOX_DIR), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_failing_endpoint_holds_up_only_its_own_deliveries() {
+        let (dead, dead_url) = start_stand_in(usize::MAX).await;
+        let (live, live_url) = start_stand_in(0).await;
+        let dir = temp_dir("endpoints");
+        let webhooks = [
+            webhook(dead_url, vec![WebhookEvent::Login]),
+            webhook(live_url, vec![WebhookEvent::Login]),
+        ];
+        let dirs = [dir.clone()];
+        queue(&dir, "smith", &webhooks, &login()).await;
+        queue(&dir, "smith", &webhooks, &login()).await;
+
+        let next = deliver_due(&webhooks, &dirs, 1000).await;
+        assert_eq!(next, Some(1000 + FIRST_RETRY.as_secs() as i64));
+        assert_eq!(live.received.lock().unwrap().len(), 2);
+        // The second delivery isn't tried after the first one failed...
+        assert_eq!(dead.received.lock().unwrap().len(), 1);
+        assert_eq!(outbox_len(&dir, OUTBOX_DIR), 2);
+
+        // ...nor on a pass before the first one's retry
+        queue(&dir, "smith", &webhooks[1..], &login()).await;
+        assert_eq!(deliver_due(&webhooks, &dirs, 1001).await, next);
+        assert_eq!(live.received.lock().unwrap().len(), 3);
+        assert_eq!(dead.received.lock().unwrap().len(), 1);
+
+        deliver_due(&webhooks, &dirs, next.unwrap()).await;
+        assert_eq!(dead.received.lock().unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn retries_back_off_up_to_an_hour() {
+        assert_eq!(retry_delay(1), Duration::from_secs(30));
+        assert_eq!(retry_delay(2), Duration::from_secs(60));
+        assert_eq!(retry_delay(5), Duration::from_secs(480));
+        assert_eq!(retry_delay(MAX_ATTEMPTS), MAX_RETRY);
+    }
+
+    #[tokio::test]
+    async fn every_key_keeps_its_own_selection() {
+        const FAMILY_KEY: &str = "the family's picking key";
+        let app = TestApp::builder("select")
+            .file(FILE, b"photo")
+            .file("other.jpg", b"photo")
+            .share(|share| {
+                share.keys.push(ShareKey {
+                    name: "family".to_string(),
+                    key_hash: hash_key(FAMILY_KEY),
+                    role: "picker".to_string(),
+                    permissions: vec![Permission::List, Permission::Select],
+                })
+            })
+            .build();
+        let submit = async |files: &[&str], key: &str| {
+            let body = serde_json::json!({ "files": files }).to_string();
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/api/v1/selection")
+                .header(header::AUTHORIZATION, format!("Bearer {}", key))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            app.request(request).await
+        };
+
+        let response = submit(&[FILE], KEY).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json(response).await["key"], "owner");
+        let response = submit(&["other.jpg"], FAMILY_KEY).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = submit(&["other.jpg", FILE], KEY).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let selections = app.dir.join(".selections");
+        let stored = |key: &str| -> serde_json::Value {
+            let path = selections.join(format!("{}.json", key));
+            serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+        };
+        assert_eq!(
+            stored("owner")["files"],
+            serde_json::json!(["other.jpg", FILE])
+        );
+        assert_eq!(stored("family")["files"], serde_json::json!(["other.jpg"]));
+
+        let response = submit(&["missing.jpg"], FAMILY_KEY).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(stored("family")["files"], serde_json::json!(["other.jpg"]));
+        let response = submit(&[FILE], VIEW_KEY).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!selections.join("guests.json").exists());
+
+        // Submissions of one key at once each replace the file whole
+        let picks: [&[&str]; 2] = [&[FILE], &["other.jpg"]];
+        let responses =
+            futures_util::future::join_all((0..8).map(|i| submit(picks[i % 2], KEY))).await;
+        assert!(responses.iter().all(|r| r.status() == StatusCode::OK));
+        let files = stored("owner")["files"].clone();
+        assert!(picks.iter().any(|pick| files == serde_json::json!(pick)));
+        let leftovers = std::fs::read_dir(&selections)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("tmp".as_ref()))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+}
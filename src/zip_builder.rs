@@ -0,0 +1,369 @@
+use crate::file_index::FileIndexes;
+use crate::file_index::ShareIndex;
+use crate::reload::SharedConfig;
+use crate::zip_cache::ActiveArchives;
+use crate::zip_cache::ArchiveLease;
+use crate::zip_cache::touch_archive;
+use crate::zip_utils::ZipOptions;
+use crate::zip_utils::build_zip;
+use crate::zip_utils::cached_zip_path;
+use crate::zip_utils::part_files;
+use crate::zip_utils::zip_part_numbers;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{error, info, warn};
+
+/// Archives are built one after another so downloads keep most of the disk
+/// bandwidth.
+const MAX_CONCURRENT_BUILDS: usize = 1;
+/// A share must stay unchanged this long before it is rebuilt, so copying a
+/// whole shoot into it triggers one build instead of hundreds.
+const PREBUILD_QUIET_PERIOD: Duration = Duration::from_secs(30);
+/// How often the prebuilder looks for shares added by a config reload.
+const SHARE_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct BuildProgress {
+    pub done_bytes: Arc<AtomicU64>,
+    pub total_bytes: u64,
+    state: watch::Sender<BuildState>,
+}
+
+#[derive(Clone, Debug)]
+pub enum BuildState {
+    Queued,
+    Running,
+    Done,
+    Failed(&'static str),
+}
+
+impl BuildProgress {
+    pub fn percent(&self) -> u8 {
+        if self.total_bytes == 0 {
+            return 0;
+        }
+        let done = self
+            .done_bytes
+            .load(Ordering::Relaxed)
+            .min(self.total_bytes);
+        (done * 100 / self.total_bytes) as u8
+    }
+
+    pub fn state(&self) -> BuildState {
+        self.state.borrow().clone()
+    }
+
+    /// Waits until the build has either produced the archive or failed.
+    pub async fn finished(&self) -> Result<(), &'static str> {
+        let mut state = self.state.subscribe();
+        let result = state
+            .wait_for(|s| matches!(s, BuildState::Done | BuildState::Failed(_)))
+            .await;
+        match result.as_deref() {
+            Ok(BuildState::Failed(message)) => Err(message),
+            Ok(_) => Ok(()),
+            Err(_) => Err("ZIP build was interrupted"),
+        }
+    }
+}
+
+pub enum BuildStatus {
+    /// Leased, so eviction leaves it alone until the lease is dropped.
+    Ready(ArchiveLease),
+    Building(Arc<BuildProgress>),
+    Failed(&'static str),
+}
+
+/// Background queue of ZIP builds, single-flight per archive. Builds are
+/// keyed by the target archive path, which contains the directory hash, so
+/// every request for the same contents shares a single build.
+pub struct ZipBuilder {
+    builds: Mutex<HashMap<PathBuf, Arc<BuildProgress>>>,
+    permits: Arc<Semaphore>,
+    tracker: TaskTracker,
+    active: Arc<ActiveArchives>,
+}
+
+impl ZipBuilder {
+    pub fn new(active: Arc<ActiveArchives>) -> Self {
+        Self {
+            builds: Mutex::new(HashMap::new()),
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_BUILDS)),
+            tracker: TaskTracker::new(),
+            active,
+        }
+    }
+
+    /// Returns the cached archive if it exists, otherwise the progress of
+    /// the build producing it, queueing one if none is running yet.
+    pub async fn request(
+        self: &Arc<Self>,
+        index: &Arc<ShareIndex>,
+        options: &ZipOptions,
+        part: Option<usize>,
+    ) -> BuildStatus {
+        // Before locking, hashing the contents may take a while
+        let target = cached_zip_path(index, options, part).await;
+        let mut builds = self.builds.lock().unwrap();
+
+        if let Some(progress) = builds.get(&target) {
+            match progress.state() {
+                BuildState::Failed(message) => {
+                    // Report the failure once, the next request retries
+                    builds.remove(&target);
+                    return BuildStatus::Failed(message);
+                }
+                _ => return BuildStatus::Building(progress.clone()),
+            }
+        }
+
+        // Leased before looking, so eviction can't remove it once found
+        let lease = self.active.lease(&target);
+        if target.exists() {
+            touch_archive(&target);
+            return BuildStatus::Ready(lease);
+        }
+        drop(lease);
+
+        if self.tracker.is_closed() {
+            return BuildStatus::Failed("Server is shutting down");
+        }
+
+        let progress = Arc::new(BuildProgress {
+            done_bytes: Arc::new(AtomicU64::new(0)),
+            total_bytes: part_files(index, options, part)
+                .iter()
+                .map(|f| f.size)
+                .sum(),
+            state: watch::channel(BuildState::Queued).0,
+        });
+        builds.insert(target.clone(), progress.clone());
+
+        let builder = self.clone();
+        let index = index.clone();
+        let options = options.clone();
+        let task_progress = progress.clone();
+        self.tracker.spawn(async move {
+            builder
+                .run_build(index, target, options, part, task_progress)
+                .await;
+        });
+
+        BuildStatus::Building(progress)
+    }
+
+    /// Like `request`, but waits for a running or newly queued build instead
+    /// of returning its progress.
+    pub async fn wait(
+        self: &Arc<Self>,
+        index: &Arc<ShareIndex>,
+        options: &ZipOptions,
+        part: Option<usize>,
+    ) -> Result<ArchiveLease, &'static str> {
+        loop {
+            match self.request(index, options, part).await {
+                BuildStatus::Ready(lease) => return Ok(lease),
+                BuildStatus::Failed(message) => return Err(message),
+                // The share may have changed meanwhile, so ask again
+                BuildStatus::Building(progress) => progress.finished().await?,
+            }
+        }
+    }
+
+    async fn run_build(
+        &self,
+        index: Arc<ShareIndex>,
+        target: PathBuf,
+        options: ZipOptions,
+        part: Option<usize>,
+        progress: Arc<BuildProgress>,
+    ) {
+        let Ok(_permit) = self.permits.acquire().await else {
+            // Closed on shutdown before this build got its turn
+            self.builds.lock().unwrap().remove(&target);
+            progress
+                .state
+                .send_replace(BuildState::Failed("Server is shutting down"));
+            return;
+        };
+        progress.state.send_replace(BuildState::Running);
+
+        info!("Building {:?} ({} bytes)", target, progress.total_bytes);
+        match build_zip(&index, &target, &options, part, &progress.done_bytes).await {
+            Ok(()) => {
+                info!("Finished {:?}", target);
+                self.builds.lock().unwrap().remove(&target);
+                progress.state.send_replace(BuildState::Done);
+            }
+            Err(e) => {
+                error!("Building {:?} failed: {}", target, e.message());
+                progress.state.send_replace(BuildState::Failed(e.message()));
+            }
+        }
+    }
+
+    /// Stops queued builds and waits until `deadline` for running ones.
+    pub async fn shutdown(&self, deadline: Instant) {
+        self.permits.close();
+        self.tracker.close();
+        if tokio::time::timeout_at(deadline, self.tracker.wait())
+            .await
+            .is_err()
+        {
+            warn!("ZIP builds still running at shutdown were interrupted");
+        }
+    }
+}
+
+/// Queues a rebuild whenever a share's contents settle after a change, and
+/// once at startup for archives that are missing.
+pub fn spawn_prebuilder(
+    config: SharedConfig,
+    indexes: Arc<FileIndexes>,
+    builder: Arc<ZipBuilder>,
+    shutdown: CancellationToken,
+) {
+    tokio::spawn(async move {
+        let mut watched: HashMap<PathBuf, Arc<ShareIndex>> = HashMap::new();
+        loop {
+            // Shares removed from the config retire their index
+            watched.retain(|_, index| !index.is_retired());
+            let snapshot = config.load_full();
+            if snapshot.cache.prebuild {
+                for share in &snapshot.shares {
+                    if watched.contains_key(&share.dir) {
+                        continue;
+                    }
+                    match indexes.get(&share.dir).await {
+                        Ok(index) => {
+                            watched.insert(share.dir.clone(), index.clone());
+                            tokio::spawn(watch_share(
+                                index,
+                                config.clone(),
+                                builder.clone(),
+                                shutdown.clone(),
+                            ));
+                        }
+                        Err(e) => warn!("Cannot index {:?} for prebuilding: {}", share.dir, e),
+                    }
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(SHARE_SCAN_INTERVAL) => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+    });
+}
+
+async fn watch_share(
+    index: Arc<ShareIndex>,
+    config: SharedConfig,
+    builder: Arc<ZipBuilder>,
+    shutdown: CancellationToken,
+) {
+    let mut generation = index.subscribe();
+    loop {
+        // Every kind of ZIP offered by a share of this directory
+        let variants: HashSet<ZipOptions> = {
+            let snapshot = config.load();
+            if snapshot.cache.prebuild {
+                snapshot
+                    .shares
+                    .iter()
+                    .filter(|s| s.dir == index.dir())
+                    .map(|s| s.zip.clone())
+                    .collect()
+            } else {
+                HashSet::new()
+            }
+        };
+        if !index.files().is_empty() {
+            for options in &variants {
+                for part in zip_part_numbers(&index, options) {
+                    builder.request(&index, options, part).await;
+                }
+            }
+        }
+
+        // Wait for a change, then for the share to stay quiet
+        tokio::select! {
+            changed = generation.changed() => if changed.is_err() { break },
+            _ = index.retired() => break,
+            _ = shutdown.cancelled() => break,
+        }
+        loop {
+            tokio::select! {
+                changed = tokio::time::timeout(PREBUILD_QUIET_PERIOD, generation.changed()) => match changed {
+                    Ok(Ok(())) => continue,
+                    Ok(Err(_)) => return,
+                    Err(_) => break,
+                },
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip_utils::zip_cache_dir;
+
+    #[tokio::test]
+    async fn concurrent_requests_share_one_build() {
+        let dir = std::env::temp_dir().join(format!("photo4share-builder-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in 0..5 {
+            std::fs::write(dir.join(format!("{}.jpg", i)), [i as u8; 4096]).unwrap();
+        }
+        let index = ShareIndex::load(&dir).await.unwrap();
+        let builder = Arc::new(ZipBuilder::new(Arc::new(ActiveArchives::default())));
+        let options = ZipOptions::default();
+
+        // Holding the only permit keeps the build queued while others ask
+        let permit = builder.permits.clone().acquire_owned().await.unwrap();
+        let statuses =
+            futures_util::future::join_all((0..4).map(|_| builder.request(&index, &options, None)))
+                .await;
+        let progress: Vec<_> = statuses
+            .into_iter()
+            .map(|status| match status {
+                BuildStatus::Building(progress) => progress,
+                _ => panic!("expected the build to be queued"),
+            })
+            .collect();
+        assert!(progress.iter().all(|p| Arc::ptr_eq(p, &progress[0])));
+        assert!(matches!(progress[0].state(), BuildState::Queued));
+        assert_eq!(progress[0].total_bytes, 5 * 4096);
+
+        let waiters =
+            futures_util::future::join_all((0..4).map(|_| builder.wait(&index, &options, None)));
+        drop(permit);
+        let leases = waiters.await;
+        let target = cached_zip_path(&index, &options, None).await;
+        for lease in &leases {
+            assert_eq!(lease.as_ref().unwrap().path(), target);
+        }
+        let built: Vec<_> = std::fs::read_dir(zip_cache_dir(&dir))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(built, [target.file_name().unwrap()]);
+        assert!(builder.builds.lock().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
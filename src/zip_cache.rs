@@ -0,0 +1,316 @@
+use crate::config::CacheSettings;
+use crate::config::Config;
+use crate::file_index::FileIndexes;
+use crate::previews::prune_stale_previews;
+use crate::reload::SharedConfig;
+use crate::zip_utils::zip_cache_dir;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::fs;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Archives that are currently being streamed to a client. Eviction skips
+/// them, so a download never loses its file halfway through.
+#[derive(Default)]
+pub struct ActiveArchives {
+    leases: Mutex<HashMap<PathBuf, usize>>,
+}
+
+impl ActiveArchives {
+    pub fn lease(self: &Arc<Self>, path: &Path) -> ArchiveLease {
+        *self
+            .leases
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_insert(0) += 1;
+        ArchiveLease {
+            archives: self.clone(),
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Removes an archive unless it is leased. Leases are checked and the
+    /// file removed under one lock, so one taken meanwhile isn't missed.
+    fn remove_unless_active(&self, path: &Path) -> std::io::Result<bool> {
+        let leases = self.leases.lock().unwrap();
+        if leases.contains_key(path) {
+            return Ok(false);
+        }
+        std::fs::remove_file(path)?;
+        Ok(true)
+    }
+}
+
+/// Keeps an archive marked as active until dropped.
+pub struct ArchiveLease {
+    archives: Arc<ActiveArchives>,
+    path: PathBuf,
+}
+
+impl ArchiveLease {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ArchiveLease {
+    fn drop(&mut self) {
+        let mut leases = self.archives.leases.lock().unwrap();
+        if let Some(count) = leases.get_mut(&self.path) {
+            *count -= 1;
+            if *count == 0 {
+                leases.remove(&self.path);
+            }
+        }
+    }
+}
+
+struct CachedArchive {
+    path: PathBuf,
+    size: u64,
+    /// Bumped on every cache hit, so this is really "last used".
+    modified: SystemTime,
+}
+
+/// Records that an archive was just used, for age and size based eviction.
+pub fn touch_archive(path: &Path) {
+    if let Ok(file) = std::fs::File::options().append(true).open(path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+/// Applies the eviction policy to the ZIP caches of the given shares, each
+/// with the content keys of what it currently holds (`None` if unknown).
+/// Returns the number of archives removed and the bytes freed.
+pub async fn evict(
+    share_dirs: &[(PathBuf, Option<HashSet<String>>)],
+    settings: &CacheSettings,
+    active: &ActiveArchives,
+) -> (usize, u64) {
+    let now = SystemTime::now();
+    let mut keep = Vec::new();
+    let mut doomed = Vec::new();
+
+    for (dir, current) in share_dirs {
+        let archives = match list_archives(&zip_cache_dir(dir)).await {
+            Ok(a) => a,
+            Err(e) => {
+                warn!("Cannot read ZIP cache of {:?}: {}", dir, e);
+                continue;
+            }
+        };
+        for archive in archives {
+            let too_old = settings
+                .max_age
+                .is_some_and(|max| now.duration_since(archive.modified).unwrap_or_default() > max);
+            let superseded = settings.keep_latest_only
+                && current
+                    .as_ref()
+                    .is_some_and(|keys| !keys.contains(content_key(&archive.path)));
+
+            if too_old || superseded {
+                doomed.push(archive);
+            } else {
+                keep.push(archive);
+            }
+        }
+    }
+
+    if let Some(max_total) = settings.max_total_bytes {
+        // Least recently used go first
+        keep.sort_by_key(|a| Reverse(a.modified));
+        let mut total: u64 = keep.iter().map(|a| a.size).sum();
+        while total > max_total {
+            let Some(archive) = keep.pop() else {
+                break;
+            };
+            total -= archive.size;
+            doomed.push(archive);
+        }
+    }
+
+    let (mut removed, mut freed) = (0, 0);
+    for archive in doomed {
+        match active.remove_unless_active(&archive.path) {
+            Ok(false) => debug!("Not evicting {:?}, it is being downloaded", archive.path),
+            Ok(true) => {
+                debug!("Evicted {:?} ({} bytes)", archive.path, archive.size);
+                removed += 1;
+                freed += archive.size;
+            }
+            Err(e) => warn!("Failed to evict {:?}: {}", archive.path, e),
+        }
+    }
+    (removed, freed)
+}
+
+/// Archives are named after the directory hash, optionally followed by
+/// `-variant`. Everything built from the same contents shares this key.
+pub fn content_key(path: &Path) -> &str {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .and_then(|s| s.split('-').next())
+        .unwrap_or_default()
+}
+
+async fn list_archives(zip_dir: &Path) -> std::io::Result<Vec<CachedArchive>> {
+    let mut entries = match fs::read_dir(zip_dir).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut archives = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        // Temp files belong to builds in progress
+        if path.extension().is_none_or(|ext| ext != "zip") {
+            continue;
+        }
+        let meta = entry.metadata().await?;
+        if meta.is_file() {
+            archives.push(CachedArchive {
+                path,
+                size: meta.len(),
+                modified: meta.modified()?,
+            });
+        }
+    }
+    Ok(archives)
+}
+
+/// Content keys of the archives every share in `config` currently needs,
+/// grouped by share directory.
+async fn current_keys(
+    config: &Config,
+    indexes: &FileIndexes,
+) -> Vec<(PathBuf, Option<HashSet<String>>)> {
+    let mut dirs: HashMap<PathBuf, Option<HashSet<String>>> = HashMap::new();
+    for share in &config.shares {
+        let key = match indexes.get(&share.dir).await {
+            Ok(index) => Some(index.content_hash(share.zip.hash_contents).await),
+            Err(e) => {
+                warn!("Cannot index {:?}, keeping its archives: {}", share.dir, e);
+                None
+            }
+        };
+        let keys = dirs
+            .entry(share.dir.clone())
+            .or_insert_with(|| Some(HashSet::new()));
+        match (keys, key) {
+            (Some(keys), Some(key)) => {
+                keys.insert(key);
+            }
+            (keys, None) => *keys = None,
+            (None, Some(_)) => {}
+        }
+    }
+    dirs.into_iter().collect()
+}
+
+/// Runs eviction now and then on the configured interval, picking up
+/// config reloads between runs. Previews of changed files go too.
+pub fn spawn_evictor(
+    config: SharedConfig,
+    indexes: Arc<FileIndexes>,
+    active: Arc<ActiveArchives>,
+    shutdown: CancellationToken,
+) {
+    tokio::spawn(async move {
+        loop {
+            let snapshot = config.load_full();
+            let dirs = current_keys(&snapshot, &indexes).await;
+            let (removed, freed) = evict(&dirs, &snapshot.cache, &active).await;
+            if removed > 0 {
+                info!(
+                    "ZIP cache eviction removed {} archive(s), freed {} bytes",
+                    removed, freed
+                );
+            }
+            prune_stale_previews(&snapshot, &indexes).await;
+
+            tokio::select! {
+                _ = tokio::time::sleep(snapshot.cache.eviction_interval) => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn settings() -> CacheSettings {
+        CacheSettings {
+            max_total_bytes: None,
+            max_age: None,
+            keep_latest_only: true,
+            eviction_interval: Duration::from_secs(60),
+            prebuild: false,
+        }
+    }
+
+    fn temp_cache(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("photo4share-evict-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(zip_cache_dir(&dir)).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn keeps_the_archives_of_the_current_contents() {
+        let dir = temp_cache("current");
+        let cache = zip_cache_dir(&dir);
+        std::fs::write(cache.join("current.zip"), b"zip").unwrap();
+        std::fs::write(cache.join("current-deflate.zip"), b"zip").unwrap();
+        // Touched last, e.g. by a client still on the old page
+        std::thread::sleep(Duration::from_millis(10));
+        std::fs::write(cache.join("old.zip"), b"zip").unwrap();
+
+        let current = Some(HashSet::from(["current".to_string()]));
+        let active = Arc::new(ActiveArchives::default());
+        let (removed, _) = evict(&[(dir.clone(), current)], &settings(), &active).await;
+        assert_eq!(removed, 1);
+        assert!(cache.join("current.zip").exists());
+        assert!(cache.join("current-deflate.zip").exists());
+        assert!(!cache.join("old.zip").exists());
+
+        // Unknown contents keep everything
+        let (removed, _) = evict(&[(dir.clone(), None)], &settings(), &active).await;
+        assert_eq!(removed, 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leased_archives_are_not_evicted() {
+        let dir = temp_cache("leased");
+        let archive = zip_cache_dir(&dir).join("old.zip");
+        std::fs::write(&archive, b"zip").unwrap();
+        let current = Some(HashSet::from(["current".to_string()]));
+        let active = Arc::new(ActiveArchives::default());
+
+        let lease = active.lease(&archive);
+        let (removed, _) = evict(&[(dir.clone(), current.clone())], &settings(), &active).await;
+        assert_eq!(removed, 0);
+        assert!(lease.path().exists());
+
+        drop(lease);
+        let (removed, _) = evict(&[(dir.clone(), current)], &settings(), &active).await;
+        assert_eq!(removed, 1);
+        assert!(!archive.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
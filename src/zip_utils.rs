@@ -1,47 +1,559 @@
+use crate::aes_zip::write_encrypted_zip;
+use crate::checksums::B3SUMS;
+use crate::checksums::SHA256SUMS;
+use crate::checksums::manifest;
+use crate::file_index::FileEntry;
+use crate::file_index::ShareIndex;
 use crate::file_utils::error_response;
-use crate::file_utils::should_include_file;
+use crate::zip_cache::ArchiveLease;
+use crate::zip_cache::content_key;
+use crate::zip_cache::touch_archive;
+use async_zip::Compression;
+use async_zip::ZipEntryBuilder;
+use async_zip::base::write::ZipFileWriter;
 use axum::body::Body;
 use axum::http::StatusCode;
 use axum::response::Response;
+use futures_util::StreamExt;
+use rand::Rng;
+use rand::rng;
+use std::ops::Range;
 use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use tokio::fs;
 use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufWriter;
+use tokio_util::compat::FuturesAsyncWriteCompatExt;
 use tokio_util::io::ReaderStream;
+use tracing::{info, warn};
 
-pub async fn calculate_directory_hash(dir: &Path) -> std::io::Result<String> {
-    let mut entries = fs::read_dir(dir).await?;
-    let mut files = Vec::new();
+pub fn zip_cache_dir(share_dir: &Path) -> PathBuf {
+    share_dir.join(".zipcache")
+}
+
+#[derive(Debug)]
+pub enum ZipBuildError {
+    Create,
+    Write,
+    Save,
+}
+
+impl ZipBuildError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ZipBuildError::Create => "Failed to create ZIP",
+            ZipBuildError::Write => "Failed to write ZIP",
+            ZipBuildError::Save => "Failed to save ZIP cache",
+        }
+    }
+}
+
+/// Formats whose data is already compressed, deflating them again only
+/// costs CPU time.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "jxl", "mp4", "mov", "m4v", "mkv",
+    "avi", "webm", "mp3", "m4a", "aac", "ogg", "opus", "flac", "zip", "7z", "rar", "gz", "xz",
+    "zst", "bz2",
+];
+
+/// How the files of a share are compressed inside its ZIP.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ZipCompression {
+    /// No compression, right for photos and videos.
+    #[default]
+    Store,
+    Deflate,
+    Zstd,
+    /// Deflate, except for formats that are already compressed.
+    Auto,
+}
+
+impl FromStr for ZipCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "store" => Ok(ZipCompression::Store),
+            "deflate" => Ok(ZipCompression::Deflate),
+            "zstd" => Ok(ZipCompression::Zstd),
+            "auto" => Ok(ZipCompression::Auto),
+            _ => Err(format!(
+                "invalid compression '{}', expected store, deflate, zstd or auto",
+                s
+            )),
+        }
+    }
+}
+
+impl ZipCompression {
+    /// Suffix of the cached archive name. Stored archives keep the plain
+    /// `{hash}.zip` name they always had.
+    pub fn variant(self) -> Option<&'static str> {
+        match self {
+            ZipCompression::Store => None,
+            ZipCompression::Deflate => Some("deflate"),
+            ZipCompression::Zstd => Some("zstd"),
+            ZipCompression::Auto => Some("auto"),
+        }
+    }
+
+    fn for_file(self, filename: &str) -> Compression {
+        match self {
+            ZipCompression::Store => Compression::Stored,
+            ZipCompression::Deflate => Compression::Deflate,
+            ZipCompression::Zstd => Compression::Zstd,
+            ZipCompression::Auto if is_compressed_format(filename) => Compression::Stored,
+            ZipCompression::Auto => Compression::Deflate,
+        }
+    }
+}
+
+/// True for formats that gain nothing from being compressed again.
+pub fn is_compressed_format(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Everything besides the files themselves that determines a share's ZIP.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct ZipOptions {
+    pub compression: ZipCompression,
+    /// Encrypts every entry with AES-256. The client gets the password out
+    /// of band, never from us.
+    pub password: Option<String>,
+    /// Splits the delivery into independent ZIPs of at most this size.
+    pub part_max_bytes: Option<u64>,
+    /// Adds a B3SUMS manifest next to SHA256SUMS.
+    pub b3sums: bool,
+    /// Keys the cached archive on the blake3 of every file's contents
+    /// rather than on its metadata alone.
+    pub hash_contents: bool,
+}
+
+impl ZipOptions {
+    /// Suffix of the cached archive name. Plain stored archives keep the
+    /// `{hash}.zip` name they always had. `password_key` keys the password
+    /// fingerprint of encrypted archives.
+    fn variant(&self, password_key: &[u8; 32]) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(variant) = self.compression.variant() {
+            parts.push(variant.to_string());
+        }
+        if let Some(password) = &self.password {
+            // Changing the password must not serve archives made with the
+            // old one. Keyed, so the name can't be used to guess it.
+            let fingerprint = blake3::keyed_hash(password_key, password.as_bytes());
+            parts.push(format!("aes{}", hex_prefix(fingerprint.as_bytes())));
+        }
+        if let Some(max) = self.part_max_bytes {
+            parts.push(format!("split{}", max));
+        }
+        if self.b3sums {
+            parts.push("b3".to_string());
+        }
+        (!parts.is_empty()).then(|| parts.join("-"))
+    }
+
+    /// Groups `files`, in order, into the parts the share is delivered in.
+    /// Returns a single group unless the share is split. A file is never
+    /// split, one larger than the limit gets a part of its own.
+    pub fn parts(&self, files: &[FileEntry]) -> Vec<Range<usize>> {
+        let Some(max) = self.part_max_bytes else {
+            return std::iter::once(0..files.len()).collect();
+        };
+
+        let mut parts = Vec::new();
+        let (mut start, mut size) = (0, ZIP_END_OVERHEAD);
+        for (i, entry) in files.iter().enumerate() {
+            let entry_size = entry.size + zip_entry_overhead(&entry.name);
+            if i > start && size + entry_size > max {
+                parts.push(start..i);
+                (start, size) = (i, ZIP_END_OVERHEAD);
+            }
+            size += entry_size;
+        }
+        parts.push(start..files.len());
+        parts
+    }
+}
+
+/// End of central directory records, including the ZIP64 ones.
+const ZIP_END_OVERHEAD: u64 = 22 + 56 + 20;
+
+/// Upper bound of the headers a ZIP adds for one entry: local header, data
+/// descriptor and central directory record, each with ZIP64 and AES extras.
+fn zip_entry_overhead(name: &str) -> u64 {
+    2 * name.len() as u64 + 30 + 24 + 46 + 2 * (20 + 11) + 28
+}
+
+/// Every archive a share is delivered as: `None` for the whole share, or
+/// the 1-based numbers of its parts when it is split.
+pub fn zip_part_numbers(index: &ShareIndex, options: &ZipOptions) -> Vec<Option<usize>> {
+    let count = options.parts(&index.files()).len();
+    if options.part_max_bytes.is_none() || count == 1 {
+        vec![None]
+    } else {
+        (1..=count).map(Some).collect()
+    }
+}
+
+/// The files of `part` (1-based), or all of them for an unsplit share.
+pub fn part_files(index: &ShareIndex, options: &ZipOptions, part: Option<usize>) -> Vec<FileEntry> {
+    let files = index.files();
+    match part {
+        None => files.to_vec(),
+        Some(part) => options
+            .parts(&files)
+            .get(part.wrapping_sub(1))
+            .map(|range| files[range.clone()].to_vec())
+            .unwrap_or_default(),
+    }
+}
+
+/// Bytes read from a file at a time while adding it to an archive.
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Random key of the password fingerprints in a share's archive names,
+/// kept next to the archives.
+const PASSWORD_KEY_FILE: &str = ".password-key";
+
+/// The key of `PASSWORD_KEY_FILE`, created on first use. Should it be
+/// unreadable, a key for this process only is used, so archives are still
+/// cached, just not reused after a restart.
+async fn password_key(zip_dir: &Path) -> [u8; 32] {
+    static FALLBACK: OnceLock<[u8; 32]> = OnceLock::new();
+    let path = zip_dir.join(PASSWORD_KEY_FILE);
+    for _ in 0..2 {
+        if let Ok(key) = fs::read(&path).await
+            && let Ok(key) = <[u8; 32]>::try_from(key)
+        {
+            return key;
+        }
+        let key: [u8; 32] = rng().random();
+        let _ = fs::create_dir_all(zip_dir).await;
+        let created = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .await;
+        match created {
+            Ok(mut file) => {
+                if file.write_all(&key).await.is_ok() && file.flush().await.is_ok() {
+                    return key;
+                }
+                let _ = fs::remove_file(&path).await;
+            }
+            // Created by a concurrent build meanwhile, read that one
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(_) => {}
+        }
+        break;
+    }
+    warn!(
+        "Cannot keep {:?}, cached archives won't survive a restart",
+        path
+    );
+    *FALLBACK.get_or_init(|| rng().random())
+}
+
+fn hex_prefix(bytes: &[u8; 32]) -> String {
+    bytes[..6].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Where the archive of the share's current contents is cached, `part`
+/// being the 1-based part of a split share. The options are part of the
+/// name, so archives built with different settings never get mixed up.
+pub async fn cached_zip_path(
+    index: &ShareIndex,
+    options: &ZipOptions,
+    part: Option<usize>,
+) -> PathBuf {
+    let mut name = index.content_hash(options.hash_contents).await;
+    let password_key = match options.password {
+        Some(_) => password_key(&zip_cache_dir(index.dir())).await,
+        None => [0; 32],
+    };
+    if let Some(variant) = options.variant(&password_key) {
+        name = format!("{}-{}", name, variant);
+    }
+    if let Some(part) = part {
+        name = format!("{}-{}of{}", name, part, options.parts(&index.files()).len());
+    }
+    zip_cache_dir(index.dir()).join(format!("{}.zip", name))
+}
+
+/// Returns the cached ZIP of the share's current contents, building it first
+/// if the directory changed since the last build.
+pub async fn ensure_cached_zip(
+    index: &ShareIndex,
+    options: &ZipOptions,
+    part: Option<usize>,
+) -> Result<PathBuf, ZipBuildError> {
+    let cached_zip = cached_zip_path(index, options, part).await;
+
+    // Return cached zip if it exists
+    if cached_zip.exists() {
+        touch_archive(&cached_zip);
+        return Ok(cached_zip);
+    }
 
+    build_zip(
+        index,
+        &cached_zip,
+        options,
+        part,
+        &Arc::new(AtomicU64::new(0)),
+    )
+    .await?;
+    Ok(cached_zip)
+}
+
+/// Writes the archive of the share's files, or of one part of them, to
+/// `cached_zip`, adding the size of every file written to `progress`.
+pub async fn build_zip(
+    index: &ShareIndex,
+    cached_zip: &Path,
+    options: &ZipOptions,
+    part: Option<usize>,
+    progress: &Arc<AtomicU64>,
+) -> Result<(), ZipBuildError> {
+    // Setup zip cache directory
+    let zip_dir = zip_cache_dir(index.dir());
+    let _ = fs::create_dir_all(&zip_dir).await;
+
+    // Unique per build, so a concurrent build of the same contents (e.g. a
+    // CLI `cache warm` next to the server) never writes into the same file
+    let suffix: u64 = rng().random();
+    let temp_path = cached_zip.with_extension(format!("{:016x}.tmp", suffix));
+
+    // The index is already filtered and sorted by name
+    let files = part_files(index, options, part);
+    let manifests = match build_manifests(index, &files, options).await {
+        Ok(manifests) => manifests,
+        Err(e) => {
+            warn!("Hashing files of {:?} failed: {}", index.dir(), e);
+            return Err(ZipBuildError::Write);
+        }
+    };
+
+    let result = match &options.password {
+        Some(password) => {
+            let dir = index.dir().to_path_buf();
+            let temp = temp_path.clone();
+            let password = password.clone();
+            let compression = options.compression;
+            let progress = progress.clone();
+            let written = tokio::task::spawn_blocking(move || {
+                write_encrypted_zip(
+                    &temp,
+                    &dir,
+                    &files,
+                    &manifests,
+                    compression,
+                    &password,
+                    &progress,
+                )
+            })
+            .await;
+            match written {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => {
+                    warn!("Encrypted ZIP of {:?} failed: {}", index.dir(), e);
+                    Err(ZipBuildError::Write)
+                }
+                Err(_) => Err(ZipBuildError::Write),
+            }
+        }
+        None => {
+            write_zip(
+                index.dir(),
+                &files,
+                &manifests,
+                &temp_path,
+                options.compression,
+                progress,
+            )
+            .await
+        }
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(e);
+    }
+
+    // Rename the temporary file to the final cached ZIP. Whichever build
+    // finishes last wins, both produced the same contents.
+    if fs::rename(&temp_path, cached_zip).await.is_err() {
+        let _ = fs::remove_file(&temp_path).await;
+        return Err(ZipBuildError::Save);
+    }
+
+    Ok(())
+}
+
+/// Checksum manifests added after the files, listing the files of this
+/// archive. A share file of the same name takes precedence.
+async fn build_manifests(
+    index: &ShareIndex,
+    files: &[FileEntry],
+    options: &ZipOptions,
+) -> std::io::Result<Vec<(&'static str, String)>> {
+    let digests = index.digests(files).await?;
+    let mut manifests = vec![(SHA256SUMS, manifest(&digests, false))];
+    if options.b3sums {
+        manifests.push((B3SUMS, manifest(&digests, true)));
+    }
+    manifests.retain(|(name, _)| !files.iter().any(|f| f.name == *name));
+    Ok(manifests)
+}
+
+async fn write_zip(
+    dir: &Path,
+    files: &[FileEntry],
+    manifests: &[(&'static str, String)],
+    temp_path: &Path,
+    compression: ZipCompression,
+    progress: &AtomicU64,
+) -> Result<(), ZipBuildError> {
+    let temp_file = File::create_new(temp_path)
+        .await
+        .map_err(|_| ZipBuildError::Write)?;
+    let mut zip = ZipFileWriter::with_tokio(BufWriter::new(temp_file));
+
+    // Add files to the zip, copying each in chunks so memory use doesn't
+    // grow with the size of the photos
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+    for entry in files {
+        let filename = entry.name.as_str();
+
+        let mut file = match File::open(dir.join(filename)).await {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+
+        // Create entry for the file
+        let zip_entry = ZipEntryBuilder::new(filename.into(), compression.for_file(filename));
+        let mut writer = zip
+            .write_entry_stream(zip_entry)
+            .await
+            .map_err(|_| ZipBuildError::Write)?
+            .compat_write();
+        loop {
+            let read = file
+                .read(&mut buffer)
+                .await
+                .map_err(|_| ZipBuildError::Write)?;
+            if read == 0 {
+                break;
+            }
+            writer
+                .write_all(&buffer[..read])
+                .await
+                .map_err(|_| ZipBuildError::Write)?;
+            progress.fetch_add(read as u64, Ordering::Relaxed);
+        }
+        writer
+            .into_inner()
+            .close()
+            .await
+            .map_err(|_| ZipBuildError::Write)?;
+    }
+
+    for (name, contents) in manifests {
+        let zip_entry = ZipEntryBuilder::new((*name).into(), compression.for_file(name));
+        zip.write_entry_whole(zip_entry, contents.as_bytes())
+            .await
+            .map_err(|_| ZipBuildError::Write)?;
+    }
+
+    // Finalize the zip
+    let mut writer = zip.close().await.map_err(|_| ZipBuildError::Create)?;
+    writer
+        .get_mut()
+        .flush()
+        .await
+        .map_err(|_| ZipBuildError::Write)
+}
+
+/// Deletes every cached archive except the ones built from the current
+/// contents. Temp files are left alone since a build may be writing them.
+/// Returns the number of files removed and the bytes freed.
+pub async fn prune_zip_cache(
+    index: &ShareIndex,
+    options: &ZipOptions,
+) -> std::io::Result<(usize, u64)> {
+    let zip_dir = zip_cache_dir(index.dir());
+    let current = index.content_hash(options.hash_contents).await;
+
+    let mut entries = match fs::read_dir(&zip_dir).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e),
+    };
+
+    let (mut removed, mut freed) = (0, 0);
     while let Some(entry) = entries.next_entry().await? {
         let path = entry.path();
-        if let Ok(true) = should_include_file(dir, &path).await {
-            files.push(path);
+        if path.extension().is_none_or(|ext| ext != "zip") || content_key(&path) == current {
+            continue;
+        }
+        let meta = entry.metadata().await?;
+        if !meta.is_file() {
+            continue;
         }
+        fs::remove_file(&path).await?;
+        removed += 1;
+        freed += meta.len();
     }
+    Ok((removed, freed))
+}
 
-    files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+/// Removes half-written archives left behind when a ZIP build was
+/// interrupted, e.g. by a shutdown that hit the drain timeout.
+pub async fn cleanup_temp_files(share_dir: &Path) -> std::io::Result<usize> {
+    let zip_dir = zip_cache_dir(share_dir);
+    let mut entries = match fs::read_dir(&zip_dir).await {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
 
-    let mut hasher = blake3::Hasher::new();
-    for path in files {
-        let file_name = path.file_name().unwrap();
-        let meta = fs::metadata(&path).await?;
-        let mtime = meta
-            .modified()?
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        hasher.update(file_name.to_string_lossy().as_bytes());
-        hasher.update(&mtime.to_le_bytes());
+    let mut removed = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "tmp") {
+            match fs::remove_file(&path).await {
+                Ok(()) => {
+                    info!("Removed leftover ZIP temp file {:?}", path);
+                    removed += 1;
+                }
+                Err(e) => warn!("Failed to remove {:?}: {}", path, e),
+            }
+        }
     }
-    Ok(hasher.finalize().to_hex().to_string())
+    Ok(removed)
 }
 
-pub fn serve_zip_file(file: File) -> Response {
-    let stream = ReaderStream::new(file);
+/// Streams an archive, holding `lease` until the last byte is sent. `part`
+/// is the part's number and the share's number of parts, if it is split.
+pub fn serve_zip_file(file: File, lease: ArchiveLease, part: Option<(usize, usize)>) -> Response {
+    let stream = ReaderStream::new(file).map(move |chunk| {
+        let _ = &lease;
+        chunk
+    });
 
     let today = chrono::Local::now();
-    let formatted_date = today.format("%d.%m.%y").to_string();
-    let filename = format!("{}_files.zip", formatted_date);
+    let filename = zip_filename(&today.format("%d.%m.%y").to_string(), part);
 
     match Response::builder()
         .status(StatusCode::OK)
@@ -56,3 +568,175 @@ pub fn serve_zip_file(file: File) -> Response {
         Err(_) => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Response error"),
     }
 }
+
+/// Name the client saves an archive as, numbered so the parts of a split
+/// share don't overwrite each other.
+fn zip_filename(date: &str, part: Option<(usize, usize)>) -> String {
+    match part {
+        Some((part, total)) => format!("{}_files_part{}of{}.zip", date, part, total),
+        None => format!("{}_files.zip", date),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn archives_files_larger_than_the_copy_buffer() {
+        let dir = std::env::temp_dir().join(format!("photo4share-zip-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let large: Vec<u8> = (0..COPY_BUFFER_SIZE * 3 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(dir.join("a.jpg"), &large).unwrap();
+        std::fs::write(dir.join("b.txt"), b"notes").unwrap();
+        std::fs::write(dir.join("empty.raw"), b"").unwrap();
+        let index = ShareIndex::load(&dir).await.unwrap();
+
+        for compression in [
+            ZipCompression::Store,
+            ZipCompression::Deflate,
+            ZipCompression::Zstd,
+            ZipCompression::Auto,
+        ] {
+            let path = dir.join(format!("{:?}.zip", compression));
+            let progress = AtomicU64::new(0);
+            let manifests = [(SHA256SUMS, "sums\n".to_string())];
+            write_zip(
+                &dir,
+                &index.files(),
+                &manifests,
+                &path,
+                compression,
+                &progress,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                progress.load(Ordering::Relaxed),
+                large.len() as u64 + 5,
+                "{:?}",
+                compression
+            );
+
+            let mut archive = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+            let names: Vec<_> = archive.file_names().map(str::to_string).collect();
+            assert_eq!(names, ["a.jpg", "b.txt", "empty.raw", SHA256SUMS]);
+            for (name, expected) in [
+                ("a.jpg", large.as_slice()),
+                ("b.txt", b"notes".as_slice()),
+                ("empty.raw", b"".as_slice()),
+                (SHA256SUMS, b"sums\n".as_slice()),
+            ] {
+                let mut contents = Vec::new();
+                archive
+                    .by_name(name)
+                    .unwrap()
+                    .read_to_end(&mut contents)
+                    .unwrap();
+                assert_eq!(contents, expected, "{} in {:?}", name, compression);
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn every_compression_has_its_own_cached_archive() {
+        let dir = std::env::temp_dir().join(format!("photo4share-zip-key-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.tif"), b"tiff").unwrap();
+        let index = ShareIndex::load(&dir).await.unwrap();
+        let path = |compression| {
+            let options = ZipOptions {
+                compression,
+                ..ZipOptions::default()
+            };
+            let index = index.clone();
+            async move { cached_zip_path(&index, &options, None).await }
+        };
+
+        let hash = index.content_hash(false).await;
+        let stored = path(ZipCompression::Store).await;
+        assert_eq!(stored, zip_cache_dir(&dir).join(format!("{}.zip", hash)));
+        let mut names = HashSet::from([stored]);
+        for compression in [
+            ZipCompression::Deflate,
+            ZipCompression::Zstd,
+            ZipCompression::Auto,
+        ] {
+            let cached = path(compression).await;
+            assert_eq!(cached, path(compression).await);
+            assert!(names.insert(cached), "{:?} shares a cache key", compression);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn entry(name: &str, size: u64) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            size,
+            modified: std::time::SystemTime::UNIX_EPOCH,
+            inode: 0,
+            changed: 0,
+        }
+    }
+
+    #[test]
+    fn parts_stay_under_the_limit_and_hold_every_file_once() {
+        const MB: u64 = 1024 * 1024;
+        let sizes = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 0, 12, 1];
+        let files: Vec<_> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| entry(&format!("{:02}.jpg", i), size * MB))
+            .collect();
+        let options = ZipOptions {
+            part_max_bytes: Some(10 * MB),
+            ..ZipOptions::default()
+        };
+
+        let parts = options.parts(&files);
+        assert!(parts.len() > 1);
+        // In order, without gaps or overlaps
+        assert_eq!(parts[0].start, 0);
+        assert_eq!(parts.last().unwrap().end, files.len());
+        for pair in parts.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        for part in &parts {
+            assert!(!part.is_empty());
+            let size: u64 = files[part.clone()]
+                .iter()
+                .map(|f| f.size + zip_entry_overhead(&f.name))
+                .sum::<u64>()
+                + ZIP_END_OVERHEAD;
+            // Only a file larger than the limit may exceed it, on its own
+            assert!(size <= 10 * MB || part.len() == 1, "{:?}", part);
+        }
+        assert!(
+            parts.contains(&(12..13)),
+            "the 12 MB file gets its own part"
+        );
+
+        // Unsplit shares are one group
+        assert_eq!(ZipOptions::default().parts(&files), vec![0..files.len()]);
+        assert_eq!(ZipOptions::default().parts(&[]), vec![0..0]);
+    }
+
+    #[test]
+    fn parts_are_named_after_their_number() {
+        assert_eq!(zip_filename("18.10.26", None), "18.10.26_files.zip");
+        assert_eq!(
+            zip_filename("18.10.26", Some((2, 3))),
+            "18.10.26_files_part2of3.zip"
+        );
+    }
+}